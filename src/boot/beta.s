@@ -12,8 +12,10 @@ stage1:
 	call print_stringln
 
 	mov eax, [0x0600 + 446 + 8]
-	add eax, 1
+	add eax, [stage2.offset]
 	mov [LBA_Packet.start], eax
+	mov ax, [stage2.sectors]
+	mov [LBA_Packet.sectors], ax
 
 	mov ah, 0x42 ; read LBA
 	mov si, LBA_Packet
@@ -54,10 +56,15 @@ LBA_Packet:
 	db 0  ; unspecified
 LBA_Packet.sectors: dw 4
 LBA_Packet.buffer:  dd 0x7e00
-LBA_Packet.start:   dd 1
+LBA_Packet.start:   dd 2
 	dd 0 ; used for 48bit LBA indexing
 
-times (510) - ($ - $$) db 0
+times (504) - ($ - $$) db 0
+
+; Where stage2 was placed, relative to the start of the partition
+; `mkdisk` fills these in, they depend on the filesystem
+stage2.offset:  dd 2 ; FAT: follows the FSInfo sector
+stage2.sectors: dw 4
 
 ; Magic number used by BIOS to recognize a valid bootsector
 dw 0xAA55
@@ -7,7 +7,8 @@ pub use disk::sector::{Sector, EMPTY_SECTOR};
 pub use disk::ramdisk::RamDisk;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use fs::{Fat32, FileSystem};
+use fs::{ExFat, Ext2, Fat32, FileSystem};
+use fs::fat::FatType;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 #[derive(Debug, Clone, PartialEq)]
@@ -15,9 +16,23 @@ pub enum Error {
     BeyondDiskSize,
     CorruptDisk,
     CorruptFAT,
+    CorruptBPB(&'static str), // describes the invalid field
     WriteError,
     InvalidPath,
-    Nonexistent(PathBuf)
+    Nonexistent(PathBuf),
+    NotAFile(PathBuf),
+    NotADirectory(PathBuf),
+    DirectoryNotEmpty(PathBuf),
+    AlreadyExists(PathBuf),
+    BufferTooSmall(usize), // size of the buffer the operation needed
+    NoSpace,
+    FileTooLarge, // a file's size doesn't fit in a usize on this platform
+}
+
+impl From<Error> for ::std::io::Error {
+    fn from(e: Error) -> ::std::io::Error {
+        ::std::io::Error::new(::std::io::ErrorKind::Other, format!("{:?}", e))
+    }
 }
 
 pub trait Disk {
@@ -27,7 +42,11 @@ pub trait Disk {
 }
 
 pub enum Format {
+    Fat12,
+    Fat16,
     Fat32,
+    ExFat,
+    Ext2,
     Unrecognized(u8),
 }
 
@@ -35,15 +54,30 @@ impl Format {
     pub fn serialize(&self) -> u8 {
         use self::Format::*;
         match *self {
+           Fat12 => 0x01,
+           Fat16 => 0x0E,
            Fat32 => 0x0C,
+           ExFat => 0x07,
+           Ext2 => 0x83,
            Unrecognized(n) => n,
         }
     }
 }
 
+impl From<FatType> for Format {
+    fn from(kind: FatType) -> Format {
+        match kind {
+            FatType::Fat12 => Format::Fat12,
+            FatType::Fat16 => Format::Fat16,
+            FatType::Fat32 => Format::Fat32,
+        }
+    }
+}
+
 pub struct DiskInfo {
     pub size: usize,
     pub sector_size: usize,
+    pub start: usize, // LBA of the first sector on the underlying device
 }
 
 pub struct Partition {
@@ -66,7 +100,10 @@ pub fn mount<T: Disk + 'static>(disk: &mut T, index: usize) -> Result<Box<FileSy
     };
 
     match pinfo.format {
-        Format::Fat32 => Ok(Box::new(try!(Fat32::new(Box::new(partition))))),
+        // `Fat32` reads the variant from the boot sector
+        Format::Fat12 | Format::Fat16 | Format::Fat32 => Ok(Box::new(try!(Fat32::new(Box::new(partition))))),
+        Format::ExFat => Ok(Box::new(try!(ExFat::new(Box::new(partition))))),
+        Format::Ext2 => Ok(Box::new(try!(Ext2::new(Box::new(partition))))),
         Format::Unrecognized(n) => panic!("Cannot mount unrecognized partition: {:x}", n),
     }
 }
@@ -108,10 +145,15 @@ pub fn get_pinfo<T: Disk>(disk: &T, index: usize) -> Result<Option<PartitionInfo
     let entry = &pt[index * 16 .. (index + 1) * 16];
 
     let format = match entry[4] {
-        // There are several FAT32 types
-        // 0x0B is for FAT32 with CHS addressing
-        // Currently only support 0x0C for FAT32 with LBA addressing
-        0x0C => Format::Fat32,
+        0x01 => Format::Fat12,
+        // FAT16 under 32MB, over 32MB, and with LBA addressing
+        0x04 | 0x06 | 0x0E => Format::Fat16,
+        // FAT32 with CHS addressing, and with LBA addressing
+        0x0B | 0x0C => Format::Fat32,
+        // shared with NTFS, `ExFat` checks the boot sector when mounting
+        0x07 => Format::ExFat,
+        // Linux native
+        0x83 => Format::Ext2,
 
         // Unused partition table entry. Return no info
         0x00 => {
@@ -166,6 +208,7 @@ impl Disk for Partition {
         DiskInfo {
             size: self.size,
             sector_size: 512,
+            start: self.start,
         }
     }
     fn read_sector(&self, lba: usize) -> Result<&Sector> {
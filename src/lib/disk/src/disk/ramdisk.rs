@@ -31,6 +31,7 @@ impl Disk for RamDisk {
         DiskInfo {
             size: self.sectors.len(),
             sector_size: 512,
+            start: 0,
         }
     }
     fn read_sector(&self, lba: usize) -> Result<&Sector> {
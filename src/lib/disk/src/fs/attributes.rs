@@ -0,0 +1,55 @@
+use std::ops::{BitOr, BitAnd, Not};
+
+/// The attribute bits of a FAT directory entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Attributes(pub u8);
+
+pub const READ_ONLY: Attributes = Attributes(1 << 0);
+pub const HIDDEN: Attributes = Attributes(1 << 1);
+pub const SYSTEM: Attributes = Attributes(1 << 2);
+pub const VOLUME_LABEL: Attributes = Attributes(1 << 3);
+pub const DIRECTORY: Attributes = Attributes(1 << 4);
+pub const ARCHIVE: Attributes = Attributes(1 << 5);
+
+/// All of the bits that FAT defines
+pub const ALL: Attributes = Attributes(0x3F);
+
+impl Attributes {
+    pub fn empty() -> Attributes {
+        Attributes(0)
+    }
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+    /// Whether all of the bits in `other` are set
+    pub fn contains(&self, other: Attributes) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub fn insert(&mut self, other: Attributes) {
+        self.0 |= other.0;
+    }
+    pub fn remove(&mut self, other: Attributes) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for Attributes {
+    type Output = Attributes;
+    fn bitor(self, other: Attributes) -> Attributes {
+        Attributes(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Attributes {
+    type Output = Attributes;
+    fn bitand(self, other: Attributes) -> Attributes {
+        Attributes(self.0 & other.0)
+    }
+}
+
+impl Not for Attributes {
+    type Output = Attributes;
+    fn not(self) -> Attributes {
+        Attributes(!self.0 & ALL.0)
+    }
+}
@@ -0,0 +1,84 @@
+/// A fixed size set of bits, used to track which blocks are in use
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitmap {
+    words: Vec<u32>,
+    len: usize, // number of bits
+}
+
+impl Bitmap {
+    /// Create a bitmap of `len` bits, all clear
+    pub fn new(len: usize) -> Bitmap {
+        Bitmap {
+            words: vec![0; (len + 31) / 32],
+            len: len,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn get(&self, i: usize) -> bool {
+        assert!(i < self.len, "Bitmap: index out of range `{}`", i);
+        self.words[i / 32] & (1 << (i % 32)) != 0
+    }
+    pub fn set(&mut self, i: usize, value: bool) {
+        assert!(i < self.len, "Bitmap: index out of range `{}`", i);
+        if value {
+            self.words[i / 32] |= 1 << (i % 32);
+        } else {
+            self.words[i / 32] &= !(1 << (i % 32));
+        }
+    }
+    /// Number of clear bits
+    pub fn count_clear(&self) -> usize {
+        let set = self.words.iter().map(|w| w.count_ones() as usize).fold(0, |a, b| a + b);
+        self.len - set
+    }
+    /// Find the first clear bit at or after `start`, wrapping around to the
+    /// beginning of the bitmap if necessary
+    pub fn find_clear(&self, start: usize) -> Option<usize> {
+        let start = if start < self.len { start } else { 0 };
+        self.find_clear_in(start, self.len).or_else(|| self.find_clear_in(0, start))
+    }
+
+    fn find_clear_in(&self, mut i: usize, end: usize) -> Option<usize> {
+        while i < end {
+            // skip whole words which are full
+            if i % 32 == 0 && self.words[i / 32] == !0 {
+                i += 32;
+                continue
+            }
+            if !self.get(i) {
+                return Some(i)
+            }
+            i += 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Bitmap;
+
+    #[test]
+    fn find_clear() {
+        let mut map = Bitmap::new(100);
+        for i in 0..70 {
+            map.set(i, true);
+        }
+        assert_eq!(map.count_clear(), 30);
+        assert_eq!(map.find_clear(0), Some(70));
+        assert_eq!(map.find_clear(80), Some(80));
+
+        map.set(5, false);
+        assert_eq!(map.find_clear(6), Some(70));
+        for i in 70..100 {
+            map.set(i, true);
+        }
+        // wraps around
+        assert_eq!(map.find_clear(6), Some(5));
+        map.set(5, true);
+        assert_eq!(map.find_clear(0), None);
+        assert_eq!(map.count_clear(), 0);
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fs::DateTime;
+
+/// A source of timestamps for new and modified files
+pub trait Clock {
+    fn now(&self) -> DateTime;
+}
+
+/// Reads the current time (UTC) from the host system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime {
+        let secs = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs(),
+            Err(_) => 0, // clock is set before 1970, not much we can do
+        };
+        from_unix(secs)
+    }
+}
+
+/// Always reports the same time
+///
+/// Useful for building reproducible images and for tests.
+pub struct FixedClock(pub DateTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime {
+        self.0
+    }
+}
+
+/// Convert seconds since the Unix epoch to a calendar date and time
+pub fn from_unix(secs: u64) -> DateTime {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // civil_from_days(), see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097; // day of era [0, 146096]
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365; // year of era [0, 399]
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // day of year [0, 365]
+    let mp = (5 * doy + 2) / 153; // month, beginning in March [0, 11]
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    DateTime {
+        year: year as u16,
+        month: month as u8,
+        day: day as u8,
+        hour: (rem / 3600) as u8,
+        minute: (rem % 3600 / 60) as u8,
+        second: (rem % 60) as u8,
+    }
+}
+
+/// Convert a calendar date and time to seconds since the Unix epoch
+///
+/// Times before 1970 are clamped to the epoch.
+pub fn to_unix(dt: &DateTime) -> u64 {
+    // days_from_civil(), the inverse of the algorithm in `from_unix()`
+    let month = dt.month as i64;
+    let y = dt.year as i64 - if month <= 2 { 1 } else { 0 };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400; // year of era [0, 399]
+    let mp = (month + 9) % 12; // month, beginning in March [0, 11]
+    let doy = (153 * mp + 2) / 5 + dt.day as i64 - 1; // day of year [0, 365]
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy; // day of era [0, 146096]
+    let days = era * 146097 + doe - 719468;
+    if days < 0 {
+        return 0
+    }
+
+    days as u64 * 86400 + dt.hour as u64 * 3600 + dt.minute as u64 * 60 + dt.second as u64
+}
+
+#[cfg(test)]
+mod test {
+    use fs::DateTime;
+
+    #[test]
+    fn from_unix() {
+        assert_eq!(super::from_unix(0),
+                   DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 });
+        assert_eq!(super::from_unix(1445000000),
+                   DateTime { year: 2015, month: 10, day: 16, hour: 12, minute: 53, second: 20 });
+        assert_eq!(super::from_unix(951782400), // leap day
+                   DateTime { year: 2000, month: 2, day: 29, hour: 0, minute: 0, second: 0 });
+    }
+
+    #[test]
+    fn to_unix() {
+        for &secs in &[0, 951782400, 1445000000, 4102444799] {
+            assert_eq!(super::to_unix(&super::from_unix(secs)), secs);
+        }
+        assert_eq!(super::to_unix(&DateTime { year: 1969, month: 12, day: 31, hour: 0, minute: 0, second: 0 }), 0);
+    }
+}
@@ -0,0 +1,2217 @@
+use std::cmp::{max, min};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use disk::{Disk, DiskInfo, Sector, EMPTY_SECTOR, Result, Error};
+use fs::{FileSystem, Attributes, Clock, SystemClock, DateTime, Entry, FileType, Handle, Metadata, Mode};
+use fs::attributes::{ARCHIVE, DIRECTORY, VOLUME_LABEL};
+use fs::bitmap::Bitmap;
+use fs::clock;
+use fs::fat::{decode_datetime, encode_datetime};
+
+/// An exFAT filesystem
+///
+/// Sizes are stored in 64 bits, so unlike FAT32 files may be larger
+/// than 4GiB.
+pub struct ExFat {
+    disk: Box<Disk>, // Underlying medium
+    fat_begin: usize, // LBA of the active FAT
+    cluster_begin: usize, // LBA of the cluster heap
+    cluster_size: usize, // Size of cluster in sectors
+    rdir_cluster: usize, // First cluster of the root directory
+    bitmap: Vec<usize>, // Clusters holding the allocation bitmap
+    used: Bitmap, // Which clusters are in use, indexed by cluster number
+    free_count: usize, // Number of free clusters
+    next_free: usize, // Cluster to begin searching from when allocating
+    upcase: Vec<u16>, // Up-case table, characters past its end are their own upper case
+    clock: Box<Clock>, // Source of timestamps for entry sets
+}
+
+/// The parameters in the boot sector of an exFAT volume
+#[derive(Debug, Clone, PartialEq)]
+struct BootSector {
+    volume_length: usize, // in sectors
+    fat_offset: usize,
+    fat_length: usize, // in sectors
+    heap_offset: usize,
+    cluster_count: usize,
+    root_cluster: usize,
+    revision: u16, // major version in the high byte
+    flags: u16,
+    sector_shift: u8, // bytes per sector as a power of two
+    cluster_shift: u8, // sectors per cluster as a power of two
+    fats: usize,
+}
+
+impl BootSector {
+    /// Parse a boot sector, making sure it belongs to an exFAT volume
+    fn parse(sector: &Sector) -> Result<BootSector> {
+        // where a FAT volume keeps its BPB must be zero
+        if sector[0..3] != [0xEB, 0x76, 0x90] || &sector[3..11] != b"EXFAT   " ||
+           sector[11..64].iter().any(|&b| b != 0) || sector[510..512] != [0x55, 0xAA] {
+            return Err(Error::CorruptBPB("not an exFAT boot sector"))
+        }
+
+        let u32_at = |i: usize| (&sector[i..i+4]).read_u32::<LittleEndian>().unwrap() as usize;
+        Ok(BootSector {
+            volume_length: (&sector[72..80]).read_u64::<LittleEndian>().unwrap() as usize,
+            fat_offset: u32_at(80),
+            fat_length: u32_at(84),
+            heap_offset: u32_at(88),
+            cluster_count: u32_at(92),
+            root_cluster: u32_at(96),
+            revision: (&sector[104..106]).read_u16::<LittleEndian>().unwrap(),
+            flags: (&sector[106..108]).read_u16::<LittleEndian>().unwrap(),
+            sector_shift: sector[108],
+            cluster_shift: sector[109],
+            fats: sector[110] as usize,
+        })
+    }
+
+    /// Make sure the regions of the volume fit on the disk and don't overlap
+    fn validate(&self, info: &DiskInfo) -> Result<()> {
+        if self.revision >> 8 != 1 {
+            return Err(Error::CorruptBPB("file system revision"))
+        }
+        // TODO: support sectors other than 512 bytes
+        if self.sector_shift != 9 {
+            return Err(Error::CorruptBPB("bytes per sector"))
+        }
+        // clusters are at most 32MiB
+        if self.cluster_shift > 16 {
+            return Err(Error::CorruptBPB("sectors per cluster"))
+        }
+        // TexFAT volumes have a second FAT
+        if self.fats != 1 && self.fats != 2 {
+            return Err(Error::CorruptBPB("number of FATs"))
+        }
+        if self.volume_length > info.size {
+            return Err(Error::CorruptBPB("volume length"))
+        }
+        if self.fat_offset < 2 * BOOT_REGION {
+            return Err(Error::CorruptBPB("FAT offset"))
+        }
+        if self.fat_length * 128 < self.cluster_count + 2 {
+            return Err(Error::CorruptBPB("FAT length"))
+        }
+        if self.cluster_count > MAX_CLUSTERS || self.heap_offset < self.fat_offset + self.fats * self.fat_length ||
+           self.heap_offset + (self.cluster_count << self.cluster_shift) > self.volume_length {
+            return Err(Error::CorruptBPB("cluster count"))
+        }
+        if self.root_cluster < 2 || self.root_cluster >= self.cluster_count + 2 {
+            return Err(Error::CorruptBPB("root directory cluster"))
+        }
+        Ok(())
+    }
+
+    /// Which FAT is in use, the second one is only found on TexFAT volumes
+    fn active_fat(&self) -> usize {
+        if self.fats > 1 { (self.flags & 1) as usize } else { 0 }
+    }
+}
+
+/// Timestamps of a file or directory
+///
+/// A zeroed timestamp means it was never recorded, so these are `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct Times {
+    created: Option<DateTime>,
+    modified: Option<DateTime>,
+    accessed: Option<DateTime>,
+}
+impl Times {
+    /// Timestamps for an entry set created at `now`
+    fn new(now: DateTime) -> Times {
+        Times {
+            created: Some(now),
+            modified: Some(now),
+            accessed: Some(now),
+        }
+    }
+    /// Record that a file was written at `now`
+    fn touch(&mut self, now: DateTime) {
+        self.modified = Some(now);
+        self.accessed = Some(now);
+    }
+}
+
+/// Where the contents of a file or directory are stored
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct Stream {
+    start: usize, // first cluster, 0 if nothing is allocated
+    size: usize, // in bytes, a whole number of clusters for directories
+    valid: usize, // bytes which were written, anything after reads as zero
+    contiguous: bool, // the clusters follow each other and aren't recorded in the FAT
+}
+
+/// A 32 byte directory entry, as stored on disk
+type RawEntry = [u8; 32];
+
+/// A File entry along with its Stream Extension and File Name entries
+#[derive(Debug, Clone, PartialEq)]
+struct EntrySet {
+    name: String,
+    attrib: Attributes,
+    times: Times,
+    stream: Stream,
+}
+
+impl EntrySet {
+    fn is_dir(&self) -> bool {
+        self.attrib.contains(DIRECTORY)
+    }
+
+    /// Parse an entry set, beginning with its File entry
+    ///
+    /// Returns `None` if the set is damaged or its checksum doesn't match,
+    /// and `FileTooLarge` if the file's size doesn't fit in a usize.
+    /// Secondary entries after the name are ignored.
+    fn decode(raw: &[RawEntry]) -> Result<Option<EntrySet>> {
+        if raw.len() < 3 || raw[0][0] != FILE_ENTRY || raw[1][0] != STREAM_ENTRY {
+            return Ok(None)
+        }
+        let (file, stream) = (&raw[0], &raw[1]);
+        if (&file[2..4]).read_u16::<LittleEndian>().unwrap() != set_checksum(raw) {
+            return Ok(None)
+        }
+
+        // the name is split across as many File Name entries as it needs
+        let len = stream[3] as usize;
+        let names = (len + 14) / 15;
+        if names == 0 || raw.len() < 2 + names || raw[2..2 + names].iter().any(|e| e[0] != NAME_ENTRY) {
+            return Ok(None)
+        }
+        let mut units = Vec::with_capacity(names * 15);
+        for entry in &raw[2..2 + names] {
+            for i in 0..15 {
+                units.push((&entry[2 + i * 2..4 + i * 2]).read_u16::<LittleEndian>().unwrap());
+            }
+        }
+        units.truncate(len);
+        let name = match String::from_utf16(&units) {
+            Ok(name) => name,
+            Err(_) => return Ok(None),
+        };
+
+        let start = (&stream[20..24]).read_u32::<LittleEndian>().unwrap() as usize;
+        let size = try!(length_at(stream, 24));
+        let valid = try!(length_at(stream, 8));
+        if size > 0 && start == 0 {
+            return Ok(None)
+        }
+
+        // the date and time in the high and low halves, like FAT
+        let timestamp = |i: usize, fine: u8, offset: u8| {
+            let n = (&file[i..i+4]).read_u32::<LittleEndian>().unwrap();
+            decode_datetime((n >> 16) as u16, n as u16, fine).map(|dt| to_utc(dt, offset))
+        };
+        Ok(Some(EntrySet {
+            name: name,
+            attrib: Attributes((&file[4..6]).read_u16::<LittleEndian>().unwrap() as u8),
+            times: Times {
+                created: timestamp(8, file[20], file[22]),
+                modified: timestamp(12, file[21], file[23]),
+                accessed: timestamp(16, 0, file[24]),
+            },
+            stream: Stream {
+                start: start,
+                size: size,
+                valid: min(valid, size),
+                contiguous: stream[1] & NO_FAT_CHAIN != 0,
+            },
+        }))
+    }
+}
+
+/// An entry set found in a directory listing
+#[derive(Debug, Clone)]
+struct Listed {
+    index: usize, // index of the File entry within the listing
+    count: usize, // number of entries in the set
+    set: EntrySet,
+}
+
+/// A directory, along with where its own entry set is stored
+#[derive(Debug, Clone)]
+struct Dir {
+    stream: Stream,
+    parent: Option<(Stream, usize)>, // listing and index of the entry set, `None` for the root
+}
+
+impl ExFat {
+    pub fn new(disk: Box<Disk>) -> Result<ExFat> {
+        ExFat::with_clock(disk, Box::new(SystemClock))
+    }
+
+    /// Mount an exFAT filesystem, taking timestamps from `clock`
+    ///
+    /// If the main boot region is damaged, the backup is used instead.
+    pub fn with_clock(disk: Box<Disk>, clock: Box<Clock>) -> Result<ExFat> {
+        let boot = match read_boot_region(&*disk, 0) {
+            Ok(boot) => boot,
+            Err(e) => match read_boot_region(&*disk, BOOT_REGION) {
+                Ok(boot) => {
+                    warn!("ExFat: main boot region is corrupt ({:?}), using the backup", e);
+                    boot
+                },
+                Err(_) => return Err(e),
+            },
+        };
+        try!(boot.validate(&disk.info()));
+        debug!("ExFat boot={:?}", boot);
+
+        let mut fs = ExFat {
+            disk: disk,
+            fat_begin: boot.fat_offset + boot.active_fat() * boot.fat_length,
+            cluster_begin: boot.heap_offset,
+            cluster_size: 1 << boot.cluster_shift,
+            rdir_cluster: boot.root_cluster,
+            bitmap: vec![],
+            used: Bitmap::new(boot.cluster_count + 2),
+            free_count: 0,
+            next_free: 2,
+            upcase: vec![],
+            clock: clock,
+        };
+
+        // the allocation bitmap and up-case table are found in the root directory
+        let mut bitmap = None;
+        let mut upcase = None;
+        let root = fs.root();
+        for raw in try!(fs.read_listing(&root.stream)) {
+            if raw[0] != BITMAP_ENTRY && raw[0] != UPCASE_ENTRY {
+                continue
+            }
+            let size = try!(length_at(&raw, 24));
+            let stream = Stream {
+                start: (&raw[20..24]).read_u32::<LittleEndian>().unwrap() as usize,
+                size: size,
+                valid: size,
+                contiguous: false,
+            };
+            match raw[0] {
+                // only TexFAT volumes have a second bitmap
+                BITMAP_ENTRY if bitmap.is_none() => bitmap = Some(stream),
+                UPCASE_ENTRY if upcase.is_none() => {
+                    upcase = Some(((&raw[4..8]).read_u32::<LittleEndian>().unwrap(), stream))
+                },
+                _ => { },
+            }
+        }
+        let (bitmap, (checksum, upcase)) = match (bitmap, upcase) {
+            (Some(bitmap), Some(upcase)) => (bitmap, upcase),
+            _ => return Err(Error::CorruptDisk),
+        };
+
+        let clusters = boot.cluster_count;
+        let mut bits = vec![0; bitmap.size];
+        if bits.len() * 8 < clusters {
+            return Err(Error::CorruptDisk)
+        }
+        try!(fs.read_stream(&bitmap, &mut bits));
+        fs.bitmap = try!(fs.clusters(&bitmap));
+        // the first bit is cluster 2, 0 and 1 don't exist
+        fs.used.set(0, true);
+        fs.used.set(1, true);
+        for c in 0..clusters {
+            if bits[c / 8] & (1 << (c % 8)) != 0 {
+                fs.used.set(c + 2, true);
+            }
+        }
+        fs.free_count = fs.used.count_clear();
+
+        let mut table = vec![0; upcase.size];
+        try!(fs.read_stream(&upcase, &mut table));
+        if table_checksum(&table) != checksum {
+            return Err(Error::CorruptDisk)
+        }
+        fs.upcase = load_upcase(&table);
+
+        debug!("ExFat clusters={} free_count={} upcase={}", clusters, fs.free_count, fs.upcase.len());
+        Ok(fs)
+    }
+
+    /// The root directory, which has no entry set
+    ///
+    /// Its size is only known from the length of its chain.
+    fn root(&self) -> Dir {
+        Dir {
+            stream: Stream { start: self.rdir_cluster, ..Stream::default() },
+            parent: None,
+        }
+    }
+
+    /// Read the `i`th sector of cluster `c`
+    fn read_cluster(&self, c: usize, i: usize) -> Result<&Sector> {
+        assert!(i < self.cluster_size, "ExFat: Invalid sector `{}` of cluster `0x{:x}`", i, c);
+        if c < 2 || c >= self.used.len() {
+            return Err(Error::CorruptFAT)
+        }
+        let lba = self.cluster_begin + (c - 2) * self.cluster_size + i;
+        debug!("read_cluster c=0x{:x} i={} r=0x{:x}", c, i, lba);
+        self.disk.read_sector(lba)
+    }
+
+    /// Write the `i`th sector of cluster `c`
+    fn write_cluster(&mut self, c: usize, i: usize, data: &[u8]) -> Result<()> {
+        assert!(i < self.cluster_size, "ExFat: Invalid sector `{}` of cluster `0x{:x}`", i, c);
+        if c < 2 || c >= self.used.len() {
+            return Err(Error::CorruptFAT)
+        }
+        let lba = self.cluster_begin + (c - 2) * self.cluster_size + i;
+        self.disk.write_sector(lba, data)
+    }
+
+    /// Write a whole cluster, zero filling whatever `data` doesn't cover
+    fn fill_cluster(&mut self, c: usize, data: &[u8]) -> Result<()> {
+        for i in 0..self.cluster_size {
+            let part = data.chunks(512).nth(i).unwrap_or(&[]);
+            try!(self.write_cluster(c, i, part));
+        }
+        Ok(())
+    }
+
+    fn read_fat(&self, c: usize) -> Result<u32> {
+        let lba = self.fat_begin + c * 4 / 512;
+        let offset = c * 4 % 512;
+        let sector = try!(self.disk.read_sector(lba));
+        Ok((&sector[offset..offset + 4]).read_u32::<LittleEndian>().unwrap())
+    }
+
+    fn write_fat(&mut self, c: usize, value: u32) -> Result<()> {
+        let lba = self.fat_begin + c * 4 / 512;
+        let offset = c * 4 % 512;
+        let mut sector = try!(self.disk.read_sector(lba)).clone();
+        (&mut sector[offset..offset + 4]).write_u32::<LittleEndian>(value).unwrap();
+        self.disk.write_sector(lba, &sector)
+    }
+
+    /// Mark a cluster as used or free, in memory and in the allocation bitmap
+    fn set_used(&mut self, c: usize, value: bool) -> Result<()> {
+        if self.used.get(c) == value {
+            return Ok(())
+        }
+        self.used.set(c, value);
+        if value {
+            self.free_count -= 1;
+        } else {
+            self.free_count += 1;
+        }
+
+        // the first bit of the allocation bitmap is cluster 2
+        let byte = (c - 2) / 8;
+        let csize = self.cluster_size * 512;
+        let (bc, i) = (self.bitmap[byte / csize], (byte % csize) / 512);
+        let mut sector = try!(self.read_cluster(bc, i)).clone();
+        if value {
+            sector[byte % 512] |= 1 << ((c - 2) % 8);
+        } else {
+            sector[byte % 512] &= !(1 << ((c - 2) % 8));
+        }
+        self.write_cluster(bc, i, &sector)
+    }
+
+    /// Follow the FAT to the next cluster of a chain
+    fn next_fat(&self, cluster: usize) -> Result<Option<usize>> {
+        match try!(self.read_fat(cluster)) {
+            END_OF_CHAIN => Ok(None),
+            n if n >= 2 && (n as usize) < self.used.len() => Ok(Some(n as usize)),
+            n => {
+                warn!("ExFat: cluster 0x{:x} has an invalid FAT entry 0x{:x}", cluster, n);
+                Err(Error::CorruptFAT)
+            },
+        }
+    }
+
+    /// Find the cluster of `stream` which follows `cluster`
+    fn next_cluster(&self, stream: &Stream, cluster: usize) -> Result<Option<usize>> {
+        if stream.contiguous {
+            let csize = self.cluster_size * 512;
+            let end = stream.start + (stream.size + csize - 1) / csize;
+            return Ok(if cluster + 1 < end { Some(cluster + 1) } else { None })
+        }
+        self.next_fat(cluster)
+    }
+
+    /// Find the next cluster of a stream which must not end at `cluster`
+    fn expect_next(&self, stream: &Stream, cluster: usize) -> Result<usize> {
+        match try!(self.next_cluster(stream, cluster)) {
+            Some(c) => Ok(c),
+            None => Err(Error::CorruptFAT),
+        }
+    }
+
+    /// List every cluster of a stream
+    fn clusters(&self, stream: &Stream) -> Result<Vec<usize>> {
+        let mut clusters = vec![];
+        let mut cluster = if stream.start == 0 { None } else { Some(stream.start) };
+        while let Some(c) = cluster {
+            // a chain longer than the volume must loop back on itself
+            if c < 2 || c >= self.used.len() || clusters.len() >= self.used.len() {
+                return Err(Error::CorruptFAT)
+            }
+            clusters.push(c);
+            cluster = try!(self.next_cluster(stream, c));
+        }
+        Ok(clusters)
+    }
+
+    /// Read the contents of a stream into `buf`, which must be no longer
+    /// than the stream
+    fn read_stream(&self, stream: &Stream, buf: &mut [u8]) -> Result<()> {
+        use std::slice::bytes::copy_memory;
+
+        // anything past the valid data length reads as zero
+        let valid = min(stream.valid, buf.len());
+        for b in &mut buf[valid..] {
+            *b = 0;
+        }
+
+        let mut cluster = stream.start;
+        let mut chunks = buf[..valid].chunks_mut(self.cluster_size * 512).peekable();
+        while let Some(chunk) = chunks.next() {
+            debug!("read_stream cluster=0x{:x}", cluster);
+            for (i, part) in chunk.chunks_mut(512).enumerate() {
+                let len = part.len();
+                copy_memory(&try!(self.read_cluster(cluster, i))[..len], part);
+            }
+
+            if chunks.peek().is_none() {
+                break
+            }
+            cluster = try!(self.expect_next(stream, cluster));
+        }
+        Ok(())
+    }
+
+    /// Find a free cluster and zero it
+    ///
+    /// If `last` is given, the new cluster is chained after it. `last` must
+    /// be the end of its chain.
+    fn alloc_cluster(&mut self, last: Option<usize>) -> Result<usize> {
+        // keep the chain contiguous if the following cluster is free,
+        // otherwise begin at the next free hint
+        let hint = last.map_or(self.next_free, |c| c + 1);
+        let new = match self.used.find_clear(hint) {
+            Some(new) => new,
+            None => return Err(Error::NoSpace),
+        };
+
+        try!(self.fill_cluster(new, &[]));
+        try!(self.set_used(new, true));
+        try!(self.write_fat(new, END_OF_CHAIN));
+        if let Some(last) = last {
+            try!(self.write_fat(last, new as u32));
+        }
+
+        self.next_free = if new + 1 == self.used.len() { 2 } else { new + 1 };
+        Ok(new)
+    }
+
+    /// Make sure `stream` can grow to hold `len` bytes
+    ///
+    /// Lets an operation fail with `NoSpace` before it modifies anything,
+    /// rather than part of the way through.
+    fn check_space(&self, stream: &Stream, len: usize) -> Result<()> {
+        let csize = self.cluster_size * 512;
+        let needed = (len + csize - 1) / csize;
+        let mut have = 0;
+        let mut cluster = if stream.start == 0 { None } else { Some(stream.start) };
+        while let Some(c) = cluster {
+            have += 1;
+            if have >= needed {
+                return Ok(())
+            }
+            cluster = try!(self.next_cluster(stream, c));
+        }
+        if needed > have + self.free_count {
+            return Err(Error::NoSpace)
+        }
+        Ok(())
+    }
+
+    /// Free every cluster of a chain, beginning with `cluster`
+    fn free_chain(&mut self, mut cluster: usize) -> Result<()> {
+        loop {
+            debug!("free_chain cluster=0x{:x}", cluster);
+            let next = try!(self.read_fat(cluster));
+            try!(self.write_fat(cluster, 0));
+            try!(self.set_used(cluster, false));
+            match next {
+                END_OF_CHAIN => return Ok(()),
+                n if n >= 2 && (n as usize) < self.used.len() => { cluster = n as usize },
+                _ => return Err(Error::CorruptFAT), // chain broken
+            }
+        }
+    }
+
+    /// End a chain at `cluster`, freeing any clusters that followed it
+    fn free_after(&mut self, cluster: usize) -> Result<()> {
+        if let Some(next) = try!(self.next_fat(cluster)) {
+            try!(self.write_fat(cluster, END_OF_CHAIN));
+            try!(self.free_chain(next));
+        }
+        Ok(())
+    }
+
+    /// Free all of the clusters of a stream
+    fn free_stream(&mut self, stream: &Stream) -> Result<()> {
+        if stream.contiguous {
+            // the FAT was never used, only the bitmap
+            for c in try!(self.clusters(stream)) {
+                try!(self.set_used(c, false));
+            }
+            Ok(())
+        } else if stream.start != 0 {
+            self.free_chain(stream.start)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Get a stream ready to be modified
+    ///
+    /// A contiguous stream has its clusters recorded in the FAT, so it can
+    /// be grown and shrunk like any other chain, and anything past the valid
+    /// data length is zeroed. The stream's entry set needs to be written
+    /// back afterwards.
+    fn prepare(&mut self, stream: &mut Stream) -> Result<()> {
+        if stream.contiguous {
+            let clusters = try!(self.clusters(stream));
+            for (i, &c) in clusters.iter().enumerate() {
+                let next = clusters.get(i + 1).map_or(END_OF_CHAIN, |&n| n as u32);
+                try!(self.write_fat(c, next));
+            }
+            stream.contiguous = false;
+        }
+        if stream.valid < stream.size {
+            let csize = self.cluster_size * 512;
+            let end = (stream.size + csize - 1) / csize * csize;
+            try!(self.zero_range(stream.start, stream.valid, end));
+            stream.valid = stream.size;
+        }
+        Ok(())
+    }
+
+    /// Zero the bytes from `from` up to `to` of the chain beginning at `start`
+    fn zero_range(&mut self, start: usize, from: usize, to: usize) -> Result<()> {
+        let csize = self.cluster_size * 512;
+        let mut cluster = if start == 0 { None } else { Some(start) };
+        let mut begin = 0; // offset of `cluster` within the chain
+        while let Some(c) = cluster {
+            if begin >= to {
+                break
+            }
+            for i in 0..self.cluster_size {
+                let offset = begin + i * 512;
+                if offset + 512 <= from || offset >= to {
+                    continue
+                }
+                let mut sector = try!(self.read_cluster(c, i)).clone();
+                for b in &mut sector[from.saturating_sub(offset)..min(to - offset, 512)] {
+                    *b = 0;
+                }
+                try!(self.write_cluster(c, i, &sector));
+            }
+            begin += csize;
+            cluster = try!(self.next_fat(c));
+        }
+        Ok(())
+    }
+
+    /// Write `buf` over the chain beginning at `start`, extending or
+    /// shortening the chain to fit
+    ///
+    /// Returns the first cluster of the chain, which is 0 if `buf` is empty.
+    fn write_chain(&mut self, start: usize, buf: &[u8]) -> Result<usize> {
+        if buf.len() == 0 {
+            if start != 0 {
+                try!(self.free_chain(start));
+            }
+            return Ok(0)
+        }
+
+        let start = if start == 0 { try!(self.alloc_cluster(None)) } else { start };
+        let mut cluster = start;
+        let mut chunks = buf.chunks(self.cluster_size * 512).peekable();
+        while let Some(chunk) = chunks.next() {
+            debug!("write_chain cluster=0x{:x}", cluster);
+            try!(self.fill_cluster(cluster, chunk));
+            if chunks.peek().is_none() {
+                break
+            }
+            cluster = match try!(self.next_fat(cluster)) {
+                Some(c) => c,
+                None => try!(self.alloc_cluster(Some(cluster))),
+            };
+        }
+
+        // an overwritten file may have had a longer chain
+        try!(self.free_after(cluster));
+        Ok(start)
+    }
+
+    /// Read the raw entries of a directory listing, up to the end marker
+    fn read_listing(&self, dir: &Stream) -> Result<Vec<RawEntry>> {
+        use std::slice::bytes::copy_memory;
+
+        let mut entries = vec![];
+        for c in try!(self.clusters(dir)) {
+            for i in 0..self.cluster_size {
+                let sector = try!(self.read_cluster(c, i));
+                for raw in sector.chunks(32) {
+                    if raw[0] == END_OF_DIR {
+                        return Ok(entries)
+                    }
+                    let mut entry = [0; 32];
+                    copy_memory(raw, &mut entry);
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Parse the entry sets of a directory listing
+    ///
+    /// Sets which are damaged or fail their checksum are skipped.
+    fn listing(&self, dir: &Stream) -> Result<Vec<Listed>> {
+        let raw = try!(self.read_listing(dir));
+        let mut listing = vec![];
+        let mut i = 0;
+        while i < raw.len() {
+            if raw[i][0] != FILE_ENTRY {
+                i += 1;
+                continue
+            }
+            let count = raw[i][1] as usize + 1;
+            let set = if i + count <= raw.len() { try!(EntrySet::decode(&raw[i..i + count])) } else { None };
+            match set {
+                Some(set) => {
+                    listing.push(Listed { index: i, count: count, set: set });
+                    i += count;
+                },
+                None => {
+                    warn!("ExFat: skipping damaged entry set at index {}", i);
+                    i += 1;
+                },
+            }
+        }
+        Ok(listing)
+    }
+
+    /// Find which cluster, sector and offset hold the `index`th entry of
+    /// a directory listing
+    fn locate_entry(&self, clusters: &[usize], index: usize) -> Result<(usize, usize, usize)> {
+        let per_cluster = self.cluster_size * 16;
+        match clusters.get(index / per_cluster) {
+            Some(&c) => Ok((c, (index % per_cluster) / 16, (index % 16) * 32)),
+            None => Err(Error::CorruptDisk),
+        }
+    }
+
+    /// Read `count` raw entries of a directory listing, beginning at `index`
+    fn get_entries(&self, dir: &Stream, index: usize, count: usize) -> Result<Vec<RawEntry>> {
+        use std::slice::bytes::copy_memory;
+
+        let clusters = try!(self.clusters(dir));
+        let mut entries = Vec::with_capacity(count);
+        for i in index..index + count {
+            let (c, s, offset) = try!(self.locate_entry(&clusters, i));
+            let mut entry = [0; 32];
+            copy_memory(&try!(self.read_cluster(c, s))[offset..offset + 32], &mut entry);
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Write raw entries to a directory listing, beginning at `index`
+    fn set_entries(&mut self, dir: &Stream, index: usize, entries: &[RawEntry]) -> Result<()> {
+        use std::slice::bytes::copy_memory;
+
+        let clusters = try!(self.clusters(dir));
+        for (i, entry) in (index..).zip(entries.iter()) {
+            let (c, s, offset) = try!(self.locate_entry(&clusters, i));
+            let mut sector = try!(self.read_cluster(c, s)).clone();
+            copy_memory(entry, &mut sector[offset..offset + 32]);
+            try!(self.write_cluster(c, s, &sector));
+        }
+        Ok(())
+    }
+
+    /// Read the entry set beginning at `index` of a directory listing
+    fn read_set(&self, dir: &Stream, index: usize) -> Result<Listed> {
+        let count = try!(self.get_entries(dir, index, 1))[0][1] as usize + 1;
+        match try!(EntrySet::decode(&try!(self.get_entries(dir, index, count)))) {
+            Some(set) => Ok(Listed { index: index, count: count, set: set }),
+            None => Err(Error::CorruptDisk),
+        }
+    }
+
+    /// Write an entry set back over the entries it was read from
+    ///
+    /// The name must not need more entries than the set already had, any
+    /// left over are marked unused.
+    fn write_set(&mut self, dir: &Stream, listed: &Listed) -> Result<()> {
+        let mut raw = self.encode_set(&listed.set);
+        assert!(raw.len() <= listed.count, "ExFat: entry set grew from {} to {} entries", listed.count, raw.len());
+        let mut rest = try!(self.get_entries(dir, listed.index + raw.len(), listed.count - raw.len()));
+        for entry in &mut rest {
+            entry[0] &= !IN_USE;
+        }
+        raw.extend(rest.into_iter());
+        self.set_entries(dir, listed.index, &raw)
+    }
+
+    /// Mark every entry of a set as unused
+    fn delete_set(&mut self, dir: &Stream, listed: &Listed) -> Result<()> {
+        let mut raw = try!(self.get_entries(dir, listed.index, listed.count));
+        for entry in &mut raw {
+            entry[0] &= !IN_USE;
+        }
+        self.set_entries(dir, listed.index, &raw)
+    }
+
+    /// Store an entry set in a directory, named after the last component
+    /// of `path`
+    fn add_set(&mut self, dir: &mut Dir, path: &Path, mut set: EntrySet) -> Result<Listed> {
+        set.name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(fname) if is_valid_name(fname) => fname.to_owned(),
+            _ => return Err(Error::InvalidPath),
+        };
+        let raw = self.encode_set(&set);
+        let index = try!(self.alloc_entries(dir, raw.len()));
+        try!(self.set_entries(&dir.stream, index, &raw));
+        Ok(Listed { index: index, count: raw.len(), set: set })
+    }
+
+    /// Finds `count` consecutive unused entries in a directory listing,
+    /// growing the listing if necessary
+    fn alloc_entries(&mut self, dir: &mut Dir, count: usize) -> Result<usize> {
+        debug!("alloc_entries start=0x{:x} count={}", dir.stream.start, count);
+
+        let mut first = 0; // beginning of the current run of unused entries
+        for (i, entry) in try!(self.read_listing(&dir.stream)).iter().enumerate() {
+            if entry[0] & IN_USE != 0 {
+                first = i + 1;
+            } else if i + 1 - first == count {
+                return Ok(first)
+            }
+        }
+
+        // everything after the end of the listing is unused
+        let per_cluster = self.cluster_size * 16;
+        let clusters = try!(self.clusters(&dir.stream));
+        let mut capacity = clusters.len() * per_cluster;
+        if first + count <= capacity {
+            return Ok(first)
+        }
+        if (first + count - capacity + per_cluster - 1) / per_cluster > self.free_count {
+            return Err(Error::NoSpace)
+        }
+
+        try!(self.prepare(&mut dir.stream));
+        let mut last = clusters[clusters.len() - 1];
+        while first + count > capacity {
+            last = try!(self.alloc_cluster(Some(last)));
+            capacity += per_cluster;
+        }
+        debug!("alloc_entries grew listing to {} entries", capacity);
+
+        // the root directory has no entry set to record the new size in
+        if let Some((parent, index)) = dir.parent {
+            dir.stream.size = capacity * 32;
+            dir.stream.valid = capacity * 32;
+            let mut listed = try!(self.read_set(&parent, index));
+            listed.set.stream = dir.stream;
+            try!(self.write_set(&parent, &listed));
+        }
+        Ok(first)
+    }
+
+    /// Look up a character in the up-case table
+    fn upcase(&self, c: u16) -> u16 {
+        self.upcase.get(c as usize).cloned().unwrap_or(c)
+    }
+
+    /// Compare two file names, ignoring case as the up-case table defines it
+    fn names_equal(&self, a: &str, b: &str) -> bool {
+        a.encode_utf16().map(|c| self.upcase(c)).eq(b.encode_utf16().map(|c| self.upcase(c)))
+    }
+
+    /// Hash of the up-cased name, kept in the Stream Extension entry
+    fn name_hash(&self, name: &str) -> u16 {
+        let mut hash = 0u16;
+        for c in name.encode_utf16().map(|c| self.upcase(c)) {
+            hash = hash.rotate_right(1).wrapping_add(c & 0xFF);
+            hash = hash.rotate_right(1).wrapping_add(c >> 8);
+        }
+        hash
+    }
+
+    /// Build the raw entries of an entry set, including its checksum
+    fn encode_set(&self, set: &EntrySet) -> Vec<RawEntry> {
+        let units = set.name.encode_utf16().collect::<Vec<u16>>();
+        let names = (units.len() + 14) / 15;
+        let mut raw = vec![[0; 32]; 2 + names];
+
+        raw[0][0] = FILE_ENTRY;
+        raw[0][1] = (1 + names) as u8; // secondary count
+        let _ = (&mut raw[0][4..6]).write_u16::<LittleEndian>(set.attrib.bits() as u16);
+        let times = [(8, Some(20), 22, set.times.created), (12, Some(21), 23, set.times.modified),
+                     (16, None, 24, set.times.accessed)];
+        for &(i, fine, offset, dt) in &times {
+            if let Some(dt) = dt {
+                let (date, time, ms) = encode_datetime(&dt);
+                let _ = (&mut raw[0][i..i + 4]).write_u32::<LittleEndian>((date as u32) << 16 | time as u32);
+                if let Some(fine) = fine {
+                    raw[0][fine] = ms;
+                }
+                // timestamps come from a UTC clock, so the offset is a valid +0
+                raw[0][offset] = UTC_OFFSET_VALID;
+            }
+        }
+
+        raw[1][0] = STREAM_ENTRY;
+        raw[1][1] = ALLOCATION_POSSIBLE | if set.stream.contiguous { NO_FAT_CHAIN } else { 0 };
+        raw[1][3] = units.len() as u8;
+        let _ = (&mut raw[1][4..6]).write_u16::<LittleEndian>(self.name_hash(&set.name));
+        let _ = (&mut raw[1][8..16]).write_u64::<LittleEndian>(set.stream.valid as u64);
+        let _ = (&mut raw[1][20..24]).write_u32::<LittleEndian>(set.stream.start as u32);
+        let _ = (&mut raw[1][24..32]).write_u64::<LittleEndian>(set.stream.size as u64);
+
+        for (entry, chunk) in raw[2..].iter_mut().zip(units.chunks(15)) {
+            entry[0] = NAME_ENTRY;
+            for (i, &c) in chunk.iter().enumerate() {
+                let _ = (&mut entry[2 + i * 2..4 + i * 2]).write_u16::<LittleEndian>(c);
+            }
+        }
+
+        let checksum = set_checksum(&raw);
+        let _ = (&mut raw[0][2..4]).write_u16::<LittleEndian>(checksum);
+        raw
+    }
+
+    /// Find an entry set in a directory listing
+    ///
+    /// Only the file name of `child` is considered.
+    fn find_listed(&self, dir: &Stream, child: &Path) -> Result<Option<Listed>> {
+        debug!("find_listed start=0x{:x} path={:?}", dir.start, child);
+        let fname = match child.file_name().and_then(|n| n.to_str()) {
+            Some(fname) => fname,
+            None => return Ok(None), // can't have been stored in the first place
+        };
+        Ok(try!(self.listing(dir)).into_iter().find(|listed| self.names_equal(&listed.set.name, fname)))
+    }
+
+    /// Find a file or directory, along with the directory holding it
+    fn find_set(&self, path: &Path) -> Result<(Dir, Listed)> {
+        let dir = try!(self.find_parent_dir(path));
+        match try!(self.find_listed(&dir.stream, path)) {
+            Some(listed) => Ok((dir, listed)),
+            None => Err(Error::Nonexistent(path.to_owned())),
+        }
+    }
+
+    /// Find a directory and every directory above it
+    ///
+    /// exFAT has no `..` entries, so the parents are kept to resolve them.
+    fn find_dirs(&self, path: &Path) -> Result<Vec<Dir>> {
+        let mut dirs = vec![self.root()];
+        let mut iter = path.iter();
+        debug!("find_dirs path={:?}", path);
+        while let Some(item) = iter.next() {
+            if Path::new(item).has_root() || item == "." {
+                continue
+            }
+            if item == ".." {
+                // the root directory is its own parent
+                if dirs.len() > 1 {
+                    dirs.pop();
+                }
+                continue
+            }
+
+            let parent = dirs[dirs.len() - 1].stream;
+            match try!(self.find_listed(&parent, item.as_ref())) {
+                Some(ref listed) if listed.set.is_dir() => {
+                    dirs.push(Dir {
+                        stream: listed.set.stream,
+                        parent: Some((parent, listed.index)),
+                    });
+                },
+                found => {
+                    let count = iter.count();
+                    let mut epath = path;
+                    for _ in 0..count {
+                        // unwrap() should be fine here, see Path::parent() docs
+                        epath = epath.parent().unwrap();
+                    }
+                    return Err(match found {
+                        Some(_) => Error::NotADirectory(epath.to_owned()),
+                        None => Error::Nonexistent(epath.to_owned()),
+                    })
+                }
+            }
+        }
+        Ok(dirs)
+    }
+
+    fn find_dir(&self, path: &Path) -> Result<Dir> {
+        // unwrap() should be safe, there is always the root directory
+        Ok(try!(self.find_dirs(path)).pop().unwrap())
+    }
+
+    fn find_parent_dir(&self, path: &Path) -> Result<Dir> {
+        match path.parent() {
+            Some(parent) => self.find_dir(parent),
+            None => Ok(self.root()),
+        }
+    }
+}
+
+impl FileSystem for ExFat {
+    fn read_dir<'a>(&'a mut self, path: &Path) -> Result<Box<Iterator<Item=Result<Entry>> + 'a>> {
+        let dir = try!(self.find_dir(path));
+        let listing = try!(self.listing(&dir.stream));
+        Ok(Box::new(listing.into_iter().map(|listed| {
+            let set = listed.set;
+            Ok(Entry {
+                kind: if set.is_dir() { FileType::Directory } else { FileType::File },
+                size: if set.is_dir() { 0 } else { set.stream.size },
+                start: set.stream.start,
+                name: set.name,
+            })
+        })))
+    }
+
+    fn open<'a>(&'a mut self, path: &Path, mode: Mode) -> Result<Box<Handle + 'a>> {
+        let mut dir = try!(self.find_parent_dir(path));
+        let mut listed = match try!(self.find_listed(&dir.stream, path)) {
+            Some(ref listed) if listed.set.is_dir() => return Err(Error::NotAFile(path.to_owned())),
+            Some(listed) => listed,
+            None if mode == Mode::Write => {
+                let set = EntrySet {
+                    name: String::new(),
+                    attrib: ARCHIVE,
+                    times: Times::new(self.clock.now()),
+                    stream: Stream::default(),
+                };
+                try!(self.add_set(&mut dir, path, set))
+            },
+            None => return Err(Error::Nonexistent(path.to_owned())),
+        };
+
+        let mut dirty = false;
+        if mode != Mode::Read {
+            let old = listed.set.stream;
+            try!(self.prepare(&mut listed.set.stream));
+            if listed.set.stream != old {
+                try!(self.write_set(&dir.stream, &listed));
+            }
+        }
+        if mode == Mode::Write && listed.set.stream.size > 0 {
+            try!(self.free_stream(&listed.set.stream));
+            listed.set.stream = Stream::default();
+            dirty = true;
+        }
+
+        let start = listed.set.stream.start;
+        Ok(Box::new(ExFatFile {
+            fs: self,
+            mode: mode,
+            dir: dir.stream,
+            listed: listed,
+            cluster: start,
+            index: 0,
+            pos: 0,
+            dirty: dirty,
+        }))
+    }
+
+    fn make_dir(&mut self, path: &Path) -> Result<()> {
+        let mut dir = try!(self.find_parent_dir(path));
+        if try!(self.find_listed(&dir.stream, path)).is_some() {
+            return Err(Error::AlreadyExists(path.to_owned()))
+        }
+
+        // an empty listing, there are no `.` and `..` entries
+        let start = try!(self.alloc_cluster(None));
+        let csize = self.cluster_size * 512;
+        let set = EntrySet {
+            name: String::new(),
+            attrib: DIRECTORY,
+            times: Times::new(self.clock.now()),
+            stream: Stream { start: start, size: csize, valid: csize, contiguous: false },
+        };
+        if let Err(e) = self.add_set(&mut dir, path, set) {
+            try!(self.free_chain(start));
+            return Err(e)
+        }
+        Ok(())
+    }
+
+    fn metadata(&mut self, path: &Path) -> Result<Metadata> {
+        if path.file_name().is_none() {
+            // the root directory has no entry set of its own
+            try!(self.find_dir(path));
+            return Ok(Metadata {
+                kind: FileType::Directory,
+                size: 0,
+                attributes: DIRECTORY,
+                created: None,
+                modified: None,
+                accessed: None,
+            })
+        }
+
+        let set = try!(self.find_set(path)).1.set;
+        Ok(Metadata {
+            kind: if set.is_dir() { FileType::Directory } else { FileType::File },
+            size: if set.is_dir() { 0 } else { set.stream.size },
+            attributes: set.attrib,
+            created: set.times.created,
+            modified: set.times.modified,
+            accessed: set.times.accessed,
+        })
+    }
+
+    fn attributes(&mut self, path: &Path) -> Result<Attributes> {
+        self.metadata(path).map(|meta| meta.attributes)
+    }
+
+    fn set_attributes(&mut self, path: &Path, attrib: Attributes) -> Result<()> {
+        if path.file_name().is_none() {
+            // the root directory has no entry set to store attributes in
+            return Err(Error::InvalidPath)
+        }
+
+        let (dir, mut listed) = try!(self.find_set(path));
+        // whether an entry is a directory can't be changed, and exFAT has
+        // no volume label attribute
+        let fixed = DIRECTORY | VOLUME_LABEL;
+        listed.set.attrib = (listed.set.attrib & fixed) | (attrib & !fixed);
+        self.write_set(&dir.stream, &listed)
+    }
+
+    fn delete(&mut self, path: &Path) -> Result<()> {
+        let (dir, listed) = try!(self.find_set(path));
+        if listed.set.is_dir() && !try!(self.listing(&listed.set.stream)).is_empty() {
+            return Err(Error::DirectoryNotEmpty(path.to_owned()))
+        }
+
+        debug!("delete start=0x{:x} index=0x{:x}", dir.stream.start, listed.index);
+        try!(self.delete_set(&dir.stream, &listed));
+        self.free_stream(&listed.set.stream)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path, replace: bool) -> Result<()> {
+        let (src, listed) = try!(self.find_set(from));
+        let mut dsts = match to.parent() {
+            Some(parent) => try!(self.find_dirs(parent)),
+            None => vec![self.root()],
+        };
+        let is_dir = listed.set.is_dir();
+
+        // a directory can't be moved inside of itself
+        if is_dir && dsts.iter().any(|dir| dir.stream.start == listed.set.stream.start) {
+            return Err(Error::InvalidPath)
+        }
+        // unwrap() should be safe, there is always the root directory
+        let mut dst = dsts.pop().unwrap();
+
+        match try!(self.find_listed(&dst.stream, to)) {
+            // only changing the case of the name
+            Some(ref target) if dst.stream.start == src.stream.start && target.index == listed.index => { },
+            Some(target) => {
+                if !replace {
+                    return Err(Error::AlreadyExists(to.to_owned()))
+                } else if is_dir && !target.set.is_dir() {
+                    return Err(Error::NotADirectory(to.to_owned()))
+                } else if !is_dir && target.set.is_dir() {
+                    return Err(Error::NotAFile(to.to_owned()))
+                }
+                // delete() refuses to remove a directory which isn't empty
+                try!(self.delete(to));
+            },
+            None => { },
+        }
+
+        // free the old entries first so they can be reused, but keep them
+        // in case the new ones don't fit
+        let old = try!(self.get_entries(&src.stream, listed.index, listed.count));
+        try!(self.delete_set(&src.stream, &listed));
+        if let Err(e) = self.add_set(&mut dst, to, listed.set) {
+            try!(self.set_entries(&src.stream, listed.index, &old));
+            return Err(e)
+        }
+        Ok(())
+    }
+
+    fn write_file(&mut self, path: &Path, buf: &[u8]) -> Result<()> {
+        let mut dir = try!(self.find_parent_dir(path));
+        let now = self.clock.now();
+        match try!(self.find_listed(&dir.stream, path)) {
+            // File exists, overwriting
+            Some(mut listed) => {
+                if listed.set.is_dir() {
+                    return Err(Error::NotAFile(path.to_owned()))
+                }
+                // fail before any of the old contents are overwritten
+                let mut stream = listed.set.stream;
+                try!(self.check_space(&stream, buf.len()));
+                try!(self.prepare(&mut stream));
+                stream.start = try!(self.write_chain(stream.start, buf));
+                stream.size = buf.len();
+                stream.valid = buf.len();
+
+                // keep any other attributes, but flag the file as modified
+                listed.set.stream = stream;
+                listed.set.attrib.insert(ARCHIVE);
+                listed.set.times.touch(now);
+                self.write_set(&dir.stream, &listed)
+            },
+            // File does not exist, write the contents then add an entry set
+            None => {
+                try!(self.check_space(&Stream::default(), buf.len()));
+                let start = try!(self.write_chain(0, buf));
+                let set = EntrySet {
+                    name: String::new(),
+                    attrib: ARCHIVE,
+                    times: Times::new(now),
+                    stream: Stream { start: start, size: buf.len(), valid: buf.len(), contiguous: false },
+                };
+                match self.add_set(&mut dir, path, set) {
+                    Ok(_) => Ok(()),
+                    Err(e) => {
+                        // growing the directory may have needed the space,
+                        // don't leave the contents behind
+                        if start != 0 {
+                            try!(self.free_chain(start));
+                        }
+                        Err(e)
+                    },
+                }
+            }
+        }
+    }
+
+    fn truncate(&mut self, path: &Path, len: usize) -> Result<()> {
+        let (dir, mut listed) = try!(self.find_set(path));
+        if listed.set.is_dir() {
+            return Err(Error::NotAFile(path.to_owned()))
+        }
+        let mut stream = listed.set.stream;
+        try!(self.check_space(&stream, len));
+        try!(self.prepare(&mut stream));
+
+        let csize = self.cluster_size * 512;
+        let keep = (len + csize - 1) / csize;
+        if keep == 0 {
+            // an empty file has no clusters at all
+            try!(self.free_stream(&stream));
+            stream.start = 0;
+        } else {
+            if stream.start == 0 {
+                stream.start = try!(self.alloc_cluster(None));
+            }
+            let mut cluster = stream.start;
+            for _ in 1..keep {
+                cluster = match try!(self.next_fat(cluster)) {
+                    Some(c) => c,
+                    // new clusters are zeroed already
+                    None => try!(self.alloc_cluster(Some(cluster))),
+                };
+            }
+            try!(self.free_after(cluster));
+
+            // anything in the kept clusters past the old or new end must read as zero
+            let end = (stream.size + csize - 1) / csize * csize;
+            try!(self.zero_range(stream.start, min(len, stream.size), min(end, keep * csize)));
+        }
+        stream.size = len;
+        stream.valid = len;
+
+        listed.set.stream = stream;
+        listed.set.attrib.insert(ARCHIVE);
+        listed.set.times.touch(self.clock.now());
+        self.write_set(&dir.stream, &listed)
+    }
+
+    fn write_at(&mut self, path: &Path, offset: usize, buf: &[u8]) -> Result<()> {
+        use std::slice::bytes::copy_memory;
+
+        let (dir, mut listed) = try!(self.find_set(path));
+        if listed.set.is_dir() {
+            return Err(Error::NotAFile(path.to_owned()))
+        }
+        if buf.len() == 0 {
+            return Ok(())
+        }
+        try!(self.check_space(&listed.set.stream, max(listed.set.stream.size, offset + buf.len())));
+        if offset > listed.set.stream.size {
+            // zero fills the gap, extending the chain up to the offset
+            try!(self.truncate(path, offset));
+            listed = try!(self.read_set(&dir.stream, listed.index));
+        }
+
+        let mut stream = listed.set.stream;
+        try!(self.prepare(&mut stream));
+        if stream.start == 0 {
+            stream.start = try!(self.alloc_cluster(None));
+        }
+
+        let csize = self.cluster_size * 512;
+        let mut cluster = stream.start;
+        let mut index = 0; // position of `cluster` within the chain
+        let mut pos = offset;
+        let mut data = buf;
+        while data.len() > 0 {
+            while index < pos / csize {
+                cluster = match try!(self.next_fat(cluster)) {
+                    Some(c) => c,
+                    // writing past the end of the chain
+                    None => try!(self.alloc_cluster(Some(cluster))),
+                };
+                index += 1;
+            }
+
+            // only write up to the end of the current sector
+            let (i, offset) = ((pos % csize) / 512, pos % 512);
+            let len = min(data.len(), 512 - offset);
+            let mut sector = if len == 512 {
+                EMPTY_SECTOR
+            } else {
+                try!(self.read_cluster(cluster, i)).clone()
+            };
+            copy_memory(&data[..len], &mut sector[offset..offset + len]);
+            try!(self.write_cluster(cluster, i, &sector));
+
+            pos += len;
+            data = &data[len..];
+        }
+        stream.size = max(stream.size, pos);
+        stream.valid = stream.size;
+
+        listed.set.stream = stream;
+        listed.set.attrib.insert(ARCHIVE);
+        listed.set.times.touch(self.clock.now());
+        self.write_set(&dir.stream, &listed)
+    }
+
+    fn append(&mut self, path: &Path, buf: &[u8]) -> Result<()> {
+        let set = try!(self.find_set(path)).1.set;
+        if set.is_dir() {
+            return Err(Error::NotAFile(path.to_owned()))
+        }
+        self.write_at(path, set.stream.size, buf)
+    }
+
+    fn read_file(&mut self, path: &Path, buf: &mut [u8]) -> Result<usize> {
+        let set = try!(self.find_set(path)).1.set;
+        if set.is_dir() {
+            return Err(Error::NotAFile(path.to_owned()))
+        }
+
+        let size = set.stream.size;
+        if buf.len() < size {
+            return Err(Error::BufferTooSmall(size))
+        }
+        try!(self.read_stream(&set.stream, &mut buf[..size]));
+        Ok(size)
+    }
+}
+
+/// An open file on an `ExFat` filesystem
+///
+/// The current cluster is cached so sequential reads and writes don't walk
+/// the chain from the start each time. The entry set is updated on
+/// `flush()` or when the handle is dropped.
+pub struct ExFatFile<'a> {
+    fs: &'a mut ExFat,
+    mode: Mode,
+    dir: Stream, // listing holding the file's entry set
+    listed: Listed, // the file's entry set, whose stream is kept up to date
+    cluster: usize, // current cluster, 0 until the file has any
+    index: usize, // position of `cluster` within the chain
+    pos: usize, // current offset in bytes
+    dirty: bool, // whether the file was written since the entry set was updated
+}
+
+impl<'a> ExFatFile<'a> {
+    /// Move `cluster` to the `index`th cluster of the file
+    ///
+    /// If `extend` is set, the chain will be grown as necessary.
+    fn seek_cluster(&mut self, index: usize, extend: bool) -> Result<()> {
+        let stream = self.listed.set.stream;
+        if stream.start == 0 {
+            if !extend {
+                return Err(Error::CorruptFAT)
+            }
+            self.listed.set.stream.start = try!(self.fs.alloc_cluster(None));
+            self.cluster = self.listed.set.stream.start;
+            self.index = 0;
+        } else if index < self.index {
+            // chains can only be followed forwards
+            self.cluster = stream.start;
+            self.index = 0;
+        }
+        while self.index < index {
+            self.cluster = match try!(self.fs.next_cluster(&stream, self.cluster)) {
+                Some(c) => c,
+                None if extend => try!(self.fs.alloc_cluster(Some(self.cluster))),
+                // chain can't be shorter than the size in the entry set
+                None => try!(self.fs.expect_next(&stream, self.cluster)),
+            };
+            self.index += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Read for ExFatFile<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::slice::bytes::copy_memory;
+
+        if self.mode == Mode::Write {
+            return Err(io::Error::new(io::ErrorKind::Other, "file not opened for reading"))
+        }
+        let stream = self.listed.set.stream;
+        if self.pos >= stream.size || buf.len() == 0 {
+            return Ok(0)
+        }
+        if self.pos >= stream.valid {
+            // never written, reads as zero
+            let len = min(buf.len(), stream.size - self.pos);
+            for b in &mut buf[..len] {
+                *b = 0;
+            }
+            self.pos += len;
+            return Ok(len)
+        }
+
+        let csize = self.fs.cluster_size * 512;
+        try!(self.seek_cluster(self.pos / csize, false));
+
+        // only read up to the end of the current sector
+        let (i, offset) = ((self.pos % csize) / 512, self.pos % 512);
+        let len = min(buf.len(), min(512 - offset, stream.valid - self.pos));
+        let sector = try!(self.fs.read_cluster(self.cluster, i));
+        copy_memory(&sector[offset..offset + len], &mut buf[..len]);
+
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl<'a> Write for ExFatFile<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        use std::slice::bytes::copy_memory;
+
+        if self.mode == Mode::Read {
+            return Err(io::Error::new(io::ErrorKind::Other, "file not opened for writing"))
+        }
+        if buf.len() == 0 {
+            return Ok(0)
+        }
+
+        let csize = self.fs.cluster_size * 512;
+        try!(self.seek_cluster(self.pos / csize, true));
+
+        // only write up to the end of the current sector
+        let (i, offset) = ((self.pos % csize) / 512, self.pos % 512);
+        let len = min(buf.len(), 512 - offset);
+        let mut sector = try!(self.fs.read_cluster(self.cluster, i)).clone();
+        copy_memory(&buf[..len], &mut sector[offset..offset + len]);
+        try!(self.fs.write_cluster(self.cluster, i, &sector));
+
+        self.pos += len;
+        let stream = &mut self.listed.set.stream;
+        if self.pos > stream.size {
+            stream.size = self.pos;
+            stream.valid = self.pos;
+        }
+        self.dirty = true;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(())
+        }
+
+        self.listed.set.attrib.insert(ARCHIVE);
+        self.listed.set.times.touch(self.fs.clock.now());
+        try!(self.fs.write_set(&self.dir, &self.listed));
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+impl<'a> Seek for ExFatFile<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => self.listed.set.stream.size as i64 + n,
+            SeekFrom::Current(n) => self.pos as i64 + n,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"))
+        }
+
+        self.pos = pos as usize;
+        Ok(self.pos as u64)
+    }
+}
+
+impl<'a> Handle for ExFatFile<'a> { }
+
+impl<'a> Drop for ExFatFile<'a> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Whether `name` may be stored in a directory
+///
+/// Names are up to 255 UTF-16 characters, without control characters or
+/// the characters reserved by VFAT. `.` and `..` only have their special
+/// meaning in paths.
+fn is_valid_name(name: &str) -> bool {
+    let reserved = |c: char| c < ' ' || "\"*/:<>?\\|".contains(c);
+    let len = name.encode_utf16().count();
+    len > 0 && len <= 255 && !name.chars().any(reserved) && name != "." && name != ".."
+}
+
+/// A 64 bit length from an entry, which must fit in a usize
+fn length_at(raw: &[u8], i: usize) -> Result<usize> {
+    let n = (&raw[i..i + 8]).read_u64::<LittleEndian>().unwrap();
+    if n > ::std::usize::MAX as u64 {
+        return Err(Error::FileTooLarge)
+    }
+    Ok(n as usize)
+}
+
+/// Convert a timestamp to UTC using its UtcOffset field
+///
+/// The offset counts 15 minute intervals as a signed 7 bit number. If it
+/// isn't marked valid the time zone is unknown and the time is kept as is.
+fn to_utc(dt: DateTime, offset: u8) -> DateTime {
+    if offset & UTC_OFFSET_VALID == 0 {
+        return dt
+    }
+    let minutes = ((offset << 1) as i8 >> 1) as i64 * 15;
+    clock::from_unix((clock::to_unix(&dt) as i64 - minutes * 60) as u64)
+}
+
+/// Checksum of an entry set, skipping the field of the File entry which
+/// holds it
+fn set_checksum(raw: &[RawEntry]) -> u16 {
+    let mut checksum = 0u16;
+    for (i, &b) in raw.iter().flat_map(|entry| entry.iter()).enumerate() {
+        if i == 2 || i == 3 {
+            continue
+        }
+        checksum = checksum.rotate_right(1).wrapping_add(b as u16);
+    }
+    checksum
+}
+
+/// Checksum of the boot region, stored throughout the sector after it
+///
+/// The volume flags and percent in use are skipped so they can change
+/// without the checksum being recomputed.
+fn boot_checksum(region: &[Sector]) -> u32 {
+    let mut checksum = 0u32;
+    for (i, &b) in region.iter().flat_map(|sector| sector.iter()).enumerate() {
+        if i == 106 || i == 107 || i == 112 {
+            continue
+        }
+        checksum = checksum.rotate_right(1).wrapping_add(b as u32);
+    }
+    checksum
+}
+
+/// Checksum of the up-case table, stored in its directory entry
+fn table_checksum(table: &[u8]) -> u32 {
+    table.iter().fold(0u32, |checksum, &b| checksum.rotate_right(1).wrapping_add(b as u32))
+}
+
+/// Read one copy of the boot region, beginning at `base`, and verify its checksum
+fn read_boot_region(disk: &Disk, base: usize) -> Result<BootSector> {
+    let boot = try!(BootSector::parse(try!(disk.read_sector(base))));
+
+    let mut region = Vec::with_capacity(BOOT_SECTORS);
+    for i in 0..BOOT_SECTORS {
+        region.push(*try!(disk.read_sector(base + i)));
+    }
+    let checksum = boot_checksum(&region);
+    let sector = try!(disk.read_sector(base + BOOT_SECTORS));
+    if sector.chunks(4).any(|n| (&n[..]).read_u32::<LittleEndian>().unwrap() != checksum) {
+        return Err(Error::CorruptBPB("boot region checksum"))
+    }
+    Ok(boot)
+}
+
+/// Expand an up-case table read from disk
+///
+/// The table is indexed by character. A 0xFFFF entry is followed by a
+/// count of characters which are their own upper case, and so are left
+/// out of the table.
+fn load_upcase(table: &[u8]) -> Vec<u16> {
+    let mut chars = table.chunks(2).filter(|c| c.len() == 2)
+                                   .map(|c| (&c[..]).read_u16::<LittleEndian>().unwrap());
+    let mut upcase = vec![];
+    while let Some(c) = chars.next() {
+        match (c, if c == 0xFFFF { chars.next() } else { None }) {
+            (_, Some(skip)) => {
+                for _ in 0..skip {
+                    let c = upcase.len() as u16;
+                    upcase.push(c);
+                }
+            },
+            (c, None) => upcase.push(c),
+        }
+    }
+    upcase.truncate(0x10000);
+    upcase
+}
+
+/// The up-case table written by `format()`
+///
+/// Only ASCII letters are mapped, which is enough for the names we store.
+/// Every character past the end of the table is its own upper case.
+fn ascii_upcase_table() -> Vec<u8> {
+    let mut table = Vec::with_capacity(UPCASE_CHARS * 2);
+    for c in 0..UPCASE_CHARS as u16 {
+        let upper = if c >= b'a' as u16 && c <= b'z' as u16 { c - 32 } else { c };
+        table.write_u16::<LittleEndian>(upper).unwrap();
+    }
+    table
+}
+
+/// Options for `format_with()`
+///
+/// Anything left as `None` is picked based on the disk.
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    /// Sectors per cluster, a power of two no greater than 65536
+    pub cluster_size: Option<usize>,
+    /// Serial number of the volume, by default made from the current time
+    pub volume_id: Option<u32>,
+}
+
+/// Format drive as an exFAT filesystem
+///
+/// This will overwrite both copies of the boot region, the FAT, and the
+/// clusters of the allocation bitmap, up-case table and root directory.
+/// The rest of the cluster heap is left intact, clusters are zeroed as
+/// they're allocated.
+pub fn format<T: Disk>(disk: &mut T) -> Result<()> {
+    format_with(disk, &FormatOptions::default())
+}
+
+/// Format drive as an exFAT filesystem, see `format()`
+pub fn format_with<T: Disk>(disk: &mut T, options: &FormatOptions) -> Result<()> {
+    use std::slice::bytes::copy_memory;
+
+    let info = disk.info();
+    let dsize = info.size;
+    assert!(dsize >= MIN_SECTORS, "Unable to fit exFAT on a disk of {} sectors", dsize);
+    let csize = options.cluster_size.unwrap_or_else(|| pick_cluster_size(dsize));
+    assert!(csize.is_power_of_two() && csize <= 1 << 16, "Invalid cluster size: {}", csize);
+
+    // the FAT follows the backup boot region, sized for every cluster
+    // which could fit after it
+    let fat_offset = 2 * BOOT_REGION;
+    let fat_length = (((dsize - fat_offset) / csize + 2) * 4 + 511) / 512;
+    // the cluster heap is aligned to the cluster size
+    let heap_offset = (fat_offset + fat_length + csize - 1) / csize * csize;
+    let clusters = min((dsize - heap_offset) / csize, MAX_CLUSTERS);
+
+    // the allocation bitmap comes first, then the up-case table and the
+    // root directory, one cluster each
+    let cbytes = csize * 512;
+    let bitmap_bytes = (clusters + 7) / 8;
+    let bitmap_clusters = (bitmap_bytes + cbytes - 1) / cbytes;
+    let upcase_cluster = 2 + bitmap_clusters;
+    let rdir_cluster = upcase_cluster + 1;
+    let used = bitmap_clusters + 2;
+    assert!(used < clusters, "Unable to fit exFAT on a disk of {} sectors", dsize);
+    debug!("format dsize={} csize={} clusters={} fat_length={}", dsize, csize, clusters, fat_length);
+
+    let volume_id = options.volume_id.unwrap_or_else(|| {
+        // like DOS, make the volume ID from the time of formatting
+        let (date, time, fine) = encode_datetime(&SystemClock.now());
+        ((date as u32) << 16 | time as u32).wrapping_add(fine as u32)
+    });
+
+    let mut region = vec![EMPTY_SECTOR; BOOT_SECTORS];
+    {
+        let header = &mut region[0];
+        copy_memory(&[0xEB, 0x76, 0x90], &mut header[0..3]); // jmp over the header to offset 120
+        copy_memory(b"EXFAT   ", &mut header[3..11]); // filesystem name
+        // bytes 11 to 64 are left zero, where FAT keeps its BPB
+        let _ = (&mut header[64..72]).write_u64::<LittleEndian>(info.start as u64); // partition offset
+        let _ = (&mut header[72..80]).write_u64::<LittleEndian>(dsize as u64); // volume length
+        let _ = (&mut header[80..84]).write_u32::<LittleEndian>(fat_offset as u32);
+        let _ = (&mut header[84..88]).write_u32::<LittleEndian>(fat_length as u32);
+        let _ = (&mut header[88..92]).write_u32::<LittleEndian>(heap_offset as u32); // cluster heap offset
+        let _ = (&mut header[92..96]).write_u32::<LittleEndian>(clusters as u32); // cluster count
+        let _ = (&mut header[96..100]).write_u32::<LittleEndian>(rdir_cluster as u32); // root directory cluster
+        let _ = (&mut header[100..104]).write_u32::<LittleEndian>(volume_id); // volume serial number
+        let _ = (&mut header[104..106]).write_u16::<LittleEndian>(0x0100); // revision 1.0
+        let _ = (&mut header[106..108]).write_u16::<LittleEndian>(0); // volume flags: first FAT, clean
+        header[108] = 9; // bytes per sector, as a power of two
+        header[109] = csize.trailing_zeros() as u8; // sectors per cluster, as a power of two
+        header[110] = 1; // number of FATs
+        header[111] = 0x80; // drive select: first hard disk
+        header[112] = 0xFF; // percent in use: not tracked
+        copy_memory(&[0x55, 0xAA], &mut header[510..512]);
+    }
+    // the extended boot sectors are empty apart from their signature,
+    // the OEM parameters and reserved sector are left zero
+    for sector in &mut region[1..9] {
+        copy_memory(&[0x00, 0x00, 0x55, 0xAA], &mut sector[508..512]);
+    }
+    let checksum = boot_checksum(&region);
+    let mut sum = Sector([0; 512]);
+    for i in 0..128 {
+        let _ = (&mut sum[i * 4..i * 4 + 4]).write_u32::<LittleEndian>(checksum);
+    }
+
+    // the backup boot region directly follows the main one
+    for &base in &[0, BOOT_REGION] {
+        for (i, sector) in region.iter().enumerate() {
+            try!(disk.write_sector(base + i, sector));
+        }
+        try!(disk.write_sector(base + BOOT_SECTORS, &sum));
+    }
+
+    // zero out the FAT, then chain together the clusters in use
+    // the first two entries are reserved
+    let mut fat = vec![MEDIA_ENTRY, END_OF_CHAIN];
+    for c in 2..upcase_cluster {
+        fat.push(if c + 1 < upcase_cluster { c as u32 + 1 } else { END_OF_CHAIN });
+    }
+    fat.push(END_OF_CHAIN); // up-case table
+    fat.push(END_OF_CHAIN); // root directory
+    for i in 0..fat_length {
+        try!(disk.write_sector(fat_offset + i, &EMPTY_SECTOR));
+    }
+    for (i, entries) in fat.chunks(128).enumerate() {
+        let mut sector = Sector([0; 512]);
+        for (j, &entry) in entries.iter().enumerate() {
+            let _ = (&mut sector[j * 4..j * 4 + 4]).write_u32::<LittleEndian>(entry);
+        }
+        try!(disk.write_sector(fat_offset + i, &sector));
+    }
+
+    let mut bitmap = vec![0; bitmap_clusters * cbytes];
+    for c in 0..used {
+        bitmap[c / 8] |= 1 << (c % 8);
+    }
+    let table = ascii_upcase_table();
+    let mut upcase = vec![0; cbytes];
+    copy_memory(&table, &mut upcase);
+
+    // the root directory begins with the allocation bitmap, up-case
+    // table, and an empty volume label
+    let mut root = vec![0; cbytes];
+    root[0] = BITMAP_ENTRY;
+    let _ = (&mut root[20..24]).write_u32::<LittleEndian>(2);
+    let _ = (&mut root[24..32]).write_u64::<LittleEndian>(bitmap_bytes as u64);
+    root[32] = UPCASE_ENTRY;
+    let _ = (&mut root[36..40]).write_u32::<LittleEndian>(table_checksum(&table));
+    let _ = (&mut root[52..56]).write_u32::<LittleEndian>(upcase_cluster as u32);
+    let _ = (&mut root[56..64]).write_u64::<LittleEndian>(table.len() as u64);
+    root[64] = LABEL_ENTRY;
+
+    for &(start, data) in &[(2, &bitmap), (upcase_cluster, &upcase), (rdir_cluster, &root)] {
+        let lba = heap_offset + (start - 2) * csize;
+        for (i, sector) in data.chunks(512).enumerate() {
+            try!(disk.write_sector(lba + i, sector));
+        }
+    }
+
+    Ok(())
+}
+
+/// Pick a cluster size in sectors for a disk of `dsize` sectors
+fn pick_cluster_size(dsize: usize) -> usize {
+    match dsize {
+        0...524287 => 8, // up to 256MB, 4KiB clusters
+        524288...67108863 => 64, // up to 32GB, 32KiB clusters
+        _ => 256, // 128KiB clusters
+    }
+}
+
+/// Sectors of the boot region which the checksum covers
+const BOOT_SECTORS: usize = 11;
+/// Sectors in each copy of the boot region, including the checksum
+const BOOT_REGION: usize = 12;
+/// Smallest volume exFAT allows, 1MiB
+const MIN_SECTORS: usize = 2048;
+/// Most clusters a volume may have
+const MAX_CLUSTERS: usize = 0xFFFFFFF5;
+
+/// FAT entry 0, which holds the media type
+const MEDIA_ENTRY: u32 = 0xFFFFFFF8;
+const END_OF_CHAIN: u32 = 0xFFFFFFFF;
+
+// Entry types, an entry is unused if `IN_USE` is cleared
+const END_OF_DIR: u8 = 0x00;
+const IN_USE: u8 = 0x80;
+const BITMAP_ENTRY: u8 = 0x81;
+const UPCASE_ENTRY: u8 = 0x82;
+const LABEL_ENTRY: u8 = 0x83;
+const FILE_ENTRY: u8 = 0x85;
+const STREAM_ENTRY: u8 = 0xC0;
+const NAME_ENTRY: u8 = 0xC1;
+
+/// Set in a File entry's UtcOffset fields when the offset is known
+const UTC_OFFSET_VALID: u8 = 0x80;
+
+// Stream Extension flags
+const ALLOCATION_POSSIBLE: u8 = 0x01;
+const NO_FAT_CHAIN: u8 = 0x02;
+
+/// Characters mapped by the up-case table `format()` writes
+const UPCASE_CHARS: usize = 128;
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write, Seek, SeekFrom};
+    use std::path::Path;
+
+    use disk::{Disk, RamDisk, Error, Sector};
+    use fs::{FileSystem, FixedClock, DateTime, Entry, FileType, Mode, attributes};
+    use super::{ExFat, EntrySet, FormatOptions, format, format_with, boot_checksum, load_upcase, set_checksum,
+                table_checksum, ascii_upcase_table, to_utc, NO_FAT_CHAIN};
+
+    fn ramdisk_fs(size: usize) -> ExFat {
+        let mut disk = RamDisk::new(size);
+        format(&mut disk).unwrap();
+        ExFat::new(Box::new(disk)).unwrap()
+    }
+
+    #[test]
+    fn boot_region() {
+        let mut disk = RamDisk::new(4096);
+        format_with(&mut disk, &FormatOptions { volume_id: Some(0x12345678), ..FormatOptions::default() }).unwrap();
+
+        let header = disk.read_sector(0).unwrap().clone();
+        assert_eq!(&header[0..11], b"\xEB\x76\x90EXFAT   ");
+        assert!(header[11..64].iter().all(|&b| b == 0));
+        assert_eq!(&header[72..80], &[0x00, 0x10, 0, 0, 0, 0, 0, 0]); // volume length
+        assert_eq!(&header[80..84], &[24, 0, 0, 0]); // FAT offset, after the backup boot region
+        assert_eq!(&header[100..104], &[0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(&header[104..106], &[0x00, 0x01]); // revision 1.0
+        assert_eq!(header[108], 9);
+        assert_eq!(header[109], 3); // 4KiB clusters
+        assert_eq!(header[110], 1);
+        assert_eq!(&header[510..512], &[0x55, 0xAA]);
+
+        // the checksum sector and backup match the boot region
+        let region = (0..11).map(|i| *disk.read_sector(i).unwrap()).collect::<Vec<Sector>>();
+        let checksum = boot_checksum(&region);
+        assert_eq!(&disk.read_sector(11).unwrap()[508..512], &[checksum as u8, (checksum >> 8) as u8,
+                                                              (checksum >> 16) as u8, (checksum >> 24) as u8]);
+        for i in 0..12 {
+            assert_eq!(&disk.read_sector(i).unwrap()[..], &disk.read_sector(12 + i).unwrap()[..]);
+        }
+
+        // the percent in use isn't covered by the checksum
+        let mut sector = header;
+        sector[112] = 50;
+        disk.write_sector(0, &sector).unwrap();
+        assert!(ExFat::new(Box::new(disk)).is_ok());
+
+        // but a damaged boot sector falls back to the backup
+        let mut disk = RamDisk::new(4096);
+        format(&mut disk).unwrap();
+        let mut sector = disk.read_sector(0).unwrap().clone();
+        sector[96] = 0xFF; // root directory cluster
+        disk.write_sector(0, &sector).unwrap();
+        let mut fs = ExFat::new(Box::new(disk)).unwrap();
+        fs.write_file(Path::new("kernel.bin"), b"kernel").unwrap();
+
+        // and if both are damaged, mounting fails
+        let mut disk = RamDisk::new(4096);
+        format(&mut disk).unwrap();
+        for &lba in &[0, 12] {
+            disk.write_sector(lba, &sector).unwrap();
+        }
+        assert_eq!(ExFat::new(Box::new(disk)).err(), Some(Error::CorruptBPB("boot region checksum")));
+
+        // a FAT volume isn't mistaken for exFAT
+        let mut disk = RamDisk::new(4096);
+        ::fs::fat::format(&mut disk).unwrap();
+        assert_eq!(ExFat::new(Box::new(disk)).err(), Some(Error::CorruptBPB("not an exFAT boot sector")));
+    }
+
+    #[test]
+    fn system_files() {
+        let mut fs = ramdisk_fs(4096);
+        // the bitmap, up-case table and root directory each need one cluster
+        let clusters = fs.used.len() - 2;
+        assert_eq!(fs.free_count, clusters - 3);
+        assert_eq!(fs.bitmap, vec![2]);
+        assert_eq!(fs.rdir_cluster, 4);
+        assert_eq!(fs.upcase('a' as u16), 'A' as u16);
+        assert_eq!(fs.upcase(0xE9), 0xE9); // past the end of the table
+        let table = ascii_upcase_table();
+        assert_eq!(table.len(), 256);
+        let raw = fs.read_listing(&fs.root().stream).unwrap();
+        assert_eq!(&raw[1][4..8], &[table_checksum(&table) as u8, (table_checksum(&table) >> 8) as u8,
+                                   (table_checksum(&table) >> 16) as u8, (table_checksum(&table) >> 24) as u8]);
+        assert_eq!(fs.read_dir(Path::new("/")).unwrap().count(), 0);
+
+        // allocations are recorded in the bitmap on disk
+        fs.write_file(Path::new("kernel.bin"), &[1; 5000]).unwrap();
+        let fs = ExFat::new(fs.disk).unwrap();
+        assert_eq!(fs.free_count, clusters - 5);
+        assert!(fs.used.get(5) && fs.used.get(6) && !fs.used.get(7));
+    }
+
+    #[test]
+    fn upcase_table() {
+        // 'a' maps to 'A', then 0xFFFF skips the next 3 characters
+        let table = [0x41, 0x00, 0xFF, 0xFF, 0x03, 0x00, 0x43, 0x00];
+        assert_eq!(load_upcase(&table), vec![0x41, 1, 2, 3, 0x43]);
+        assert_eq!(load_upcase(&ascii_upcase_table())[b'z' as usize], b'Z' as u16);
+    }
+
+    #[test]
+    fn read_file() {
+        let mut fs = ramdisk_fs(4096);
+        let data = (0..20000).map(|i| i as u8).collect::<Vec<u8>>();
+        fs.write_file(Path::new("kernel.bin"), &data).unwrap();
+        fs.write_file(Path::new("empty.bin"), &[]).unwrap();
+
+        let mut buf = vec![0; 20000];
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(20000));
+        assert_eq!(buf, data);
+        assert_eq!(fs.read_file(Path::new("empty.bin"), &mut buf), Ok(0));
+
+        let mut small = vec![0; 100];
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut small), Err(Error::BufferTooSmall(20000)));
+        assert_eq!(fs.read_file(Path::new("missing.bin"), &mut buf),
+                   Err(Error::Nonexistent(Path::new("missing.bin").to_owned())));
+
+        // empty files have no clusters
+        let entries = fs.read_dir(Path::new("/")).unwrap().collect::<Vec<_>>();
+        assert_eq!(entries[1], Ok(Entry { name: "empty.bin".to_owned(), kind: FileType::File, size: 0, start: 0 }));
+
+        // a smaller overwrite frees the rest of the chain
+        let free = fs.free_count;
+        fs.write_file(Path::new("kernel.bin"), &data[..100]).unwrap();
+        assert_eq!(fs.free_count, free + 4);
+        let mut fs = ExFat::new(fs.disk).unwrap();
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(100));
+        assert_eq!(&buf[..100], &data[..100]);
+    }
+
+    #[test]
+    fn entry_sets() {
+        let mut fs = ramdisk_fs(4096);
+        // needs three File Name entries
+        let name = "a rather long file name, for exFAT.txt";
+        fs.write_file(Path::new(name), b"hello").unwrap();
+
+        let root = fs.root();
+        let raw = fs.read_listing(&root.stream).unwrap();
+        // the bitmap, up-case table and volume label come first
+        let set = &raw[3..];
+        assert_eq!(set.len(), 5);
+        assert_eq!((set[0][0], set[0][1], set[1][0], set[1][3]), (0x85, 4, 0xC0, name.len() as u8));
+        assert!(set[2..].iter().all(|entry| entry[0] == 0xC1));
+        let checksum = set_checksum(set);
+        assert_eq!(&set[0][2..4], &[checksum as u8, (checksum >> 8) as u8]);
+        let hash = fs.name_hash(&name.to_uppercase());
+        assert_eq!(&set[1][4..6], &[hash as u8, (hash >> 8) as u8]);
+
+        // names compare using the up-case table
+        let mut buf = [0; 5];
+        assert_eq!(fs.read_file(Path::new(&name.to_uppercase()), &mut buf), Ok(5));
+        assert_eq!(fs.write_file(Path::new("bad?.txt"), b""), Err(Error::InvalidPath));
+        let long = (0..256).map(|_| "x").collect::<String>();
+        assert_eq!(fs.write_file(Path::new(&long), b""), Err(Error::InvalidPath));
+        fs.write_file(Path::new(&long[..255]), b"").unwrap();
+
+        // a size of 4GiB or more only fits where usize has 64 bits
+        let listed = fs.find_listed(&root.stream, Path::new(name)).unwrap().unwrap();
+        let mut big = fs.get_entries(&root.stream, listed.index, listed.count).unwrap();
+        big[1][28] = 1; // DataLength
+        big[1][12] = 1; // ValidDataLength
+        let checksum = set_checksum(&big);
+        big[0][2] = checksum as u8;
+        big[0][3] = (checksum >> 8) as u8;
+        let size = EntrySet::decode(&big).map(|set| set.unwrap().stream.size as u64);
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(size, Ok(5 | 1 << 32));
+        } else {
+            assert_eq!(size, Err(Error::FileTooLarge));
+        }
+
+        // a set whose checksum doesn't match is skipped
+        let mut damaged = fs.get_entries(&root.stream, listed.index + 2, 1).unwrap();
+        damaged[0][2] = b'A';
+        fs.set_entries(&root.stream, listed.index + 2, &damaged).unwrap();
+        assert!(fs.metadata(Path::new(name)).is_err());
+        assert_eq!(fs.read_dir(Path::new("/")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn timestamps() {
+        let now = DateTime { year: 2015, month: 7, day: 4, hour: 13, minute: 37, second: 43 };
+        let mut disk = RamDisk::new(4096);
+        format(&mut disk).unwrap();
+        let mut fs = ExFat::with_clock(Box::new(disk), Box::new(FixedClock(now))).unwrap();
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.write_file(Path::new("boot/kernel.bin"), &[0; 10]).unwrap();
+
+        // unlike FAT the time of access is kept, but only the creation and
+        // modification times have 10ms fields for the odd seconds
+        let meta = fs.metadata(Path::new("boot/kernel.bin")).unwrap();
+        let accessed = DateTime { second: 42, ..now };
+        assert_eq!((meta.created, meta.modified, meta.accessed), (Some(now), Some(now), Some(accessed)));
+        assert_eq!(meta.attributes, attributes::ARCHIVE);
+
+        // the stamps are marked as UTC, other offsets are converted to it
+        let (dir, listed) = fs.find_set(Path::new("boot/kernel.bin")).unwrap();
+        let raw = fs.get_entries(&dir.stream, listed.index, 1).unwrap();
+        assert_eq!(&raw[0][22..25], &[0x80, 0x80, 0x80]);
+        assert_eq!(to_utc(now, 0x80 | 8), DateTime { hour: 11, ..now }); // UTC+2
+        assert_eq!(to_utc(now, 0x80 | 0x7E), DateTime { hour: 14, minute: 7, ..now }); // UTC-0:30
+        assert_eq!(to_utc(now, 8), now);
+        let meta = fs.metadata(Path::new("boot")).unwrap();
+        assert_eq!((meta.kind, meta.size, meta.attributes), (FileType::Directory, 0, attributes::DIRECTORY));
+
+        fs.set_attributes(Path::new("boot"), attributes::HIDDEN).unwrap();
+        assert_eq!(fs.attributes(Path::new("boot")), Ok(attributes::DIRECTORY | attributes::HIDDEN));
+    }
+
+    #[test]
+    fn directories() {
+        let mut fs = ramdisk_fs(4096);
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.make_dir(Path::new("boot/grub")).unwrap();
+        fs.write_file(Path::new("boot/grub/grub.cfg"), b"menu").unwrap();
+        assert_eq!(fs.make_dir(Path::new("boot")), Err(Error::AlreadyExists(Path::new("boot").to_owned())));
+
+        // there are no `..` entries, but paths can still use them
+        let mut buf = [0; 4];
+        assert_eq!(fs.read_file(Path::new("boot/grub/../grub/./grub.cfg"), &mut buf), Ok(4));
+        assert_eq!(fs.read_file(Path::new("../boot/grub/grub.cfg"), &mut buf), Ok(4));
+        assert_eq!(fs.read_dir(Path::new("boot/grub/grub.cfg/..")).err(),
+                   Some(Error::NotADirectory(Path::new("boot/grub/grub.cfg").to_owned())));
+
+        assert_eq!(fs.delete(Path::new("boot/grub")),
+                   Err(Error::DirectoryNotEmpty(Path::new("boot/grub").to_owned())));
+        fs.delete(Path::new("boot/grub/grub.cfg")).unwrap();
+        fs.delete(Path::new("boot/grub")).unwrap();
+        assert_eq!(fs.read_dir(Path::new("boot")).unwrap().count(), 0);
+
+        // each set takes 3 entries, so the listing grows past one cluster
+        let free = fs.free_count;
+        for i in 0..60 {
+            fs.write_file(Path::new(&format!("boot/{}.bin", i)), &[]).unwrap();
+        }
+        assert_eq!(fs.free_count, free - 1);
+        let mut fs = ExFat::new(fs.disk).unwrap();
+        assert_eq!(fs.read_dir(Path::new("boot")).unwrap().count(), 60);
+        let root = fs.root();
+        let boot = fs.find_listed(&root.stream, Path::new("boot")).unwrap().unwrap();
+        assert_eq!((boot.set.stream.size, boot.set.stream.valid), (8192, 8192));
+    }
+
+    #[test]
+    fn open() {
+        let mut fs = ramdisk_fs(4096);
+        let data = (0..10000).map(|i| (i / 7) as u8).collect::<Vec<u8>>();
+        {
+            let mut file = fs.open(Path::new("kernel.bin"), Mode::Write).unwrap();
+            for chunk in data.chunks(300) {
+                file.write_all(chunk).unwrap();
+            }
+        }
+        let mut buf = vec![0; 10000];
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(10000));
+        assert_eq!(buf, data);
+
+        {
+            let mut file = fs.open(Path::new("kernel.bin"), Mode::ReadWrite).unwrap();
+            file.seek(SeekFrom::Start(5000)).unwrap();
+            file.write_all(b"patch").unwrap();
+            file.seek(SeekFrom::Start(4998)).unwrap();
+            let mut small = [0; 9];
+            file.read(&mut small).unwrap();
+            assert_eq!(&small[..], &[data[4998], data[4999], b'p', b'a', b't', b'c', b'h', data[5005], data[5006]][..]);
+            assert!(file.write(b"x").is_ok());
+        }
+
+        // writing truncates, reading can't write
+        {
+            let mut file = fs.open(Path::new("kernel.bin"), Mode::Write).unwrap();
+            file.write_all(b"short").unwrap();
+        }
+        let mut file = fs.open(Path::new("kernel.bin"), Mode::Read).unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "short");
+        assert!(file.write(b"x").is_err());
+    }
+
+    #[test]
+    fn truncate() {
+        let mut fs = ramdisk_fs(4096);
+        let data = (0..10000).map(|i| i as u8).collect::<Vec<u8>>();
+        fs.write_file(Path::new("kernel.bin"), &data).unwrap();
+        let free = fs.free_count;
+
+        fs.truncate(Path::new("kernel.bin"), 100).unwrap();
+        assert_eq!(fs.free_count, free + 2);
+        // growing again zero fills
+        fs.truncate(Path::new("kernel.bin"), 9000).unwrap();
+        let mut buf = vec![0; 9000];
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(9000));
+        assert_eq!(&buf[..100], &data[..100]);
+        assert!(buf[100..].iter().all(|&b| b == 0));
+
+        fs.truncate(Path::new("kernel.bin"), 0).unwrap();
+        assert_eq!(fs.free_count, free + 3);
+        assert_eq!(fs.metadata(Path::new("kernel.bin")).unwrap().size, 0);
+    }
+
+    #[test]
+    fn write_at() {
+        let mut fs = ramdisk_fs(4096);
+        fs.write_file(Path::new("log.txt"), b"tick\n").unwrap();
+        fs.append(Path::new("log.txt"), b"tock\n").unwrap();
+        fs.write_at(Path::new("log.txt"), 5, b"TICK\n").unwrap();
+        // leaves a zeroed gap across a cluster boundary
+        fs.write_at(Path::new("log.txt"), 5000, b"end").unwrap();
+
+        let mut buf = vec![0xAA; 5003];
+        assert_eq!(fs.read_file(Path::new("log.txt"), &mut buf), Ok(5003));
+        assert_eq!(&buf[..10], b"tick\nTICK\n");
+        assert!(buf[10..5000].iter().all(|&b| b == 0));
+        assert_eq!(&buf[5000..], b"end");
+
+        fs.write_file(Path::new("new.txt"), b"").unwrap();
+        fs.append(Path::new("new.txt"), b"first").unwrap();
+        assert_eq!(fs.read_file(Path::new("new.txt"), &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"first");
+    }
+
+    #[test]
+    fn no_fat_chain() {
+        let mut fs = ramdisk_fs(4096);
+        let data = (0..10000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs.write_file(Path::new("kernel.bin"), &data).unwrap();
+
+        // rewrite the file like other implementations store contiguous
+        // files, with nothing in the FAT and only part of it written
+        let root = fs.root();
+        let mut listed = fs.find_listed(&root.stream, Path::new("kernel.bin")).unwrap().unwrap();
+        let start = listed.set.stream.start;
+        for c in start..start + 3 {
+            fs.write_fat(c, 0).unwrap();
+        }
+        listed.set.stream.contiguous = true;
+        listed.set.stream.valid = 6000;
+        fs.write_set(&root.stream, &listed).unwrap();
+        let raw = fs.get_entries(&root.stream, listed.index + 1, 1).unwrap();
+        assert_eq!(raw[0][1] & NO_FAT_CHAIN, NO_FAT_CHAIN);
+
+        let mut buf = vec![0xAA; 10003];
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(10000));
+        assert_eq!(&buf[..6000], &data[..6000]);
+        assert!(buf[6000..10000].iter().all(|&b| b == 0));
+
+        // modifying the file records the chain in the FAT
+        fs.append(Path::new("kernel.bin"), b"end").unwrap();
+        let listed = fs.find_listed(&root.stream, Path::new("kernel.bin")).unwrap().unwrap();
+        assert!(!listed.set.stream.contiguous);
+        assert_eq!(fs.read_fat(start), Ok(start as u32 + 1));
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(10003));
+        assert!(buf[6000..10000].iter().all(|&b| b == 0));
+        assert_eq!(&buf[10000..10003], b"end");
+
+        let free = fs.free_count;
+        fs.delete(Path::new("kernel.bin")).unwrap();
+        assert_eq!(fs.free_count, free + 3);
+    }
+
+    #[test]
+    fn no_space() {
+        let mut fs = ramdisk_fs(2048);
+        let free = fs.free_count;
+        let data = vec![7; (free + 1) * 4096];
+
+        // too big to ever fit, nothing is left behind
+        assert_eq!(fs.write_file(Path::new("big.bin"), &data), Err(Error::NoSpace));
+        assert_eq!(fs.free_count, free);
+        assert!(fs.metadata(Path::new("big.bin")).is_err());
+
+        // fills the volume exactly
+        fs.write_file(Path::new("big.bin"), &data[..free * 4096]).unwrap();
+        assert_eq!(fs.free_count, 0);
+        assert_eq!(fs.make_dir(Path::new("boot")), Err(Error::NoSpace));
+        assert_eq!(fs.append(Path::new("big.bin"), &[1]), Err(Error::NoSpace));
+        assert_eq!(fs.truncate(Path::new("big.bin"), free * 4096 + 1), Err(Error::NoSpace));
+        fs.write_at(Path::new("big.bin"), 0, &[8; 100]).unwrap();
+
+        // a failed overwrite keeps the old contents
+        fs.write_file(Path::new("big.bin"), &data[..4096]).unwrap();
+        assert_eq!(fs.write_file(Path::new("big.bin"), &data), Err(Error::NoSpace));
+        let mut buf = vec![0; 4096];
+        assert_eq!(fs.read_file(Path::new("big.bin"), &mut buf), Ok(4096));
+
+        // the root directory can't grow once the volume is full
+        fs.write_file(Path::new("rest.bin"), &data[..(free - 1) * 4096]).unwrap();
+        let mut added = 0;
+        while fs.write_file(Path::new(&format!("{}.bin", added)), &[]).is_ok() {
+            added += 1;
+        }
+        assert_eq!(fs.write_file(Path::new("full.bin"), &[]), Err(Error::NoSpace));
+        assert!(added > 30);
+        fs.delete(Path::new("big.bin")).unwrap();
+        fs.delete(Path::new("rest.bin")).unwrap();
+        assert_eq!(fs.free_count, free);
+    }
+
+    #[test]
+    fn rename() {
+        let mut fs = ramdisk_fs(4096);
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.make_dir(Path::new("boot/grub")).unwrap();
+        fs.write_file(Path::new("kernel.bin"), b"kernel").unwrap();
+        fs.write_file(Path::new("boot/old.bin"), b"old").unwrap();
+
+        fs.rename(Path::new("kernel.bin"), Path::new("boot/a much longer kernel name.bin"), false).unwrap();
+        let mut buf = [0; 6];
+        assert_eq!(fs.read_file(Path::new("boot/a much longer kernel name.bin"), &mut buf), Ok(6));
+        assert!(fs.metadata(Path::new("kernel.bin")).is_err());
+
+        assert_eq!(fs.rename(Path::new("boot/old.bin"), Path::new("boot/a much longer kernel name.bin"), false),
+                   Err(Error::AlreadyExists(Path::new("boot/a much longer kernel name.bin").to_owned())));
+        fs.rename(Path::new("boot/old.bin"), Path::new("boot/a much longer kernel name.bin"), true).unwrap();
+        assert_eq!(fs.read_file(Path::new("boot/a much longer kernel name.bin"), &mut buf), Ok(3));
+
+        // directories keep their contents, but can't move inside themselves
+        assert_eq!(fs.rename(Path::new("boot"), Path::new("boot/grub/boot"), false), Err(Error::InvalidPath));
+        fs.rename(Path::new("boot/grub"), Path::new("grub"), false).unwrap();
+        fs.rename(Path::new("boot"), Path::new("grub/boot"), false).unwrap();
+        assert_eq!(fs.read_file(Path::new("grub/boot/a much longer kernel name.bin"), &mut buf), Ok(3));
+
+        // only changing the case
+        fs.rename(Path::new("grub"), Path::new("GRUB"), false).unwrap();
+        let names = fs.read_dir(Path::new("/")).unwrap().map(|e| e.unwrap().name).collect::<Vec<String>>();
+        assert_eq!(names, vec!["GRUB".to_owned()]);
+    }
+}
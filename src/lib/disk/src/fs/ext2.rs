@@ -0,0 +1,2401 @@
+use std::cmp::{max, min};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use disk::{Disk, DiskInfo, EMPTY_SECTOR, Result, Error};
+use fs::{FileSystem, Attributes, Clock, SystemClock, Entry, FileType, Handle, Metadata, Mode};
+use fs::attributes::{DIRECTORY, HIDDEN, READ_ONLY};
+use fs::bitmap::Bitmap;
+use fs::clock::{from_unix, to_unix};
+
+/// An ext2 filesystem
+///
+/// Unlike FAT, files carry Unix permissions and an owner, see
+/// `permissions()` and `owner()`. As on Linux, only the primary superblock
+/// and group descriptors are kept up to date, the backups are left as
+/// `format()` wrote them.
+pub struct Ext2 {
+    disk: Box<Disk>, // Underlying medium
+    sb: Superblock,
+    groups: Vec<Group>,
+    used_blocks: Bitmap, // Which blocks are in use, indexed by block number
+    used_inodes: Bitmap, // Which inodes are in use, indexed by inode number - 1
+    free_blocks: usize,
+    free_inodes: usize,
+    dirty_groups: Bitmap, // Groups whose free counts changed since they were written
+    clock: Box<Clock>, // Source of timestamps for inodes
+}
+
+/// The parameters in the superblock of an ext2 volume
+#[derive(Debug, Clone, PartialEq)]
+struct Superblock {
+    inodes: usize,
+    blocks: usize,
+    first_data_block: usize, // block holding the superblock, 1 for 1KiB blocks otherwise 0
+    block_size: usize, // in bytes
+    blocks_per_group: usize,
+    inodes_per_group: usize,
+    revision: u32,
+    first_ino: usize, // first inode which isn't reserved
+    inode_size: usize, // in bytes
+    incompat: u32, // features which must be understood to mount the volume
+    ro_compat: u32, // features which must be understood to write to the volume
+}
+
+impl Superblock {
+    /// Parse a superblock, making sure it belongs to an ext2 volume
+    fn parse(raw: &[u8]) -> Result<Superblock> {
+        if u16_at(raw, 56) != MAGIC {
+            return Err(Error::CorruptBPB("not an ext2 superblock"))
+        }
+        let log_block_size = u32_at(raw, 24);
+        if log_block_size > 5 {
+            return Err(Error::CorruptBPB("block size"))
+        }
+
+        let revision = u32_at(raw, 76);
+        // revision 0 has fixed size inodes and no feature flags
+        let (first_ino, inode_size, incompat, ro_compat) = if revision == 0 {
+            (11, 128, 0, 0)
+        } else {
+            (u32_at(raw, 84) as usize, u16_at(raw, 88) as usize, u32_at(raw, 96), u32_at(raw, 100))
+        };
+        Ok(Superblock {
+            inodes: u32_at(raw, 0) as usize,
+            blocks: u32_at(raw, 4) as usize,
+            first_data_block: u32_at(raw, 20) as usize,
+            block_size: 1024 << log_block_size,
+            blocks_per_group: u32_at(raw, 32) as usize,
+            inodes_per_group: u32_at(raw, 40) as usize,
+            revision: revision,
+            first_ino: first_ino,
+            inode_size: inode_size,
+            incompat: incompat,
+            ro_compat: ro_compat,
+        })
+    }
+
+    /// Make sure the volume fits on the disk and its features are supported
+    fn validate(&self, info: &DiskInfo) -> Result<()> {
+        if self.revision > 1 {
+            return Err(Error::CorruptBPB("revision"))
+        }
+        if self.incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(Error::CorruptBPB("incompatible features"))
+        }
+        if self.ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE) != 0 {
+            return Err(Error::CorruptBPB("read-only compatible features"))
+        }
+        if self.first_data_block != if self.block_size == 1024 { 1 } else { 0 } {
+            return Err(Error::CorruptBPB("first data block"))
+        }
+        if self.blocks <= self.first_data_block || self.blocks * (self.block_size / 512) > info.size {
+            return Err(Error::CorruptBPB("block count"))
+        }
+        // each group's bitmaps are a single block
+        let bits = self.block_size * 8;
+        if self.blocks_per_group == 0 || self.blocks_per_group > bits {
+            return Err(Error::CorruptBPB("blocks per group"))
+        }
+        if self.inodes_per_group == 0 || self.inodes_per_group > bits ||
+           self.inodes != self.inodes_per_group * self.groups() {
+            return Err(Error::CorruptBPB("inodes per group"))
+        }
+        if self.inode_size < 128 || !self.inode_size.is_power_of_two() || self.inode_size > self.block_size {
+            return Err(Error::CorruptBPB("inode size"))
+        }
+        if self.first_ino <= ROOT_INO as usize || self.first_ino > self.inodes {
+            return Err(Error::CorruptBPB("first inode"))
+        }
+
+        Ok(())
+    }
+
+    fn groups(&self) -> usize {
+        (self.blocks - self.first_data_block + self.blocks_per_group - 1) / self.blocks_per_group
+    }
+}
+
+/// A block group descriptor, with free counts taken from the bitmaps
+#[derive(Debug, Clone, PartialEq)]
+struct Group {
+    block_bitmap: usize,
+    inode_bitmap: usize,
+    inode_table: usize, // first block of the table
+    free_blocks: usize,
+    free_inodes: usize,
+    dirs: usize, // number of directories
+}
+
+/// The fields of an inode which are used, the rest are left alone when
+/// it's written back
+#[derive(Debug, Clone, PartialEq)]
+struct Inode {
+    mode: u16, // file type and permissions
+    uid: u32,
+    gid: u32,
+    size: usize, // in bytes
+    atime: u32, // last accessed, in seconds since the Unix epoch
+    ctime: u32, // inode last changed
+    mtime: u32, // contents last modified
+    dtime: u32, // deleted
+    links: u16,
+    sectors: usize, // 512 byte units in use, including indirect blocks
+    flags: u32,
+    block: [u32; 15], // direct blocks, then single, double and triple indirect
+    file_acl: u32, // block of extended attributes
+}
+
+impl Inode {
+    fn new(mode: u16, now: u32) -> Inode {
+        Inode {
+            mode: mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: now,
+            ctime: now,
+            mtime: now,
+            dtime: 0,
+            links: 1,
+            sectors: 0,
+            flags: 0,
+            block: [0; 15],
+            file_acl: 0,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    fn decode(raw: &[u8]) -> Result<Inode> {
+        let mut block = [0; 15];
+        for (i, ptr) in block.iter_mut().enumerate() {
+            *ptr = u32_at(raw, 40 + i * 4);
+        }
+        let mut inode = Inode {
+            mode: u16_at(raw, 0),
+            uid: u16_at(raw, 2) as u32 | (u16_at(raw, 120) as u32) << 16,
+            gid: u16_at(raw, 24) as u32 | (u16_at(raw, 122) as u32) << 16,
+            size: u32_at(raw, 4) as usize,
+            atime: u32_at(raw, 8),
+            ctime: u32_at(raw, 12),
+            mtime: u32_at(raw, 16),
+            dtime: u32_at(raw, 20),
+            links: u16_at(raw, 26),
+            sectors: u32_at(raw, 28) as usize,
+            flags: u32_at(raw, 32),
+            block: block,
+            file_acl: u32_at(raw, 104),
+        };
+        // directories use the upper half of the size for something else
+        if inode.is_file() {
+            let size = inode.size as u64 | (u32_at(raw, 108) as u64) << 32;
+            if size > ::std::usize::MAX as u64 {
+                return Err(Error::FileTooLarge)
+            }
+            inode.size = size as usize;
+        }
+        Ok(inode)
+    }
+
+    /// Write the fields over the first 128 bytes of `raw`
+    fn encode(&self, raw: &mut [u8]) {
+        let _ = (&mut raw[0..2]).write_u16::<LittleEndian>(self.mode);
+        let _ = (&mut raw[2..4]).write_u16::<LittleEndian>(self.uid as u16);
+        let _ = (&mut raw[4..8]).write_u32::<LittleEndian>(self.size as u32);
+        let _ = (&mut raw[8..12]).write_u32::<LittleEndian>(self.atime);
+        let _ = (&mut raw[12..16]).write_u32::<LittleEndian>(self.ctime);
+        let _ = (&mut raw[16..20]).write_u32::<LittleEndian>(self.mtime);
+        let _ = (&mut raw[20..24]).write_u32::<LittleEndian>(self.dtime);
+        let _ = (&mut raw[24..26]).write_u16::<LittleEndian>(self.gid as u16);
+        let _ = (&mut raw[26..28]).write_u16::<LittleEndian>(self.links);
+        let _ = (&mut raw[28..32]).write_u32::<LittleEndian>(self.sectors as u32);
+        let _ = (&mut raw[32..36]).write_u32::<LittleEndian>(self.flags);
+        for (i, &ptr) in self.block.iter().enumerate() {
+            let _ = (&mut raw[40 + i * 4..44 + i * 4]).write_u32::<LittleEndian>(ptr);
+        }
+        let _ = (&mut raw[104..108]).write_u32::<LittleEndian>(self.file_acl);
+        if self.is_file() {
+            let _ = (&mut raw[108..112]).write_u32::<LittleEndian>((self.size as u64 >> 32) as u32);
+        }
+        let _ = (&mut raw[120..122]).write_u16::<LittleEndian>((self.uid >> 16) as u16);
+        let _ = (&mut raw[122..124]).write_u16::<LittleEndian>((self.gid >> 16) as u16);
+    }
+}
+
+/// A record in a directory
+#[derive(Debug, Clone, PartialEq)]
+struct DirEntry {
+    inode: u32, // 0 if the record is unused
+    name: Vec<u8>,
+    kind: u8, // file type, 0 if the volume doesn't record them
+    offset: usize, // in bytes from the start of the directory
+    len: usize, // length of the record, including any slack after the name
+}
+
+impl Ext2 {
+    pub fn new(disk: Box<Disk>) -> Result<Ext2> {
+        Ext2::with_clock(disk, Box::new(SystemClock))
+    }
+
+    /// Mount an ext2 filesystem, taking timestamps from `clock`
+    pub fn with_clock(disk: Box<Disk>, clock: Box<Clock>) -> Result<Ext2> {
+        let mut raw = vec![];
+        for lba in SUPERBLOCK / 512..SUPERBLOCK / 512 + 2 {
+            raw.extend(try!(disk.read_sector(lba)).iter().cloned());
+        }
+        let sb = try!(Superblock::parse(&raw));
+        try!(sb.validate(&disk.info()));
+        debug!("Ext2 sb={:?}", sb);
+
+        let mut fs = Ext2 {
+            disk: disk,
+            sb: sb.clone(),
+            groups: vec![],
+            used_blocks: Bitmap::new(sb.blocks),
+            used_inodes: Bitmap::new(sb.inodes),
+            free_blocks: 0,
+            free_inodes: 0,
+            dirty_groups: Bitmap::new(sb.groups()),
+            clock: clock,
+        };
+
+        // the descriptor table follows the superblock
+        let bs = sb.block_size;
+        let mut table = vec![];
+        for i in 0..(sb.groups() * 32 + bs - 1) / bs {
+            table.extend(try!(fs.read_block(sb.first_data_block + 1 + i)));
+        }
+        let table_blocks = (sb.inodes_per_group * sb.inode_size + bs - 1) / bs;
+        for g in 0..sb.groups() {
+            let desc = &table[g * 32..g * 32 + 32];
+            let mut group = Group {
+                block_bitmap: u32_at(desc, 0) as usize,
+                inode_bitmap: u32_at(desc, 4) as usize,
+                inode_table: u32_at(desc, 8) as usize,
+                free_blocks: 0,
+                free_inodes: 0,
+                dirs: u16_at(desc, 16) as usize,
+            };
+            if group.block_bitmap >= sb.blocks || group.inode_bitmap >= sb.blocks ||
+               group.inode_table + table_blocks > sb.blocks {
+                return Err(Error::CorruptDisk)
+            }
+
+            let start = sb.first_data_block + g * sb.blocks_per_group;
+            let bits = try!(fs.read_block(group.block_bitmap));
+            for j in 0..min(sb.blocks_per_group, sb.blocks - start) {
+                if bits[j / 8] & (1 << (j % 8)) != 0 {
+                    fs.used_blocks.set(start + j, true);
+                } else {
+                    group.free_blocks += 1;
+                }
+            }
+            let bits = try!(fs.read_block(group.inode_bitmap));
+            for j in 0..sb.inodes_per_group {
+                if bits[j / 8] & (1 << (j % 8)) != 0 {
+                    fs.used_inodes.set(g * sb.inodes_per_group + j, true);
+                } else {
+                    group.free_inodes += 1;
+                }
+            }
+            fs.groups.push(group);
+        }
+        // with 1KiB blocks, the boot block isn't part of any group
+        for b in 0..sb.first_data_block {
+            fs.used_blocks.set(b, true);
+        }
+        fs.free_blocks = fs.used_blocks.count_clear();
+        fs.free_inodes = fs.used_inodes.count_clear();
+
+        debug!("Ext2 groups={} free_blocks={} free_inodes={}", fs.groups.len(), fs.free_blocks, fs.free_inodes);
+        Ok(fs)
+    }
+
+    /// Unix permission bits of a file or directory, including the setuid,
+    /// setgid and sticky bits
+    pub fn permissions(&self, path: &Path) -> Result<u16> {
+        let ino = try!(self.find_inode(path));
+        Ok(try!(self.read_inode(ino)).mode & !S_IFMT)
+    }
+
+    pub fn set_permissions(&mut self, path: &Path, mode: u16) -> Result<()> {
+        let ino = try!(self.find_inode(path));
+        let mut inode = try!(self.read_inode(ino));
+        inode.mode = (inode.mode & S_IFMT) | (mode & !S_IFMT);
+        inode.ctime = self.now();
+        self.write_inode(ino, &inode)
+    }
+
+    /// User and group IDs owning a file or directory
+    pub fn owner(&self, path: &Path) -> Result<(u32, u32)> {
+        let ino = try!(self.find_inode(path));
+        let inode = try!(self.read_inode(ino));
+        Ok((inode.uid, inode.gid))
+    }
+
+    pub fn set_owner(&mut self, path: &Path, uid: u32, gid: u32) -> Result<()> {
+        let ino = try!(self.find_inode(path));
+        let mut inode = try!(self.read_inode(ino));
+        inode.uid = uid;
+        inode.gid = gid;
+        inode.ctime = self.now();
+        self.write_inode(ino, &inode)
+    }
+
+    /// Reserve `len` zeroed bytes for a boot loader in the inode ext2 sets
+    /// aside for one, replacing any it held
+    ///
+    /// The blocks are contiguous so the boot loader can be written to and
+    /// read from them directly, the first sector of them is returned.
+    pub fn reserve_boot_loader(&mut self, len: usize) -> Result<usize> {
+        let bs = self.sb.block_size;
+        let count = (len + bs - 1) / bs;
+        let mut inode = try!(self.read_inode(BOOT_LOADER_INO));
+        if inode.is_dir() {
+            return Err(Error::CorruptDisk)
+        }
+        try!(self.truncate_blocks(&mut inode, 0));
+
+        // the first run of `count` free blocks, indirect blocks go elsewhere
+        let mut start = self.sb.first_data_block;
+        loop {
+            start = match self.used_blocks.find_clear(start) {
+                Some(b) => b,
+                None => return Err(Error::NoSpace),
+            };
+            match (start..start + count).find(|&b| b >= self.sb.blocks || self.used_blocks.get(b)) {
+                Some(b) if b >= self.sb.blocks => return Err(Error::NoSpace),
+                Some(b) => start = b + 1,
+                None => break,
+            }
+        }
+        for b in start..start + count {
+            try!(self.write_block(b, &[]));
+            try!(self.set_block_used(b, true));
+        }
+        for i in 0..count {
+            try!(self.map_block_to(&mut inode, i, start + i));
+            inode.sectors += bs / 512;
+        }
+
+        let now = self.now();
+        inode.mode = S_IFREG | 0o400;
+        inode.size = len;
+        inode.mtime = now;
+        inode.ctime = now;
+        try!(self.write_inode(BOOT_LOADER_INO, &inode));
+        try!(self.flush_counts());
+        Ok(start * (bs / 512))
+    }
+
+    fn now(&self) -> u32 {
+        to_unix(&self.clock.now()) as u32
+    }
+
+    fn filetype(&self) -> bool {
+        self.sb.incompat & INCOMPAT_FILETYPE != 0
+    }
+
+    /// Number of block numbers which fit in an indirect block
+    fn ptrs(&self) -> usize {
+        self.sb.block_size / 4
+    }
+
+    /// Read all of block `b`
+    fn read_block(&self, b: usize) -> Result<Vec<u8>> {
+        if b >= self.sb.blocks {
+            return Err(Error::CorruptDisk)
+        }
+        let spb = self.sb.block_size / 512;
+        let mut block = Vec::with_capacity(self.sb.block_size);
+        for i in 0..spb {
+            block.extend(try!(self.disk.read_sector(b * spb + i)).iter().cloned());
+        }
+        Ok(block)
+    }
+
+    /// Write `data` to block `b`, zero filling the rest of it
+    fn write_block(&mut self, b: usize, data: &[u8]) -> Result<()> {
+        if b >= self.sb.blocks {
+            return Err(Error::CorruptDisk)
+        }
+        let spb = self.sb.block_size / 512;
+        for i in 0..spb {
+            let part = data.chunks(512).nth(i).unwrap_or(&[]);
+            try!(self.disk.write_sector(b * spb + i, part));
+        }
+        Ok(())
+    }
+
+    /// Overwrite part of a block, `data` must not cross a sector boundary
+    fn patch(&mut self, b: usize, offset: usize, data: &[u8]) -> Result<()> {
+        use std::slice::bytes::copy_memory;
+
+        let lba = b * (self.sb.block_size / 512) + offset / 512;
+        let i = offset % 512;
+        let mut sector = try!(self.disk.read_sector(lba)).clone();
+        copy_memory(data, &mut sector[i..i + data.len()]);
+        self.disk.write_sector(lba, &sector)
+    }
+
+    /// Update a field of the primary superblock
+    fn patch_superblock(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let bs = self.sb.block_size;
+        self.patch(SUPERBLOCK / bs, SUPERBLOCK % bs + offset, data)
+    }
+
+    /// Read the `i`th block number in indirect block `b`
+    fn read_ptr(&self, b: usize, i: usize) -> Result<usize> {
+        let lba = b * (self.sb.block_size / 512) + i * 4 / 512;
+        let sector = try!(self.disk.read_sector(lba));
+        Ok(u32_at(&sector[..], i * 4 % 512) as usize)
+    }
+
+    fn write_ptr(&mut self, b: usize, i: usize, value: usize) -> Result<()> {
+        let mut raw = [0; 4];
+        let _ = (&mut raw[..]).write_u32::<LittleEndian>(value as u32);
+        self.patch(b, i * 4, &raw)
+    }
+
+    /// Block and offset within it of an inode's record
+    fn inode_location(&self, ino: u32) -> Result<(usize, usize)> {
+        let i = ino as usize;
+        if i == 0 || i > self.sb.inodes {
+            return Err(Error::CorruptDisk)
+        }
+        let ipg = self.sb.inodes_per_group;
+        let byte = (i - 1) % ipg * self.sb.inode_size;
+        let bs = self.sb.block_size;
+        Ok((self.groups[(i - 1) / ipg].inode_table + byte / bs, byte % bs))
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<Inode> {
+        let (b, offset) = try!(self.inode_location(ino));
+        let lba = b * (self.sb.block_size / 512) + offset / 512;
+        let sector = try!(self.disk.read_sector(lba));
+        Inode::decode(&sector[offset % 512..offset % 512 + 128])
+    }
+
+    fn write_inode(&mut self, ino: u32, inode: &Inode) -> Result<()> {
+        if inode.is_file() && inode.size > 0x7FFFFFFF && self.sb.ro_compat & RO_COMPAT_LARGE_FILE == 0 {
+            // the upper half of the size is only read with this feature
+            self.sb.ro_compat |= RO_COMPAT_LARGE_FILE;
+            let mut raw = [0; 4];
+            let _ = (&mut raw[..]).write_u32::<LittleEndian>(self.sb.ro_compat);
+            try!(self.patch_superblock(100, &raw));
+        }
+
+        let (b, offset) = try!(self.inode_location(ino));
+        let lba = b * (self.sb.block_size / 512) + offset / 512;
+        let mut sector = try!(self.disk.read_sector(lba)).clone();
+        inode.encode(&mut sector[offset % 512..offset % 512 + 128]);
+        self.disk.write_sector(lba, &sector)
+    }
+
+    /// Write the free counts of every group which changed to its
+    /// descriptor, and the totals to the superblock
+    ///
+    /// Called once at the end of each operation rather than for every
+    /// block or inode.
+    fn flush_counts(&mut self) -> Result<()> {
+        if self.dirty_groups.count_clear() == self.groups.len() {
+            return Ok(())
+        }
+        for g in 0..self.groups.len() {
+            if !self.dirty_groups.get(g) {
+                continue
+            }
+            let mut raw = [0; 6];
+            let _ = (&mut raw[0..2]).write_u16::<LittleEndian>(self.groups[g].free_blocks as u16);
+            let _ = (&mut raw[2..4]).write_u16::<LittleEndian>(self.groups[g].free_inodes as u16);
+            let _ = (&mut raw[4..6]).write_u16::<LittleEndian>(self.groups[g].dirs as u16);
+            let bs = self.sb.block_size;
+            let table = self.sb.first_data_block + 1;
+            try!(self.patch(table + g * 32 / bs, g * 32 % bs + 12, &raw));
+            self.dirty_groups.set(g, false);
+        }
+
+        let mut raw = [0; 8];
+        let _ = (&mut raw[0..4]).write_u32::<LittleEndian>(self.free_blocks as u32);
+        let _ = (&mut raw[4..8]).write_u32::<LittleEndian>(self.free_inodes as u32);
+        self.patch_superblock(12, &raw)
+    }
+
+    /// Set or clear bit `i` of the bitmap in block `b`
+    fn set_bit(&mut self, b: usize, i: usize, value: bool) -> Result<()> {
+        let lba = b * (self.sb.block_size / 512) + i / 8 / 512;
+        let mut sector = try!(self.disk.read_sector(lba)).clone();
+        if value {
+            sector[i / 8 % 512] |= 1 << (i % 8);
+        } else {
+            sector[i / 8 % 512] &= !(1 << (i % 8));
+        }
+        self.disk.write_sector(lba, &sector)
+    }
+
+    fn set_block_used(&mut self, b: usize, value: bool) -> Result<()> {
+        if self.used_blocks.get(b) == value {
+            return Ok(())
+        }
+        let i = b - self.sb.first_data_block;
+        let g = i / self.sb.blocks_per_group;
+        let bitmap = self.groups[g].block_bitmap;
+        try!(self.set_bit(bitmap, i % self.sb.blocks_per_group, value));
+
+        self.used_blocks.set(b, value);
+        if value {
+            self.groups[g].free_blocks -= 1;
+            self.free_blocks -= 1;
+        } else {
+            self.groups[g].free_blocks += 1;
+            self.free_blocks += 1;
+        }
+        self.dirty_groups.set(g, true);
+        Ok(())
+    }
+
+    fn set_inode_used(&mut self, ino: u32, value: bool, dir: bool) -> Result<()> {
+        let i = ino as usize - 1;
+        if self.used_inodes.get(i) == value {
+            return Ok(())
+        }
+        let g = i / self.sb.inodes_per_group;
+        let bitmap = self.groups[g].inode_bitmap;
+        try!(self.set_bit(bitmap, i % self.sb.inodes_per_group, value));
+
+        self.used_inodes.set(i, value);
+        if value {
+            self.groups[g].free_inodes -= 1;
+            self.free_inodes -= 1;
+            if dir {
+                self.groups[g].dirs += 1;
+            }
+        } else {
+            self.groups[g].free_inodes += 1;
+            self.free_inodes += 1;
+            if dir {
+                self.groups[g].dirs -= 1;
+            }
+        }
+        self.dirty_groups.set(g, true);
+        Ok(())
+    }
+
+    /// Allocate a zeroed block, the first free one at or after `goal`
+    fn alloc_block(&mut self, goal: usize) -> Result<usize> {
+        let b = match self.used_blocks.find_clear(goal) {
+            Some(b) => b,
+            None => return Err(Error::NoSpace),
+        };
+        try!(self.write_block(b, &[]));
+        try!(self.set_block_used(b, true));
+        Ok(b)
+    }
+
+    /// Allocate an inode and clear its record, keeping it in the same
+    /// group as its directory if possible
+    fn alloc_inode(&mut self, parent: u32, dir: bool) -> Result<u32> {
+        let ipg = self.sb.inodes_per_group;
+        let i = match self.used_inodes.find_clear((parent as usize - 1) / ipg * ipg) {
+            Some(i) => i,
+            None => return Err(Error::NoSpace),
+        };
+        let ino = i as u32 + 1;
+        try!(self.set_inode_used(ino, true, dir));
+
+        let (b, offset) = try!(self.inode_location(ino));
+        let mut pos = offset;
+        while pos < offset + self.sb.inode_size {
+            let len = min(offset + self.sb.inode_size - pos, 512 - pos % 512);
+            try!(self.patch(b, pos, &[0; 512][..len]));
+            pos += len;
+        }
+        Ok(ino)
+    }
+
+    /// Release an inode which no directory links to any more, along with
+    /// its blocks
+    fn free_inode(&mut self, ino: u32, inode: &mut Inode) -> Result<()> {
+        let spb = self.sb.block_size / 512;
+        if !self.is_fast_symlink(inode) {
+            try!(self.truncate_blocks(inode, 0));
+        }
+        if inode.file_acl != 0 {
+            // the block of extended attributes may be shared with other inodes
+            let b = inode.file_acl as usize;
+            let mut block = try!(self.read_block(b));
+            let refs = u32_at(&block, 4);
+            if refs > 1 {
+                let _ = (&mut block[4..8]).write_u32::<LittleEndian>(refs - 1);
+                try!(self.write_block(b, &block));
+            } else {
+                try!(self.set_block_used(b, false));
+            }
+            inode.file_acl = 0;
+            inode.sectors -= spb;
+        }
+
+        inode.links = 0;
+        inode.dtime = self.now();
+        try!(self.write_inode(ino, inode));
+        self.set_inode_used(ino, false, inode.is_dir())
+    }
+
+    /// Whether an inode is a symlink keeping its target in place of the
+    /// block numbers
+    fn is_fast_symlink(&self, inode: &Inode) -> bool {
+        let acl = if inode.file_acl != 0 { self.sb.block_size / 512 } else { 0 };
+        inode.mode & S_IFMT == S_IFLNK && inode.sectors == acl
+    }
+
+    /// Where the `index`th block of a file is pointed to from
+    ///
+    /// Returns the slot in the inode's block array, how many levels of
+    /// indirect blocks are below it, and the index relative to the slot.
+    fn tier(&self, index: usize) -> Option<(usize, usize, usize)> {
+        if index < DIRECT {
+            return Some((index, 0, 0))
+        }
+        let p = self.ptrs();
+        let mut rel = index - DIRECT;
+        let mut span = p;
+        for level in 1..4 {
+            if rel < span {
+                return Some((DIRECT + level - 1, level, rel))
+            }
+            rel -= span;
+            span *= p;
+        }
+        None
+    }
+
+    /// The slot, levels of indirection and first index of each indirect tier
+    fn tiers(&self) -> [(usize, usize, usize); 3] {
+        let p = self.ptrs();
+        [(DIRECT, 1, DIRECT), (DIRECT + 1, 2, DIRECT + p), (DIRECT + 2, 3, DIRECT + p + p * p)]
+    }
+
+    /// Find the `index`th block of a file, `None` if it's a hole
+    fn map_block(&self, inode: &Inode, index: usize) -> Result<Option<usize>> {
+        let (slot, level, mut rel) = match self.tier(index) {
+            Some(tier) => tier,
+            None => return Ok(None),
+        };
+        let p = self.ptrs();
+        let mut b = inode.block[slot] as usize;
+        for l in (0..level).rev() {
+            if b == 0 {
+                return Ok(None)
+            }
+            let span = p.pow(l as u32);
+            b = try!(self.read_ptr(b, rel / span));
+            rel %= span;
+        }
+        Ok(if b == 0 { None } else { Some(b) })
+    }
+
+    /// Find the `index`th block of a file, allocating it and any indirect
+    /// blocks leading to it as necessary
+    ///
+    /// `inode` is updated but not written back.
+    fn map_block_alloc(&mut self, inode: &mut Inode, index: usize, goal: usize) -> Result<usize> {
+        let (slot, level, mut rel) = match self.tier(index) {
+            Some(tier) => tier,
+            // past what triple indirect blocks can reach
+            None => return Err(Error::NoSpace),
+        };
+        let p = self.ptrs();
+        let spb = self.sb.block_size / 512;
+        if inode.block[slot] == 0 {
+            inode.block[slot] = try!(self.alloc_block(goal)) as u32;
+            inode.sectors += spb;
+        }
+        let mut b = inode.block[slot] as usize;
+        for l in (0..level).rev() {
+            let span = p.pow(l as u32);
+            let mut next = try!(self.read_ptr(b, rel / span));
+            if next == 0 {
+                next = try!(self.alloc_block(b + 1));
+                try!(self.write_ptr(b, rel / span, next));
+                inode.sectors += spb;
+            }
+            b = next;
+            rel %= span;
+        }
+        Ok(b)
+    }
+
+    /// Point the `index`th block of a file at `b`, allocating any indirect
+    /// blocks leading to it as necessary
+    ///
+    /// `inode` is updated but not written back.
+    fn map_block_to(&mut self, inode: &mut Inode, index: usize, b: usize) -> Result<()> {
+        let (slot, level, mut rel) = match self.tier(index) {
+            Some(tier) => tier,
+            None => return Err(Error::NoSpace),
+        };
+        if level == 0 {
+            inode.block[slot] = b as u32;
+            return Ok(())
+        }
+        let p = self.ptrs();
+        let spb = self.sb.block_size / 512;
+        if inode.block[slot] == 0 {
+            inode.block[slot] = try!(self.alloc_block(b + 1)) as u32;
+            inode.sectors += spb;
+        }
+        let mut parent = inode.block[slot] as usize;
+        for l in (1..level).rev() {
+            let span = p.pow(l as u32);
+            let mut next = try!(self.read_ptr(parent, rel / span));
+            if next == 0 {
+                next = try!(self.alloc_block(parent + 1));
+                try!(self.write_ptr(parent, rel / span, next));
+                inode.sectors += spb;
+            }
+            parent = next;
+            rel %= span;
+        }
+        self.write_ptr(parent, rel, b)
+    }
+
+    /// Count the blocks, including indirect ones, which mapping blocks
+    /// `first..last` of a file would allocate
+    fn blocks_needed(&self, inode: &Inode, first: usize, last: usize) -> Result<usize> {
+        let mut needed = 0;
+        for i in first..min(last, DIRECT) {
+            if inode.block[i] == 0 {
+                needed += 1;
+            }
+        }
+        for &(slot, level, base) in &self.tiers() {
+            needed += try!(self.missing(inode.block[slot] as usize, level, base, first, last));
+        }
+        Ok(needed)
+    }
+
+    /// Count the missing blocks in range in the subtree at `b`, which maps
+    /// the blocks from `base` onwards
+    fn missing(&self, b: usize, level: usize, base: usize, first: usize, last: usize) -> Result<usize> {
+        let p = self.ptrs();
+        let span = p.pow(level as u32);
+        let (from, to) = (max(first, base), min(last, base + span));
+        if from >= to {
+            return Ok(0)
+        }
+        if level == 0 {
+            return Ok(if b == 0 { 1 } else { 0 })
+        }
+        if b == 0 && level == 1 {
+            return Ok(1 + to - from)
+        }
+
+        let block = if b == 0 { None } else { Some(try!(self.read_block(b))) };
+        let mut needed = if b == 0 { 1 } else { 0 };
+        let child_span = span / p;
+        for i in (from - base) / child_span..(to - 1 - base) / child_span + 1 {
+            let child = block.as_ref().map_or(0, |block| u32_at(block, i * 4) as usize);
+            needed += try!(self.missing(child, level - 1, base + i * child_span, first, last));
+        }
+        Ok(needed)
+    }
+
+    /// Free every block of a file from the `keep`th onwards
+    ///
+    /// `inode` is updated but not written back.
+    fn truncate_blocks(&mut self, inode: &mut Inode, keep: usize) -> Result<()> {
+        let mut freed = 0;
+        for i in 0..DIRECT {
+            let b = inode.block[i] as usize;
+            if b != 0 && try!(self.free_tree(b, 0, i, keep, &mut freed)) {
+                inode.block[i] = 0;
+            }
+        }
+        for &(slot, level, base) in &self.tiers() {
+            let b = inode.block[slot] as usize;
+            if b != 0 && try!(self.free_tree(b, level, base, keep, &mut freed)) {
+                inode.block[slot] = 0;
+            }
+        }
+        inode.sectors -= freed * (self.sb.block_size / 512);
+        Ok(())
+    }
+
+    /// Free the blocks past `keep` in the subtree at `b`, which maps the
+    /// blocks from `base` onwards
+    ///
+    /// Returns whether `b` itself was freed.
+    fn free_tree(&mut self, b: usize, level: usize, base: usize, keep: usize, freed: &mut usize) -> Result<bool> {
+        let p = self.ptrs();
+        let span = p.pow(level as u32);
+        if base + span <= keep {
+            return Ok(false)
+        }
+
+        if level > 0 {
+            let mut block = try!(self.read_block(b));
+            let mut modified = false;
+            let child_span = span / p;
+            for i in 0..p {
+                let child = u32_at(&block, i * 4) as usize;
+                if child != 0 && try!(self.free_tree(child, level - 1, base + i * child_span, keep, freed)) {
+                    let _ = (&mut block[i * 4..i * 4 + 4]).write_u32::<LittleEndian>(0);
+                    modified = true;
+                }
+            }
+            if base < keep {
+                // still needed for the blocks which are kept
+                if modified {
+                    try!(self.write_block(b, &block));
+                }
+                return Ok(false)
+            }
+        }
+        try!(self.set_block_used(b, false));
+        *freed += 1;
+        Ok(true)
+    }
+
+    /// Zero the rest of the block containing byte `from` of a file, so
+    /// growing it doesn't bring back old contents
+    fn zero_tail(&mut self, inode: &Inode, from: usize) -> Result<()> {
+        let bs = self.sb.block_size;
+        if from % bs == 0 {
+            return Ok(())
+        }
+        if let Some(b) = try!(self.map_block(inode, from / bs)) {
+            let mut block = try!(self.read_block(b));
+            for byte in &mut block[from % bs..] {
+                *byte = 0;
+            }
+            try!(self.write_block(b, &block));
+        }
+        Ok(())
+    }
+
+    /// Write `buf` to the start of a file which has no blocks
+    fn write_data(&mut self, ino: u32, inode: &mut Inode, buf: &[u8]) -> Result<()> {
+        let mut goal = self.group_start(ino);
+        for (i, chunk) in buf.chunks(self.sb.block_size).enumerate() {
+            let b = try!(self.map_block_alloc(inode, i, goal));
+            try!(self.write_block(b, chunk));
+            goal = b + 1;
+        }
+        Ok(())
+    }
+
+    /// First block of the group holding an inode, where its blocks are
+    /// allocated from
+    fn group_start(&self, ino: u32) -> usize {
+        let g = (ino as usize - 1) / self.sb.inodes_per_group;
+        self.sb.first_data_block + g * self.sb.blocks_per_group
+    }
+
+    /// Every record in a directory, including unused ones
+    fn read_entries(&self, dir: &Inode) -> Result<Vec<DirEntry>> {
+        let bs = self.sb.block_size;
+        let filetype = self.filetype();
+        let mut entries = vec![];
+        for index in 0..dir.size / bs {
+            let block = match try!(self.map_block(dir, index)) {
+                Some(b) => try!(self.read_block(b)),
+                None => return Err(Error::CorruptDisk),
+            };
+            let mut offset = 0;
+            while offset < bs {
+                if offset + 8 > bs {
+                    return Err(Error::CorruptDisk)
+                }
+                let raw = &block[offset..];
+                let len = u16_at(raw, 4) as usize;
+                let name_len = if filetype { raw[6] as usize } else { u16_at(raw, 6) as usize };
+                if len < 8 || len % 4 != 0 || offset + len > bs || 8 + name_len > len {
+                    return Err(Error::CorruptDisk)
+                }
+                entries.push(DirEntry {
+                    inode: u32_at(raw, 0),
+                    name: raw[8..8 + name_len].to_vec(),
+                    kind: if filetype { raw[7] } else { 0 },
+                    offset: index * bs + offset,
+                    len: len,
+                });
+                offset += len;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write_entry(&mut self, dir: &Inode, entry: &DirEntry) -> Result<()> {
+        let bs = self.sb.block_size;
+        let b = match try!(self.map_block(dir, entry.offset / bs)) {
+            Some(b) => b,
+            None => return Err(Error::CorruptDisk),
+        };
+        let kind = if self.filetype() { entry.kind } else { 0 };
+        let mut block = try!(self.read_block(b));
+        encode_entry(&mut block[entry.offset % bs..], entry.inode, entry.len, &entry.name, kind);
+        self.write_block(b, &block)
+    }
+
+    fn find_entry(&self, dir: &Inode, name: &[u8]) -> Result<Option<DirEntry>> {
+        let entries = try!(self.read_entries(dir));
+        Ok(entries.into_iter().find(|entry| entry.inode != 0 && entry.name == name))
+    }
+
+    /// Link inode `ino` into a directory, using the slack after an existing
+    /// record or growing the directory by a block
+    ///
+    /// `dir` is updated but not written back.
+    fn add_entry(&mut self, dir: &mut Inode, name: &str, ino: u32, kind: u8) -> Result<()> {
+        let needed = rec_size(name.len());
+        let now = self.now();
+        // hash indexes aren't kept up to date, so fall back to a linear listing
+        dir.flags &= !INDEX_FL;
+        dir.mtime = now;
+        dir.ctime = now;
+
+        for entry in try!(self.read_entries(dir)) {
+            let used = if entry.inode == 0 { 0 } else { rec_size(entry.name.len()) };
+            if entry.len - used < needed {
+                continue
+            }
+            let new = DirEntry {
+                inode: ino,
+                name: name.as_bytes().to_vec(),
+                kind: kind,
+                offset: entry.offset + used,
+                len: entry.len - used,
+            };
+            if used > 0 {
+                try!(self.write_entry(dir, &DirEntry { len: used, ..entry }));
+            }
+            return self.write_entry(dir, &new)
+        }
+
+        // records can't cross blocks, start a new one
+        let bs = self.sb.block_size;
+        let index = dir.size / bs;
+        if try!(self.blocks_needed(dir, index, index + 1)) > self.free_blocks {
+            return Err(Error::NoSpace)
+        }
+        let goal = match try!(self.map_block(dir, max(index, 1) - 1)) {
+            Some(b) => b + 1,
+            None => 0,
+        };
+        try!(self.map_block_alloc(dir, index, goal));
+        dir.size += bs;
+        let new = DirEntry {
+            inode: ino,
+            name: name.as_bytes().to_vec(),
+            kind: kind,
+            offset: index * bs,
+            len: bs,
+        };
+        self.write_entry(dir, &new)
+    }
+
+    /// Unlink `name` from a directory, merging its record into the previous
+    /// one in the block
+    ///
+    /// `dir` is updated but not written back.
+    fn remove_entry(&mut self, dir: &mut Inode, name: &[u8]) -> Result<()> {
+        let entries = try!(self.read_entries(dir));
+        let i = match entries.iter().position(|entry| entry.inode != 0 && entry.name == name) {
+            Some(i) => i,
+            None => return Err(Error::CorruptDisk),
+        };
+        let now = self.now();
+        dir.flags &= !INDEX_FL;
+        dir.mtime = now;
+        dir.ctime = now;
+
+        let entry = &entries[i];
+        if entry.offset % self.sb.block_size == 0 {
+            // the first record of a block has nothing before it to merge with
+            self.write_entry(dir, &DirEntry { inode: 0, ..entry.clone() })
+        } else {
+            let prev = &entries[i - 1];
+            self.write_entry(dir, &DirEntry { len: prev.len + entry.len, ..prev.clone() })
+        }
+    }
+
+    /// Create an empty file in a directory
+    ///
+    /// `dir` is written back.
+    fn create(&mut self, pino: u32, dir: &mut Inode, name: &str) -> Result<(u32, Inode)> {
+        let ino = try!(self.alloc_inode(pino, false));
+        let mut inode = Inode::new(S_IFREG | 0o644, self.now());
+        try!(self.write_inode(ino, &inode));
+        if let Err(e) = self.add_entry(dir, name, ino, FT_REG_FILE) {
+            // growing the directory may have needed the space
+            try!(self.free_inode(ino, &mut inode));
+            return Err(e)
+        }
+        try!(self.write_inode(pino, dir));
+        Ok((ino, inode))
+    }
+
+    /// Follow a path to its inode
+    fn find_inode(&self, path: &Path) -> Result<u32> {
+        let mut ino = ROOT_INO;
+        let mut walked = PathBuf::new();
+        for item in path.iter() {
+            if Path::new(item).has_root() || item == "." {
+                walked.push(item);
+                continue
+            }
+            let inode = try!(self.read_inode(ino));
+            if !inode.is_dir() {
+                return Err(Error::NotADirectory(walked))
+            }
+            walked.push(item);
+            // `..` is an entry like any other
+            let name = match item.to_str() {
+                Some(name) => name,
+                None => return Err(Error::Nonexistent(walked)),
+            };
+            ino = match try!(self.find_entry(&inode, name.as_bytes())) {
+                Some(entry) => entry.inode,
+                None => return Err(Error::Nonexistent(walked)),
+            };
+        }
+        Ok(ino)
+    }
+
+    fn find_dir(&self, path: &Path) -> Result<(u32, Inode)> {
+        let ino = try!(self.find_inode(path));
+        let inode = try!(self.read_inode(ino));
+        if !inode.is_dir() {
+            return Err(Error::NotADirectory(path.to_owned()))
+        }
+        Ok((ino, inode))
+    }
+
+    fn find_parent(&self, path: &Path) -> Result<(u32, Inode)> {
+        match path.parent() {
+            Some(parent) => self.find_dir(parent),
+            None => Ok((ROOT_INO, try!(self.read_inode(ROOT_INO)))),
+        }
+    }
+
+    /// Find the entry for a path, and the directory holding it
+    fn lookup(&self, path: &Path) -> Result<(u32, Inode, DirEntry)> {
+        let (pino, dir) = try!(self.find_parent(path));
+        let entry = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => try!(self.find_entry(&dir, name.as_bytes())),
+            None => None,
+        };
+        match entry {
+            Some(entry) => Ok((pino, dir, entry)),
+            None => Err(Error::Nonexistent(path.to_owned())),
+        }
+    }
+
+    /// Find an existing regular file
+    fn find_file(&self, path: &Path) -> Result<(u32, Inode)> {
+        let ino = try!(self.lookup(path)).2.inode;
+        let inode = try!(self.read_inode(ino));
+        if !inode.is_file() {
+            return Err(Error::NotAFile(path.to_owned()))
+        }
+        Ok((ino, inode))
+    }
+}
+
+impl FileSystem for Ext2 {
+    fn read_dir<'a>(&'a mut self, path: &Path) -> Result<Box<Iterator<Item=Result<Entry>> + 'a>> {
+        let dir = try!(self.find_dir(path)).1;
+        let mut listing = vec![];
+        for entry in try!(self.read_entries(&dir)) {
+            if entry.inode == 0 || entry.name == b"." || entry.name == b".." {
+                continue
+            }
+            let inode = try!(self.read_inode(entry.inode));
+            listing.push(Ok(Entry {
+                name: String::from_utf8_lossy(&entry.name).into_owned(),
+                kind: if inode.is_dir() { FileType::Directory } else { FileType::File },
+                size: if inode.is_dir() { 0 } else { inode.size },
+                start: if self.is_fast_symlink(&inode) { 0 } else { inode.block[0] as usize },
+            }));
+        }
+        Ok(Box::new(listing.into_iter()))
+    }
+
+    fn open<'a>(&'a mut self, path: &Path, mode: Mode) -> Result<Box<Handle + 'a>> {
+        let (pino, mut dir) = try!(self.find_parent(path));
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        let (ino, mut inode) = match try!(self.find_entry(&dir, name.as_bytes())) {
+            Some(entry) => {
+                let inode = try!(self.read_inode(entry.inode));
+                if !inode.is_file() {
+                    return Err(Error::NotAFile(path.to_owned()))
+                }
+                (entry.inode, inode)
+            },
+            None if mode == Mode::Write => {
+                if !is_valid_name(name) {
+                    return Err(Error::InvalidPath)
+                }
+                try!(self.create(pino, &mut dir, name))
+            },
+            None => return Err(Error::Nonexistent(path.to_owned())),
+        };
+
+        let mut dirty = false;
+        if mode == Mode::Write && inode.size > 0 {
+            try!(self.truncate_blocks(&mut inode, 0));
+            inode.size = 0;
+            dirty = true;
+        }
+
+        let goal = self.group_start(ino);
+        Ok(Box::new(Ext2File {
+            fs: self,
+            mode: mode,
+            ino: ino,
+            inode: inode,
+            goal: goal,
+            pos: 0,
+            dirty: dirty,
+        }))
+    }
+
+    fn make_dir(&mut self, path: &Path) -> Result<()> {
+        let (pino, mut dir) = try!(self.find_parent(path));
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        if try!(self.find_entry(&dir, name.as_bytes())).is_some() {
+            return Err(Error::AlreadyExists(path.to_owned()))
+        }
+        if !is_valid_name(name) {
+            return Err(Error::InvalidPath)
+        }
+        if self.free_inodes == 0 || self.free_blocks == 0 {
+            return Err(Error::NoSpace)
+        }
+
+        let ino = try!(self.alloc_inode(pino, true));
+        let mut inode = Inode::new(S_IFDIR | 0o755, self.now());
+        inode.links = 2; // its entry and its own `.`
+        let goal = self.group_start(ino);
+        let b = try!(self.map_block_alloc(&mut inode, 0, goal));
+        inode.size = self.sb.block_size;
+
+        let mut block = vec![0; self.sb.block_size];
+        let kind = if self.filetype() { FT_DIR } else { 0 };
+        encode_entry(&mut block, ino, 12, b".", kind);
+        encode_entry(&mut block[12..], pino, self.sb.block_size - 12, b"..", kind);
+        try!(self.write_block(b, &block));
+        try!(self.write_inode(ino, &inode));
+
+        if let Err(e) = self.add_entry(&mut dir, name, ino, FT_DIR) {
+            try!(self.free_inode(ino, &mut inode));
+            return Err(e)
+        }
+        // for the new directory's `..`
+        dir.links += 1;
+        try!(self.write_inode(pino, &dir));
+        self.flush_counts()
+    }
+
+    fn metadata(&mut self, path: &Path) -> Result<Metadata> {
+        let ino = try!(self.find_inode(path));
+        let inode = try!(self.read_inode(ino));
+        Ok(Metadata {
+            kind: if inode.is_dir() { FileType::Directory } else { FileType::File },
+            size: if inode.is_dir() { 0 } else { inode.size },
+            attributes: attributes_of(path, &inode),
+            // ext2 doesn't record when a file was created
+            created: None,
+            modified: Some(from_unix(inode.mtime as u64)),
+            accessed: Some(from_unix(inode.atime as u64)),
+        })
+    }
+
+    fn attributes(&mut self, path: &Path) -> Result<Attributes> {
+        self.metadata(path).map(|meta| meta.attributes)
+    }
+
+    /// Only `READ_ONLY` is stored, by clearing every write permission bit
+    ///
+    /// Clearing it gives the owner write permission back.
+    fn set_attributes(&mut self, path: &Path, attrib: Attributes) -> Result<()> {
+        let ino = try!(self.find_inode(path));
+        let mut inode = try!(self.read_inode(ino));
+        if attrib.contains(READ_ONLY) {
+            inode.mode &= !0o222;
+        } else if inode.mode & 0o222 == 0 {
+            inode.mode |= 0o200;
+        }
+        inode.ctime = self.now();
+        self.write_inode(ino, &inode)
+    }
+
+    fn delete(&mut self, path: &Path) -> Result<()> {
+        let (pino, mut dir, entry) = try!(self.lookup(path));
+        let mut inode = try!(self.read_inode(entry.inode));
+        if inode.is_dir() {
+            let entries = try!(self.read_entries(&inode));
+            if entries.iter().any(|e| e.inode != 0 && e.name != b"." && e.name != b"..") {
+                return Err(Error::DirectoryNotEmpty(path.to_owned()))
+            }
+        }
+
+        debug!("delete dir={} ino={}", pino, entry.inode);
+        try!(self.remove_entry(&mut dir, &entry.name));
+        if inode.is_dir() {
+            // nothing refers to an empty directory but its entry and `.`
+            dir.links -= 1;
+            inode.links = 0;
+        } else {
+            inode.links = inode.links.saturating_sub(1);
+        }
+        try!(self.write_inode(pino, &dir));
+
+        if inode.links == 0 {
+            try!(self.free_inode(entry.inode, &mut inode));
+        } else {
+            // still linked from somewhere else
+            inode.ctime = self.now();
+            try!(self.write_inode(entry.inode, &inode));
+        }
+        self.flush_counts()
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path, replace: bool) -> Result<()> {
+        let (src_ino, _, entry) = try!(self.lookup(from));
+        let (dst_ino, dst) = try!(self.find_parent(to));
+        let name = to.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        let inode = try!(self.read_inode(entry.inode));
+        let is_dir = inode.is_dir();
+        if !is_valid_name(name) {
+            return Err(Error::InvalidPath)
+        }
+
+        if is_dir {
+            // a directory can't be moved inside of itself
+            let mut ino = dst_ino;
+            loop {
+                if ino == entry.inode {
+                    return Err(Error::InvalidPath)
+                }
+                if ino == ROOT_INO {
+                    break
+                }
+                ino = match try!(self.find_entry(&try!(self.read_inode(ino)), b"..")) {
+                    Some(parent) => parent.inode,
+                    None => return Err(Error::CorruptDisk),
+                };
+            }
+        }
+
+        match try!(self.find_entry(&dst, name.as_bytes())) {
+            // the same entry, or another link to the same file
+            Some(ref target) if target.inode == entry.inode => return Ok(()),
+            Some(target) => {
+                let target = try!(self.read_inode(target.inode));
+                if !replace {
+                    return Err(Error::AlreadyExists(to.to_owned()))
+                } else if is_dir && !target.is_dir() {
+                    return Err(Error::NotADirectory(to.to_owned()))
+                } else if !is_dir && target.is_dir() {
+                    return Err(Error::NotAFile(to.to_owned()))
+                }
+                // delete() refuses to remove a directory which isn't empty
+                try!(self.delete(to));
+            },
+            None => { },
+        }
+
+        // link the new name first, so running out of space leaves the old one
+        let mut dst = try!(self.read_inode(dst_ino));
+        try!(self.add_entry(&mut dst, name, entry.inode, file_type(inode.mode)));
+        if src_ino == dst_ino {
+            try!(self.remove_entry(&mut dst, &entry.name));
+            try!(self.write_inode(dst_ino, &dst));
+            return self.flush_counts()
+        }
+
+        let mut src = try!(self.read_inode(src_ino));
+        try!(self.remove_entry(&mut src, &entry.name));
+        if is_dir {
+            // `..` moves from the old parent to the new one
+            let mut parent = match try!(self.find_entry(&inode, b"..")) {
+                Some(parent) => parent,
+                None => return Err(Error::CorruptDisk),
+            };
+            parent.inode = dst_ino;
+            try!(self.write_entry(&inode, &parent));
+            src.links -= 1;
+            dst.links += 1;
+        }
+        try!(self.write_inode(src_ino, &src));
+        try!(self.write_inode(dst_ino, &dst));
+        self.flush_counts()
+    }
+
+    fn write_file(&mut self, path: &Path, buf: &[u8]) -> Result<()> {
+        let (pino, mut dir) = try!(self.find_parent(path));
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        let bs = self.sb.block_size;
+        let count = (buf.len() + bs - 1) / bs;
+        let needed = try!(self.blocks_needed(&Inode::new(0, 0), 0, count));
+
+        let (ino, mut inode) = match try!(self.find_entry(&dir, name.as_bytes())) {
+            // File exists, overwriting
+            Some(entry) => {
+                let mut inode = try!(self.read_inode(entry.inode));
+                if !inode.is_file() {
+                    return Err(Error::NotAFile(path.to_owned()))
+                }
+                // fail before any of the old contents are freed
+                let acl = if inode.file_acl != 0 { 1 } else { 0 };
+                if needed > self.free_blocks + inode.sectors / (bs / 512) - acl {
+                    return Err(Error::NoSpace)
+                }
+                try!(self.truncate_blocks(&mut inode, 0));
+                (entry.inode, inode)
+            },
+            None => {
+                if !is_valid_name(name) {
+                    return Err(Error::InvalidPath)
+                }
+                if needed > self.free_blocks || self.free_inodes == 0 {
+                    return Err(Error::NoSpace)
+                }
+                let created = try!(self.create(pino, &mut dir, name));
+                if needed > self.free_blocks {
+                    // growing the directory took the space
+                    try!(self.delete(path));
+                    return Err(Error::NoSpace)
+                }
+                created
+            },
+        };
+
+        try!(self.write_data(ino, &mut inode, buf));
+        let now = self.now();
+        inode.size = buf.len();
+        inode.mtime = now;
+        inode.ctime = now;
+        try!(self.write_inode(ino, &inode));
+        self.flush_counts()
+    }
+
+    fn truncate(&mut self, path: &Path, len: usize) -> Result<()> {
+        let (ino, mut inode) = try!(self.find_file(path));
+        if len < inode.size {
+            let bs = self.sb.block_size;
+            try!(self.truncate_blocks(&mut inode, (len + bs - 1) / bs));
+            try!(self.zero_tail(&inode, len));
+        } else {
+            // the rest of the file is a hole, which reads as zero
+            try!(self.zero_tail(&inode, inode.size));
+        }
+
+        let now = self.now();
+        inode.size = len;
+        inode.mtime = now;
+        inode.ctime = now;
+        try!(self.write_inode(ino, &inode));
+        self.flush_counts()
+    }
+
+    fn write_at(&mut self, path: &Path, offset: usize, buf: &[u8]) -> Result<()> {
+        use std::slice::bytes::copy_memory;
+
+        let (ino, mut inode) = try!(self.find_file(path));
+        if buf.len() == 0 {
+            return Ok(())
+        }
+        let bs = self.sb.block_size;
+        let (first, last) = (offset / bs, (offset + buf.len() + bs - 1) / bs);
+        if try!(self.blocks_needed(&inode, first, last)) > self.free_blocks {
+            return Err(Error::NoSpace)
+        }
+        if offset > inode.size {
+            // any gap is left as a hole
+            try!(self.zero_tail(&inode, inode.size));
+        }
+
+        let spb = bs / 512;
+        let mut goal = self.group_start(ino);
+        let mut pos = offset;
+        let mut data = buf;
+        while data.len() > 0 {
+            let b = try!(self.map_block_alloc(&mut inode, pos / bs, goal));
+            goal = b + 1;
+
+            // only write up to the end of the current sector
+            let lba = b * spb + pos % bs / 512;
+            let offset = pos % 512;
+            let len = min(data.len(), 512 - offset);
+            let mut sector = if len == 512 {
+                EMPTY_SECTOR
+            } else {
+                try!(self.disk.read_sector(lba)).clone()
+            };
+            copy_memory(&data[..len], &mut sector[offset..offset + len]);
+            try!(self.disk.write_sector(lba, &sector));
+
+            pos += len;
+            data = &data[len..];
+        }
+
+        let now = self.now();
+        inode.size = max(inode.size, pos);
+        inode.mtime = now;
+        inode.ctime = now;
+        try!(self.write_inode(ino, &inode));
+        self.flush_counts()
+    }
+
+    fn append(&mut self, path: &Path, buf: &[u8]) -> Result<()> {
+        let size = try!(self.find_file(path)).1.size;
+        self.write_at(path, size, buf)
+    }
+
+    fn read_file(&mut self, path: &Path, buf: &mut [u8]) -> Result<usize> {
+        use std::slice::bytes::copy_memory;
+
+        let inode = try!(self.find_file(path)).1;
+        let size = inode.size;
+        if buf.len() < size {
+            return Err(Error::BufferTooSmall(size))
+        }
+
+        let bs = self.sb.block_size;
+        for (i, chunk) in buf[..size].chunks_mut(bs).enumerate() {
+            match try!(self.map_block(&inode, i)) {
+                Some(b) => {
+                    let block = try!(self.read_block(b));
+                    copy_memory(&block[..chunk.len()], chunk);
+                },
+                None => for byte in chunk.iter_mut() {
+                    *byte = 0;
+                },
+            }
+        }
+        Ok(size)
+    }
+}
+
+/// An open file on an `Ext2` filesystem
+///
+/// The inode is updated on `flush()` or when the handle is dropped.
+pub struct Ext2File<'a> {
+    fs: &'a mut Ext2,
+    mode: Mode,
+    ino: u32,
+    inode: Inode, // kept up to date with the blocks and size
+    goal: usize, // where to look for a free block when allocating
+    pos: usize, // current offset in bytes
+    dirty: bool, // whether the file was written since the inode was updated
+}
+
+impl<'a> Read for Ext2File<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::slice::bytes::copy_memory;
+
+        if self.mode == Mode::Write {
+            return Err(io::Error::new(io::ErrorKind::Other, "file not opened for reading"))
+        }
+        if self.pos >= self.inode.size || buf.len() == 0 {
+            return Ok(0)
+        }
+
+        // only read up to the end of the current sector
+        let bs = self.fs.sb.block_size;
+        let offset = self.pos % 512;
+        let len = min(buf.len(), min(512 - offset, self.inode.size - self.pos));
+        match try!(self.fs.map_block(&self.inode, self.pos / bs)) {
+            Some(b) => {
+                let lba = b * (bs / 512) + self.pos % bs / 512;
+                let sector = try!(self.fs.disk.read_sector(lba));
+                copy_memory(&sector[offset..offset + len], &mut buf[..len]);
+            },
+            // a hole
+            None => for byte in &mut buf[..len] {
+                *byte = 0;
+            },
+        }
+
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl<'a> Write for Ext2File<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        use std::slice::bytes::copy_memory;
+
+        if self.mode == Mode::Read {
+            return Err(io::Error::new(io::ErrorKind::Other, "file not opened for writing"))
+        }
+        if buf.len() == 0 {
+            return Ok(0)
+        }
+        if self.pos > self.inode.size {
+            // seeked past the end, the gap is left as a hole
+            try!(self.fs.zero_tail(&self.inode, self.inode.size));
+        }
+
+        let bs = self.fs.sb.block_size;
+        let b = try!(self.fs.map_block_alloc(&mut self.inode, self.pos / bs, self.goal));
+        self.goal = b + 1;
+
+        // only write up to the end of the current sector
+        let lba = b * (bs / 512) + self.pos % bs / 512;
+        let offset = self.pos % 512;
+        let len = min(buf.len(), 512 - offset);
+        let mut sector = try!(self.fs.disk.read_sector(lba)).clone();
+        copy_memory(&buf[..len], &mut sector[offset..offset + len]);
+        try!(self.fs.disk.write_sector(lba, &sector));
+
+        self.pos += len;
+        self.inode.size = max(self.inode.size, self.pos);
+        self.dirty = true;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // opening the file may have created it
+        try!(self.fs.flush_counts());
+        if !self.dirty {
+            return Ok(())
+        }
+
+        let now = self.fs.now();
+        self.inode.mtime = now;
+        self.inode.ctime = now;
+        try!(self.fs.write_inode(self.ino, &self.inode));
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+impl<'a> Seek for Ext2File<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => self.inode.size as i64 + n,
+            SeekFrom::Current(n) => self.pos as i64 + n,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"))
+        }
+
+        self.pos = pos as usize;
+        Ok(self.pos as u64)
+    }
+}
+
+impl<'a> Handle for Ext2File<'a> { }
+
+impl<'a> Drop for Ext2File<'a> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Whether `name` may be stored in a directory
+///
+/// Anything but `/` and NUL is allowed, up to 255 bytes.
+fn is_valid_name(name: &str) -> bool {
+    name.len() > 0 && name.len() <= 255 && !name.contains('/') && !name.contains('\0') &&
+        name != "." && name != ".."
+}
+
+/// Attributes in the sense of FAT, made from the permissions
+///
+/// Following Unix convention, names starting with `.` are hidden.
+fn attributes_of(path: &Path, inode: &Inode) -> Attributes {
+    let mut attrib = Attributes(0);
+    if inode.is_dir() {
+        attrib.insert(DIRECTORY);
+    }
+    if inode.mode & 0o222 == 0 {
+        attrib.insert(READ_ONLY);
+    }
+    if path.file_name().and_then(|name| name.to_str()).map_or(false, |name| name.starts_with(".")) {
+        attrib.insert(HIDDEN);
+    }
+    attrib
+}
+
+/// The file type recorded in directory entries for an inode's mode
+fn file_type(mode: u16) -> u8 {
+    match mode & S_IFMT {
+        S_IFREG => FT_REG_FILE,
+        S_IFDIR => FT_DIR,
+        0x2000 => 3, // character device
+        0x6000 => 4, // block device
+        0x1000 => 5, // FIFO
+        0xC000 => 6, // socket
+        S_IFLNK => 7,
+        _ => 0,
+    }
+}
+
+/// Length of the smallest record which fits a name
+fn rec_size(name_len: usize) -> usize {
+    (8 + name_len + 3) / 4 * 4
+}
+
+fn encode_entry(raw: &mut [u8], inode: u32, len: usize, name: &[u8], kind: u8) {
+    use std::slice::bytes::copy_memory;
+
+    let _ = (&mut raw[0..4]).write_u32::<LittleEndian>(inode);
+    let _ = (&mut raw[4..6]).write_u16::<LittleEndian>(len as u16);
+    raw[6] = name.len() as u8;
+    raw[7] = kind;
+    copy_memory(name, &mut raw[8..8 + name.len()]);
+}
+
+fn u16_at(raw: &[u8], i: usize) -> u16 {
+    (&raw[i..i + 2]).read_u16::<LittleEndian>().unwrap()
+}
+
+fn u32_at(raw: &[u8], i: usize) -> u32 {
+    (&raw[i..i + 4]).read_u32::<LittleEndian>().unwrap()
+}
+
+/// Whether group `g` holds a backup of the superblock
+///
+/// With sparse superblocks, only groups 0, 1 and powers of 3, 5 and 7 do.
+fn has_super(g: usize) -> bool {
+    fn is_power(mut n: usize, base: usize) -> bool {
+        while n % base == 0 {
+            n /= base;
+        }
+        n == 1
+    }
+    g <= 1 || is_power(g, 3) || is_power(g, 5) || is_power(g, 7)
+}
+
+/// Options for `format_with()`
+///
+/// Anything left as `None` is picked based on the disk.
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    /// Bytes per block, a power of two from 1024 to 32768
+    pub block_size: Option<usize>,
+    /// Bytes of disk per inode, which decides how many files fit
+    pub bytes_per_inode: Option<usize>,
+    /// UUID of the volume, by default made from the current time
+    pub uuid: Option<[u8; 16]>,
+}
+
+/// Format drive as an ext2 filesystem
+///
+/// The first 1024 bytes are left alone, for a boot loader. Every group's
+/// metadata is written and the inode tables are zeroed, data blocks are
+/// zeroed as they're allocated. The root directory holds an empty
+/// `lost+found` for `e2fsck` to use.
+pub fn format<T: Disk>(disk: &mut T) -> Result<()> {
+    format_with(disk, &FormatOptions::default())
+}
+
+/// Format drive as an ext2 filesystem, see `format()`
+pub fn format_with<T: Disk>(disk: &mut T, options: &FormatOptions) -> Result<()> {
+    use std::slice::bytes::copy_memory;
+
+    let dsize = disk.info().size;
+    let bs = options.block_size.unwrap_or(if dsize < SMALL_VOLUME { 1024 } else { 4096 });
+    assert!(bs.is_power_of_two() && bs >= 1024 && bs <= 32768, "Invalid block size: {}", bs);
+    let spb = bs / 512;
+    let first_data_block = if bs == 1024 { 1 } else { 0 };
+    let bpg = bs * 8;
+    let mut blocks = min(dsize / spb, 0xFFFFFFFF);
+    assert!(blocks > first_data_block + 32, "Unable to fit ext2 on a disk of {} sectors", dsize);
+    let mut groups = (blocks - first_data_block + bpg - 1) / bpg;
+
+    // whole blocks of the inode table, and whole bytes of the inode bitmap
+    let ratio = options.bytes_per_inode.unwrap_or(if dsize < SMALL_VOLUME { 4096 } else { 16384 });
+    let align = max(bs / INODE_SIZE, 8);
+    let ipg = max((blocks * bs / ratio + groups - 1) / groups, LOST_FOUND_INO as usize + 5);
+    let ipg = min((ipg + align - 1) / align * align, bpg);
+    let table_blocks = ipg * INODE_SIZE / bs;
+    let overhead = |g: usize, groups: usize| {
+        let descs = (groups * 32 + bs - 1) / bs;
+        (if has_super(g) { 1 + descs } else { 0 }) + 2 + table_blocks
+    };
+
+    // a last group too small to be worth its metadata is left out
+    let last = blocks - first_data_block - (groups - 1) * bpg;
+    if groups > 1 && last < overhead(groups - 1, groups) + 50 {
+        groups -= 1;
+        blocks = first_data_block + groups * bpg;
+    }
+    let descs = (groups * 32 + bs - 1) / bs;
+    // the root directory and lost+found take a block each
+    assert!(blocks - first_data_block >= overhead(0, groups) + 2,
+            "Unable to fit ext2 on a disk of {} sectors", dsize);
+    debug!("format dsize={} bs={} blocks={} groups={} ipg={}", dsize, bs, blocks, groups, ipg);
+
+    let now = to_unix(&SystemClock.now()) as u32;
+    let uuid = options.uuid.unwrap_or_else(|| {
+        let mut uuid = [0; 16];
+        let _ = (&mut uuid[0..4]).write_u32::<LittleEndian>(now);
+        uuid[6] = 0x40; // version 4
+        uuid[8] = 0x80; // variant 1
+        uuid
+    });
+
+    // block number of each group's bitmaps and inode table, and the blocks
+    // in use
+    let layout = |g: usize| {
+        let start = first_data_block + g * bpg;
+        let block_bitmap = start + overhead(g, groups) - 2 - table_blocks;
+        let used = overhead(g, groups) + if g == 0 { 2 } else { 0 };
+        (start, min(bpg, blocks - start), block_bitmap, used)
+    };
+    let free_blocks = (0..groups).map(|g| { let (_, size, _, used) = layout(g); size - used })
+                                 .fold(0, |a, b| a + b);
+    let free_inodes = groups * ipg - LOST_FOUND_INO as usize;
+
+    let mut sb = vec![0; 1024];
+    let _ = (&mut sb[0..4]).write_u32::<LittleEndian>((groups * ipg) as u32); // inode count
+    let _ = (&mut sb[4..8]).write_u32::<LittleEndian>(blocks as u32); // block count
+    let _ = (&mut sb[8..12]).write_u32::<LittleEndian>(0); // none are reserved for the superuser
+    let _ = (&mut sb[12..16]).write_u32::<LittleEndian>(free_blocks as u32);
+    let _ = (&mut sb[16..20]).write_u32::<LittleEndian>(free_inodes as u32);
+    let _ = (&mut sb[20..24]).write_u32::<LittleEndian>(first_data_block as u32);
+    let _ = (&mut sb[24..28]).write_u32::<LittleEndian>(bs.trailing_zeros() - 10); // block size
+    let _ = (&mut sb[28..32]).write_u32::<LittleEndian>(bs.trailing_zeros() - 10); // fragment size
+    let _ = (&mut sb[32..36]).write_u32::<LittleEndian>(bpg as u32); // blocks per group
+    let _ = (&mut sb[36..40]).write_u32::<LittleEndian>(bpg as u32); // fragments per group
+    let _ = (&mut sb[40..44]).write_u32::<LittleEndian>(ipg as u32); // inodes per group
+    let _ = (&mut sb[48..52]).write_u32::<LittleEndian>(now); // last written
+    let _ = (&mut sb[54..56]).write_u16::<LittleEndian>(0xFFFF); // mounts before a check: never
+    let _ = (&mut sb[56..58]).write_u16::<LittleEndian>(MAGIC);
+    let _ = (&mut sb[58..60]).write_u16::<LittleEndian>(1); // state: clean
+    let _ = (&mut sb[60..62]).write_u16::<LittleEndian>(1); // on errors: continue
+    let _ = (&mut sb[64..68]).write_u32::<LittleEndian>(now); // last checked
+    let _ = (&mut sb[76..80]).write_u32::<LittleEndian>(1); // revision, creator OS is Linux (0)
+    let _ = (&mut sb[84..88]).write_u32::<LittleEndian>(LOST_FOUND_INO);
+    let _ = (&mut sb[88..90]).write_u16::<LittleEndian>(INODE_SIZE as u16);
+    let _ = (&mut sb[96..100]).write_u32::<LittleEndian>(INCOMPAT_FILETYPE);
+    let _ = (&mut sb[100..104]).write_u32::<LittleEndian>(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE);
+    copy_memory(&uuid, &mut sb[104..120]);
+    let _ = (&mut sb[264..268]).write_u32::<LittleEndian>(now); // created
+
+    let mut table = vec![0; descs * bs];
+    for g in 0..groups {
+        let (_, size, block_bitmap, used) = layout(g);
+        let desc = &mut table[g * 32..g * 32 + 32];
+        let _ = (&mut desc[0..4]).write_u32::<LittleEndian>(block_bitmap as u32);
+        let _ = (&mut desc[4..8]).write_u32::<LittleEndian>(block_bitmap as u32 + 1); // inode bitmap
+        let _ = (&mut desc[8..12]).write_u32::<LittleEndian>(block_bitmap as u32 + 2); // inode table
+        let _ = (&mut desc[12..14]).write_u16::<LittleEndian>((size - used) as u16);
+        let inodes = if g == 0 { ipg - LOST_FOUND_INO as usize } else { ipg };
+        let _ = (&mut desc[14..16]).write_u16::<LittleEndian>(inodes as u16);
+        let _ = (&mut desc[16..18]).write_u16::<LittleEndian>(if g == 0 { 2 } else { 0 }); // directories
+    }
+
+    for g in 0..groups {
+        let (start, size, block_bitmap, used) = layout(g);
+        if has_super(g) {
+            // backups are at the start of their group rather than 1024 bytes in
+            let _ = (&mut sb[90..92]).write_u16::<LittleEndian>(g as u16);
+            let lba = if g == 0 { SUPERBLOCK / 512 } else { start * spb };
+            for (i, sector) in sb.chunks(512).enumerate() {
+                try!(disk.write_sector(lba + i, sector));
+            }
+            for (i, sector) in table.chunks(512).enumerate() {
+                try!(disk.write_sector((start + 1) * spb + i, sector));
+            }
+        }
+
+        // bits past the end of the group are set, as if those blocks were in use
+        let mut bits = vec![0; bs];
+        for i in (0..used).chain(size..bpg) {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+        let reserved = if g == 0 { LOST_FOUND_INO as usize } else { 0 };
+        let mut inode_bits = vec![0; bs];
+        for i in (0..reserved).chain(ipg..bpg) {
+            inode_bits[i / 8] |= 1 << (i % 8);
+        }
+        for (b, data) in vec![(block_bitmap, bits), (block_bitmap + 1, inode_bits)] {
+            for (i, sector) in data.chunks(512).enumerate() {
+                try!(disk.write_sector(b * spb + i, sector));
+            }
+        }
+        for i in 0..table_blocks * spb {
+            try!(disk.write_sector((block_bitmap + 2) * spb + i, &EMPTY_SECTOR));
+        }
+    }
+
+    // the root directory and lost+found, in the first two data blocks
+    let (_, _, block_bitmap, used) = layout(0);
+    let inode_table = block_bitmap + 2;
+    let (root_block, lost_block) = (used - 2, used - 1);
+    let mut root = vec![0; bs];
+    encode_entry(&mut root, ROOT_INO, 12, b".", FT_DIR);
+    encode_entry(&mut root[12..], ROOT_INO, 12, b"..", FT_DIR);
+    encode_entry(&mut root[24..], LOST_FOUND_INO, bs - 24, b"lost+found", FT_DIR);
+    let mut lost = vec![0; bs];
+    encode_entry(&mut lost, LOST_FOUND_INO, 12, b".", FT_DIR);
+    encode_entry(&mut lost[12..], ROOT_INO, bs - 12, b"..", FT_DIR);
+
+    for &(ino, b, ref data, mode, links) in &[(ROOT_INO, root_block, root, S_IFDIR | 0o755, 3),
+                                              (LOST_FOUND_INO, lost_block, lost, S_IFDIR | 0o700, 2)] {
+        let b = first_data_block + b;
+        for (i, sector) in data.chunks(512).enumerate() {
+            try!(disk.write_sector(b * spb + i, sector));
+        }
+
+        let mut inode = Inode::new(mode, now);
+        inode.size = bs;
+        inode.links = links;
+        inode.sectors = spb;
+        inode.block[0] = b as u32;
+        let byte = (ino as usize - 1) * INODE_SIZE;
+        let lba = inode_table * spb + byte / 512;
+        let mut sector = try!(disk.read_sector(lba)).clone();
+        inode.encode(&mut sector[byte % 512..byte % 512 + INODE_SIZE]);
+        try!(disk.write_sector(lba, &sector));
+    }
+
+    Ok(())
+}
+
+/// Byte offset of the primary superblock, the boot block comes before it
+const SUPERBLOCK: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+/// Volumes of fewer sectors get 1KiB blocks and more inodes, like mke2fs
+/// picks for "small" filesystems
+const SMALL_VOLUME: usize = 1 << 20;
+
+const ROOT_INO: u32 = 2;
+/// Reserved for a boot loader
+const BOOT_LOADER_INO: u32 = 5;
+/// The first inode which isn't reserved
+const LOST_FOUND_INO: u32 = 11;
+/// Size of the inodes written by `format()`
+const INODE_SIZE: usize = 128;
+/// Blocks an inode points to directly, before the indirect ones
+const DIRECT: usize = 12;
+
+// Feature flags
+const INCOMPAT_FILETYPE: u32 = 0x0002; // directory entries record the file type
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001; // not every group has a backup superblock
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002; // files may be 2GiB or larger
+
+// Inode flags
+const INDEX_FL: u32 = 0x1000; // directory has a hash index
+
+// Inode modes
+const S_IFMT: u16 = 0xF000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xA000;
+
+// File types in directory entries
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write, Seek, SeekFrom};
+    use std::path::Path;
+
+    use disk::{Disk, RamDisk, Error};
+    use fs::{FileSystem, FixedClock, DateTime, FileType, Mode, attributes};
+    use super::{Ext2, Inode, FormatOptions, format, format_with, u16_at, u32_at, BOOT_LOADER_INO, DIRECT, ROOT_INO, S_IFREG};
+
+    fn ramdisk_fs(size: usize) -> Ext2 {
+        let mut disk = RamDisk::new(size);
+        format(&mut disk).unwrap();
+        Ext2::new(Box::new(disk)).unwrap()
+    }
+
+    /// The superblock at byte 1024 of a disk
+    fn superblock(disk: &Disk) -> Vec<u8> {
+        let mut raw = disk.read_sector(2).unwrap().to_vec();
+        raw.extend(disk.read_sector(3).unwrap().iter().cloned());
+        raw
+    }
+
+    #[test]
+    fn superblock_fields() {
+        let mut disk = RamDisk::new(4096);
+        let uuid = [7; 16];
+        format_with(&mut disk, &FormatOptions { uuid: Some(uuid), ..FormatOptions::default() }).unwrap();
+
+        // 2048 blocks of 1KiB in one group, the boot block isn't part of it
+        let sb = superblock(&disk);
+        assert_eq!(u16_at(&sb, 56), 0xEF53);
+        assert_eq!(u32_at(&sb, 4), 2048);
+        assert_eq!(u32_at(&sb, 20), 1);
+        assert_eq!(u32_at(&sb, 24), 0);
+        assert_eq!(u32_at(&sb, 0), 512); // one inode per 4KiB
+        assert_eq!(u32_at(&sb, 16), 512 - 11);
+        assert_eq!(u32_at(&sb, 76), 1);
+        assert_eq!(u16_at(&sb, 88), 128);
+        assert_eq!(&sb[104..120], &uuid);
+        // superblock, descriptors, bitmaps, 64 blocks of inodes, root and lost+found
+        assert_eq!(u32_at(&sb, 12), 2048 - 1 - 70);
+
+        let mut fs = Ext2::new(Box::new(disk)).unwrap();
+        assert_eq!(fs.free_blocks, 2048 - 1 - 70);
+        assert_eq!(fs.free_inodes, 512 - 11);
+        let names = fs.read_dir(Path::new("/")).unwrap().map(|e| e.unwrap().name).collect::<Vec<String>>();
+        assert_eq!(names, vec!["lost+found".to_owned()]);
+        let root = fs.read_inode(ROOT_INO).unwrap();
+        assert_eq!((root.links, root.size, root.sectors), (3, 1024, 2));
+
+        // features it doesn't know of keep it from mounting
+        let mut disk = RamDisk::new(4096);
+        format(&mut disk).unwrap();
+        let mut sector = disk.read_sector(2).unwrap().clone();
+        sector[96] |= 0x40; // extents
+        disk.write_sector(2, &sector).unwrap();
+        assert_eq!(Ext2::new(Box::new(disk)).err(), Some(Error::CorruptBPB("incompatible features")));
+
+        // a FAT volume isn't mistaken for ext2
+        let mut disk = RamDisk::new(4096);
+        ::fs::fat::format(&mut disk).unwrap();
+        assert_eq!(Ext2::new(Box::new(disk)).err(), Some(Error::CorruptBPB("not an ext2 superblock")));
+    }
+
+    #[test]
+    fn groups() {
+        // 20000 blocks, the third group is smaller than the others
+        let mut disk = RamDisk::new(40000);
+        format(&mut disk).unwrap();
+        let sb = superblock(&disk);
+        assert_eq!(u32_at(&sb, 4), 20000);
+        assert_eq!(u32_at(&sb, 32), 8192);
+
+        // only groups 0, 1 and powers of 3, 5 and 7 have a backup
+        let backup = disk.read_sector(8193 * 2).unwrap();
+        assert_eq!(u16_at(&backup[..], 56), 0xEF53);
+        assert_eq!(u16_at(&backup[..], 90), 1);
+        let start = disk.read_sector(16385 * 2).unwrap();
+        assert!(u16_at(&start[..], 56) != 0xEF53);
+
+        let fs = Ext2::new(Box::new(disk)).unwrap();
+        assert_eq!(fs.groups.len(), 3);
+        assert_eq!(fs.groups[1].block_bitmap, 8193 + 2);
+        assert_eq!(fs.groups[2].block_bitmap, 16385);
+        let free = fs.groups.iter().map(|g| g.free_blocks).fold(0, |a, b| a + b);
+        assert_eq!(free, fs.free_blocks);
+        assert_eq!(free, u32_at(&sb, 12) as usize);
+
+        // with larger blocks the superblock shares block 0 with the boot sectors
+        let mut disk = RamDisk::new(40000);
+        disk.write_sector(1, &[0xBB; 512]).unwrap();
+        format_with(&mut disk, &FormatOptions { block_size: Some(4096), ..FormatOptions::default() }).unwrap();
+        assert_eq!(disk.read_sector(1).unwrap()[0], 0xBB);
+        let sb = superblock(&disk);
+        assert_eq!((u32_at(&sb, 4), u32_at(&sb, 20), u32_at(&sb, 24)), (5000, 0, 2));
+        let mut fs = Ext2::new(Box::new(disk)).unwrap();
+        fs.write_file(Path::new("kernel.bin"), &[1; 10000]).unwrap();
+        assert_eq!(fs.read_inode(12).unwrap().sectors, 24);
+    }
+
+    #[test]
+    fn read_file() {
+        let mut fs = ramdisk_fs(4096);
+        let free = fs.free_blocks;
+        fs.write_file(Path::new("kernel.bin"), &[0xAA; 3000]).unwrap();
+        assert_eq!(fs.free_blocks, free - 3);
+
+        let mut buf = [0; 3000];
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf[..100]), Err(Error::BufferTooSmall(3000)));
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(3000));
+        assert!(buf.iter().all(|&b| b == 0xAA));
+        assert_eq!(fs.read_file(Path::new("initrd.bin"), &mut buf),
+                   Err(Error::Nonexistent(Path::new("initrd.bin").to_owned())));
+        assert_eq!(fs.read_file(Path::new("lost+found"), &mut buf),
+                   Err(Error::NotAFile(Path::new("lost+found").to_owned())));
+
+        // overwriting frees what's no longer needed
+        fs.write_file(Path::new("kernel.bin"), b"kernel").unwrap();
+        assert_eq!(fs.free_blocks, free - 1);
+        let mut fs = Ext2::new(fs.disk).unwrap();
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(6));
+        assert_eq!(&buf[..6], b"kernel");
+        assert_eq!(fs.free_blocks, free - 1);
+
+        fs.delete(Path::new("kernel.bin")).unwrap();
+        assert_eq!(fs.free_blocks, free);
+        assert_eq!(fs.free_inodes, 512 - 11);
+    }
+
+    #[test]
+    fn indirect_blocks() {
+        let fs = ramdisk_fs(4096);
+        // 256 block numbers fit in each indirect block
+        assert_eq!(fs.tier(11), Some((11, 0, 0)));
+        assert_eq!(fs.tier(DIRECT), Some((12, 1, 0)));
+        assert_eq!(fs.tier(DIRECT + 256), Some((13, 2, 0)));
+        assert_eq!(fs.tier(DIRECT + 256 + 65536 + 5), Some((14, 3, 5)));
+        assert_eq!(fs.tier(DIRECT + 256 + 65536 + 256 * 65536), None);
+        let empty = Inode::new(0, 0);
+        assert_eq!(fs.blocks_needed(&empty, 0, 300).unwrap(), 300 + 1 + 2);
+        assert_eq!(fs.blocks_needed(&empty, 100000, 100001).unwrap(), 1 + 3);
+
+        // 293 blocks of data, reaching into the double indirect blocks
+        let mut fs = fs;
+        let free = fs.free_blocks;
+        let data = (0..300000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs.write_file(Path::new("kernel.bin"), &data).unwrap();
+        assert_eq!(fs.free_blocks, free - 293 - 3);
+        let inode = fs.read_inode(12).unwrap();
+        assert_eq!(inode.sectors, (293 + 3) * 2);
+        assert!(inode.block[..14].iter().all(|&b| b != 0));
+        assert_eq!(inode.block[14], 0);
+
+        let mut buf = vec![0; 300000];
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(300000));
+        assert!(buf == data);
+
+        // only the direct blocks are left
+        fs.truncate(Path::new("kernel.bin"), DIRECT * 1024).unwrap();
+        assert_eq!(fs.free_blocks, free - 12);
+        let inode = fs.read_inode(12).unwrap();
+        assert_eq!((inode.block[12], inode.block[13], inode.sectors), (0, 0, 24));
+        fs.delete(Path::new("kernel.bin")).unwrap();
+        assert_eq!(fs.free_blocks, free);
+    }
+
+    #[test]
+    fn directories() {
+        let mut fs = ramdisk_fs(4096);
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.make_dir(Path::new("boot/grub")).unwrap();
+        fs.write_file(Path::new("boot/grub/grub.cfg"), b"menu").unwrap();
+        assert_eq!(fs.make_dir(Path::new("boot")), Err(Error::AlreadyExists(Path::new("boot").to_owned())));
+
+        // each subdirectory links back with `..`
+        assert_eq!(fs.read_inode(ROOT_INO).unwrap().links, 4);
+        let boot = fs.find_inode(Path::new("boot")).unwrap();
+        assert_eq!(fs.read_inode(boot).unwrap().links, 3);
+        assert_eq!(fs.groups[0].dirs, 4);
+
+        let mut buf = [0; 4];
+        assert_eq!(fs.read_file(Path::new("boot/grub/../grub/./grub.cfg"), &mut buf), Ok(4));
+        assert_eq!(fs.read_file(Path::new("../boot/grub/grub.cfg"), &mut buf), Ok(4));
+        assert_eq!(fs.read_dir(Path::new("boot/grub/grub.cfg/..")).err(),
+                   Some(Error::NotADirectory(Path::new("boot/grub/grub.cfg").to_owned())));
+        let listing = fs.read_dir(Path::new("boot")).unwrap().map(|e| e.unwrap()).collect::<Vec<_>>();
+        assert_eq!(listing.len(), 1);
+        assert_eq!((&*listing[0].name, listing[0].kind, listing[0].size), ("grub", FileType::Directory, 0));
+
+        assert_eq!(fs.delete(Path::new("boot/grub")),
+                   Err(Error::DirectoryNotEmpty(Path::new("boot/grub").to_owned())));
+        fs.delete(Path::new("boot/grub/grub.cfg")).unwrap();
+        fs.delete(Path::new("boot/grub")).unwrap();
+        assert_eq!(fs.read_dir(Path::new("boot")).unwrap().count(), 0);
+        assert_eq!(fs.read_inode(boot).unwrap().links, 2);
+
+        // names are case sensitive, and only `/` and NUL are reserved
+        fs.write_file(Path::new("boot/Kernel"), b"1").unwrap();
+        fs.write_file(Path::new("boot/kernel:*?"), b"2").unwrap();
+        assert_eq!(fs.read_dir(Path::new("boot")).unwrap().count(), 2);
+        assert_eq!(fs.write_file(Path::new("boot/a\0b"), b""), Err(Error::InvalidPath));
+
+        // the listing grows past one block, and deleted records are reused
+        let free = fs.free_blocks;
+        for i in 0..60 {
+            fs.write_file(Path::new(&format!("boot/module {:02}.bin", i)), &[]).unwrap();
+        }
+        assert_eq!(fs.free_blocks, free - 1);
+        assert_eq!(fs.read_inode(boot).unwrap().size, 2048);
+        for i in 0..60 {
+            fs.delete(Path::new(&format!("boot/module {:02}.bin", i))).unwrap();
+        }
+        for i in 0..60 {
+            fs.write_file(Path::new(&format!("boot/driver {:02}.bin", i)), &[]).unwrap();
+        }
+        assert_eq!(fs.free_blocks, free - 1);
+        let mut fs = Ext2::new(fs.disk).unwrap();
+        assert_eq!(fs.read_dir(Path::new("boot")).unwrap().count(), 62);
+    }
+
+    /// The free block and inode counts in the superblock and the first
+    /// group descriptor, which must agree with those in memory
+    fn check_counts(fs: &Ext2) {
+        let sb = superblock(&*fs.disk);
+        assert_eq!(u32_at(&sb, 12) as usize, fs.free_blocks);
+        assert_eq!(u32_at(&sb, 16) as usize, fs.free_inodes);
+        let gd = fs.disk.read_sector(4).unwrap();
+        assert_eq!(u16_at(&gd[..], 12) as usize, fs.groups[0].free_blocks);
+        assert_eq!(u16_at(&gd[..], 14) as usize, fs.groups[0].free_inodes);
+        assert_eq!(u16_at(&gd[..], 16) as usize, fs.groups[0].dirs);
+    }
+
+    #[test]
+    fn free_counts() {
+        let mut fs = ramdisk_fs(4096);
+        let (blocks, inodes) = (fs.free_blocks, fs.free_inodes);
+        fs.write_file(Path::new("kernel.bin"), &[1; 20000]).unwrap();
+        check_counts(&fs);
+        fs.make_dir(Path::new("boot")).unwrap();
+        check_counts(&fs);
+        {
+            let mut handle = fs.open(Path::new("boot/initrd.img"), Mode::Write).unwrap();
+            handle.write_all(&[2; 5000]).unwrap();
+        }
+        check_counts(&fs);
+        fs.truncate(Path::new("kernel.bin"), 1000).unwrap();
+        check_counts(&fs);
+        fs.delete(Path::new("kernel.bin")).unwrap();
+        fs.delete(Path::new("boot/initrd.img")).unwrap();
+        fs.delete(Path::new("boot")).unwrap();
+        check_counts(&fs);
+        assert_eq!((fs.free_blocks, fs.free_inodes), (blocks, inodes));
+    }
+
+    #[test]
+    fn boot_loader() {
+        let mut fs = ramdisk_fs(4096);
+        let free = fs.free_blocks;
+        let sector = fs.reserve_boot_loader(3000).unwrap();
+        let inode = fs.read_inode(BOOT_LOADER_INO).unwrap();
+        assert_eq!(inode.size, 3000);
+        assert_eq!(inode.sectors, 6);
+        assert_eq!(fs.map_block(&inode, 0).unwrap(), Some(sector / 2));
+        assert_eq!(fs.free_blocks, free - 3);
+        check_counts(&fs);
+
+        // reserving again replaces the old blocks, an indirect block
+        // mustn't land among the new ones
+        fs.write_file(Path::new("kernel.bin"), &[1; 1024]).unwrap();
+        let sector = fs.reserve_boot_loader(DIRECT * 1024 + 100).unwrap();
+        let inode = fs.read_inode(BOOT_LOADER_INO).unwrap();
+        for i in 0..DIRECT + 1 {
+            assert_eq!(fs.map_block(&inode, i).unwrap(), Some(sector / 2 + i));
+        }
+        assert!(inode.block[DIRECT] as usize > sector / 2 + DIRECT);
+        assert_eq!(fs.free_blocks, free - 1 - (DIRECT + 2));
+        check_counts(&fs);
+        let mut buf = [0; 1024];
+        fs.read_file(Path::new("kernel.bin"), &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn open() {
+        let mut fs = ramdisk_fs(4096);
+        {
+            let mut handle = fs.open(Path::new("kernel.bin"), Mode::Write).unwrap();
+            for i in 0..100 {
+                handle.write_all(&[i as u8; 100]).unwrap();
+            }
+            let mut buf = [0; 1];
+            assert!(handle.read(&mut buf).is_err());
+        }
+        assert_eq!(fs.metadata(Path::new("kernel.bin")).unwrap().size, 10000);
+
+        {
+            let mut handle = fs.open(Path::new("kernel.bin"), Mode::ReadWrite).unwrap();
+            handle.seek(SeekFrom::Start(5050)).unwrap();
+            handle.write_all(b"patched").unwrap();
+            // past the end leaves a hole
+            handle.seek(SeekFrom::End(3000)).unwrap();
+            handle.write_all(b"end").unwrap();
+
+            handle.seek(SeekFrom::Start(5000)).unwrap();
+            let mut buf = vec![];
+            Read::by_ref(&mut handle).take(57).read_to_end(&mut buf).unwrap();
+            assert_eq!(&buf[50..], b"patched");
+            assert!(buf[..50].iter().all(|&b| b == 50));
+            handle.seek(SeekFrom::Start(12000)).unwrap();
+            let mut rest = vec![];
+            handle.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest.len(), 1003);
+            assert!(rest[..1000].iter().all(|&b| b == 0));
+        }
+        assert_eq!(fs.metadata(Path::new("kernel.bin")).unwrap().size, 13003);
+
+        // opening for writing truncates
+        let free = fs.free_blocks;
+        fs.open(Path::new("kernel.bin"), Mode::Write).unwrap();
+        assert_eq!(fs.metadata(Path::new("kernel.bin")).unwrap().size, 0);
+        assert_eq!(fs.free_blocks, free + 12);
+        assert!(fs.open(Path::new("initrd.bin"), Mode::Read).is_err());
+        assert!(fs.open(Path::new("lost+found"), Mode::ReadWrite).is_err());
+    }
+
+    #[test]
+    fn sparse_files() {
+        let mut fs = ramdisk_fs(4096);
+        fs.write_file(Path::new("disk.img"), b"header").unwrap();
+        let free = fs.free_blocks;
+
+        // growing doesn't allocate, the rest reads as zero
+        fs.truncate(Path::new("disk.img"), 1 << 20).unwrap();
+        assert_eq!(fs.free_blocks, free);
+        let mut buf = vec![0xFF; 1 << 20];
+        assert_eq!(fs.read_file(Path::new("disk.img"), &mut buf), Ok(1 << 20));
+        assert_eq!(&buf[..6], b"header");
+        assert!(buf[6..].iter().all(|&b| b == 0));
+
+        // the data block, with an indirect and double indirect block to find it
+        fs.write_at(Path::new("disk.img"), 500000, b"middle").unwrap();
+        assert_eq!(fs.free_blocks, free - 3);
+        fs.write_at(Path::new("disk.img"), 2 << 20, b"end").unwrap();
+        assert_eq!(fs.metadata(Path::new("disk.img")).unwrap().size, (2 << 20) + 3);
+        let mut buf = vec![0xFF; (2 << 20) + 3];
+        fs.read_file(Path::new("disk.img"), &mut buf).unwrap();
+        assert_eq!(&buf[500000..500006], b"middle");
+        assert_eq!(&buf[2 << 20..], b"end");
+        assert_eq!(buf.iter().filter(|&&b| b != 0).count(), 6 + 6 + 3);
+
+        // shrinking then growing doesn't bring back the old contents
+        fs.truncate(Path::new("disk.img"), 3).unwrap();
+        assert_eq!(fs.free_blocks, free);
+        fs.append(Path::new("disk.img"), b"!").unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(fs.read_file(Path::new("disk.img"), &mut buf), Ok(4));
+        assert_eq!(&buf, b"hea!");
+
+        // a size of 4GiB or more only fits where usize has 64 bits
+        let mut raw = [0; 128];
+        Inode::new(S_IFREG | 0o644, 0).encode(&mut raw);
+        raw[108] = 1;
+        let size = Inode::decode(&raw).map(|inode| inode.size as u64);
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(size, Ok(1 << 32));
+        } else {
+            assert_eq!(size, Err(Error::FileTooLarge));
+        }
+        fs.truncate(Path::new("disk.img"), 0).unwrap();
+        assert_eq!(fs.free_blocks, free + 1);
+    }
+
+    #[test]
+    fn no_space() {
+        let mut fs = ramdisk_fs(4096);
+        let free = fs.free_blocks;
+        let data = vec![7; free * 1024];
+
+        // too big to ever fit, nothing is left behind
+        assert_eq!(fs.write_file(Path::new("big.bin"), &data), Err(Error::NoSpace));
+        assert_eq!(fs.free_blocks, free);
+        assert!(fs.metadata(Path::new("big.bin")).is_err());
+
+        // fills the volume exactly, with the indirect blocks it needs: one
+        // single, one double and the 7 below that
+        let fits = (free - 9) * 1024;
+        fs.write_file(Path::new("big.bin"), &data[..fits]).unwrap();
+        assert_eq!(fs.free_blocks, 0);
+        assert_eq!(fs.make_dir(Path::new("boot")), Err(Error::NoSpace));
+        assert_eq!(fs.append(Path::new("big.bin"), &[1]), Err(Error::NoSpace));
+        fs.write_at(Path::new("big.bin"), 0, &[8; 100]).unwrap();
+        // files without any data only need an inode
+        fs.write_file(Path::new("empty.bin"), &[]).unwrap();
+
+        // a failed overwrite keeps the old contents
+        assert_eq!(fs.write_file(Path::new("empty.bin"), &[1]), Err(Error::NoSpace));
+        assert_eq!(fs.write_file(Path::new("big.bin"), &data[..fits + 1]), Err(Error::NoSpace));
+        let mut buf = vec![0; fits];
+        assert_eq!(fs.read_file(Path::new("big.bin"), &mut buf), Ok(fits));
+        assert_eq!(&buf[..101], &[&[8; 100][..], &[7]].concat()[..]);
+        fs.delete(Path::new("big.bin")).unwrap();
+        fs.delete(Path::new("empty.bin")).unwrap();
+        assert_eq!(fs.free_blocks, free);
+
+        // running out of inodes
+        let mut disk = RamDisk::new(4096);
+        format_with(&mut disk, &FormatOptions { bytes_per_inode: Some(1 << 20), ..FormatOptions::default() })
+            .unwrap();
+        let mut fs = Ext2::new(Box::new(disk)).unwrap();
+        assert_eq!(fs.free_inodes, 16 - 11);
+        for i in 0..5 {
+            fs.write_file(Path::new(&format!("{}.bin", i)), b"x").unwrap();
+        }
+        let free = fs.free_blocks;
+        assert_eq!(fs.write_file(Path::new("5.bin"), b"x"), Err(Error::NoSpace));
+        assert_eq!(fs.make_dir(Path::new("boot")), Err(Error::NoSpace));
+        assert_eq!(fs.free_blocks, free);
+    }
+
+    #[test]
+    fn rename() {
+        let mut fs = ramdisk_fs(4096);
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.make_dir(Path::new("boot/grub")).unwrap();
+        fs.write_file(Path::new("kernel.bin"), b"kernel").unwrap();
+        fs.write_file(Path::new("boot/old.bin"), b"old").unwrap();
+
+        fs.rename(Path::new("kernel.bin"), Path::new("boot/a much longer kernel name.bin"), false).unwrap();
+        let mut buf = [0; 6];
+        assert_eq!(fs.read_file(Path::new("boot/a much longer kernel name.bin"), &mut buf), Ok(6));
+        assert!(fs.metadata(Path::new("kernel.bin")).is_err());
+
+        assert_eq!(fs.rename(Path::new("boot/old.bin"), Path::new("boot/a much longer kernel name.bin"), false),
+                   Err(Error::AlreadyExists(Path::new("boot/a much longer kernel name.bin").to_owned())));
+        assert_eq!(fs.rename(Path::new("boot/old.bin"), Path::new("boot/grub"), true),
+                   Err(Error::NotAFile(Path::new("boot/grub").to_owned())));
+        let free = fs.free_inodes;
+        fs.rename(Path::new("boot/old.bin"), Path::new("boot/a much longer kernel name.bin"), true).unwrap();
+        assert_eq!(fs.read_file(Path::new("boot/a much longer kernel name.bin"), &mut buf), Ok(3));
+        assert_eq!(fs.free_inodes, free + 1);
+
+        // directories keep their contents, but can't move inside themselves
+        assert_eq!(fs.rename(Path::new("boot"), Path::new("boot/grub/boot"), false), Err(Error::InvalidPath));
+        fs.rename(Path::new("boot/grub"), Path::new("grub"), false).unwrap();
+        fs.rename(Path::new("boot"), Path::new("grub/boot"), false).unwrap();
+        assert_eq!(fs.read_file(Path::new("grub/boot/a much longer kernel name.bin"), &mut buf), Ok(3));
+        assert_eq!(fs.read_file(Path::new("grub/boot/../boot/a much longer kernel name.bin"), &mut buf), Ok(3));
+
+        // link counts follow the `..` entries
+        let grub = fs.find_inode(Path::new("grub")).unwrap();
+        assert_eq!(fs.read_inode(ROOT_INO).unwrap().links, 4);
+        assert_eq!(fs.read_inode(grub).unwrap().links, 3);
+
+        // names differing only in case are different names
+        fs.rename(Path::new("grub"), Path::new("GRUB"), false).unwrap();
+        fs.rename(Path::new("GRUB"), Path::new("GRUB"), false).unwrap();
+        let mut names = fs.read_dir(Path::new("/")).unwrap().map(|e| e.unwrap().name).collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(names, vec!["GRUB".to_owned(), "lost+found".to_owned()]);
+    }
+
+    #[test]
+    fn permissions() {
+        let time = DateTime { year: 2015, month: 10, day: 16, hour: 12, minute: 53, second: 21 };
+        let mut disk = RamDisk::new(4096);
+        format(&mut disk).unwrap();
+        let mut fs = Ext2::with_clock(Box::new(disk), Box::new(FixedClock(time))).unwrap();
+        fs.make_dir(Path::new("etc")).unwrap();
+        fs.write_file(Path::new("etc/shadow"), b"root:*:").unwrap();
+        fs.write_file(Path::new(".profile"), b"").unwrap();
+
+        assert_eq!(fs.permissions(Path::new("etc")), Ok(0o755));
+        assert_eq!(fs.permissions(Path::new("etc/shadow")), Ok(0o644));
+        assert_eq!(fs.permissions(Path::new("lost+found")), Ok(0o700));
+        fs.set_permissions(Path::new("etc/shadow"), 0o4600).unwrap();
+        fs.set_owner(Path::new("etc/shadow"), 100000, 42).unwrap();
+
+        // read only means nobody may write to it
+        assert_eq!(fs.attributes(Path::new("etc")), Ok(attributes::DIRECTORY));
+        assert_eq!(fs.attributes(Path::new(".profile")), Ok(attributes::HIDDEN));
+        fs.set_attributes(Path::new("etc/shadow"), attributes::READ_ONLY | attributes::ARCHIVE).unwrap();
+        assert_eq!(fs.permissions(Path::new("etc/shadow")), Ok(0o4400));
+        fs.set_attributes(Path::new("etc/shadow"), attributes::Attributes::empty()).unwrap();
+        assert_eq!(fs.permissions(Path::new("etc/shadow")), Ok(0o4600));
+
+        let mut fs = Ext2::new(fs.disk).unwrap();
+        assert_eq!(fs.owner(Path::new("etc/shadow")), Ok((100000, 42)));
+        assert_eq!(fs.owner(Path::new("etc")), Ok((0, 0)));
+        let meta = fs.metadata(Path::new("etc/shadow")).unwrap();
+        assert_eq!((meta.kind, meta.size), (FileType::File, 7));
+        assert_eq!((meta.created, meta.modified, meta.accessed), (None, Some(time), Some(time)));
+        assert_eq!(fs.metadata(Path::new("/")).unwrap().kind, FileType::Directory);
+    }
+}
@@ -1,16 +1,233 @@
-use std::path::Path;
+use std::cell::Cell;
+use std::cmp::{max, min};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use disk::{Disk, Sector, EMPTY_SECTOR, Result, Error};
-use fs::FileSystem;
+use disk::{Disk, DiskInfo, Sector, EMPTY_SECTOR, Result, Error};
+use fs::{FileSystem, Attributes, Clock, SystemClock, DateTime, Entry, FileType, Handle, Metadata, Mode};
+use fs::attributes::{ARCHIVE, DIRECTORY, VOLUME_LABEL};
+use fs::bitmap::Bitmap;
 
+/// A FAT filesystem, which may be FAT12 or FAT16 despite the name
 pub struct Fat32 {
     disk: Box<Disk>, // Underlying medium
+    fat_type: FatType, // Width of the FAT entries
     fat_begin: usize, // LBA of first FAT
+    fats: usize, // Number of copies of the FAT
+    fat_size: usize, // Size of each FAT in sectors
+    active_fat: Cell<usize>, // Copy of the FAT being read from
+    mirrored: bool, // Whether writes go to every copy of the FAT
     cluster_begin: usize, // LBA address of first cluster
     cluster_size: usize, // Size of cluster in sectors
-    rdir_cluster: usize,  //Root directory cluster
+    rdir_cluster: usize,  //Root directory cluster, 0 for the fixed FAT12/16 root directory
+    rdir_begin: usize, // LBA of the fixed FAT12/16 root directory
+    rdir_entries: usize, // Size of the fixed FAT12/16 root directory, 0 for FAT32
+    fsinfo: Option<usize>, // LBA of the FSInfo sector, if there is one
+    fsinfo_dirty: bool, // Whether the free count or hint changed since FSInfo was written
+    used: Bitmap, // Which clusters are in use, indexed by cluster number
+    used_stale: Cell<bool>, // Whether `used` was loaded from a copy of the FAT since found corrupt
+    free_count: usize, // Number of free clusters
+    next_free: usize, // Cluster to begin searching from when allocating
+    clock: Box<Clock>, // Source of timestamps for dir entries
+}
+
+/// The BIOS parameter block and extended boot record of a FAT volume
+#[derive(Debug, Clone, PartialEq)]
+struct Bpb {
+    kind: FatType,
+    bytes_per_sector: usize,
+    sectors_per_cluster: usize,
+    reserved: usize, // reserved sectors, including the boot sector
+    fats: usize, // number of FATs
+    root_entries: usize, // size of the FAT12/16 root directory, 0 for FAT32
+    media: u8, // media descriptor
+    total_sectors: usize,
+    fat_size: usize, // size of each FAT in sectors
+    // the rest are only found on FAT32 volumes, and are zero otherwise
+    flags: u16, // bit 7 disables mirroring, bits 0-3 are then the active FAT
+    root_cluster: usize,
+    fsinfo: usize, // sector of the FSInfo structure, 0 if there is none
+    backup: usize, // sector of the backup boot sector, 0 if there is none
+}
+
+impl Bpb {
+    /// Parse the boot sector of a FAT volume
+    fn parse(header: &Sector) -> Result<Bpb> {
+        let u16_at = |i: usize| (&header[i..i+2]).read_u16::<LittleEndian>().unwrap() as usize;
+        let u32_at = |i: usize| (&header[i..i+4]).read_u32::<LittleEndian>().unwrap() as usize;
+
+        if header[510..512] != [0x55, 0xAA] {
+            return Err(Error::CorruptBPB("missing boot sector signature"))
+        }
+        // the FAT12/16 total sector count is only used if it's nonzero
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            n => n,
+        };
+
+        let mut bpb = Bpb {
+            kind: FatType::Fat32,
+            bytes_per_sector: u16_at(11),
+            sectors_per_cluster: header[13] as usize,
+            reserved: u16_at(14),
+            fats: header[16] as usize,
+            root_entries: u16_at(17),
+            media: header[21],
+            total_sectors: total_sectors,
+            fat_size: u16_at(22),
+            flags: 0,
+            root_cluster: 0,
+            fsinfo: 0,
+            backup: 0,
+        };
+        if bpb.fat_size == 0 {
+            // only FAT32 has a 32 bit FAT size, like Linux this is trusted
+            // over the cluster count so small FAT32 volumes can be mounted
+            bpb.fat_size = u32_at(36);
+            bpb.flags = u16_at(40) as u16;
+            bpb.root_cluster = u32_at(44);
+            bpb.fsinfo = match u16_at(48) { 0xFFFF => 0, n => n };
+            bpb.backup = match u16_at(50) { 0xFFFF => 0, n => n };
+        } else {
+            // nothing else tells FAT12 and FAT16 apart
+            let data = bpb.total_sectors.saturating_sub(bpb.cluster_begin());
+            bpb.kind = match FatType::from_clusters(data / max(bpb.sectors_per_cluster, 1)) {
+                FatType::Fat32 => return Err(Error::CorruptBPB("too many clusters for FAT16")),
+                kind => kind,
+            };
+        }
+        Ok(bpb)
+    }
+
+    /// Check that the values are sensible for a volume of `info.size` sectors
+    fn validate(&self, info: &DiskInfo) -> Result<()> {
+        let fail = |s| Err(Error::CorruptBPB(s));
+        if self.bytes_per_sector != info.sector_size {
+            return fail("bytes per sector doesn't match the disk")
+        }
+        if !self.sectors_per_cluster.is_power_of_two() || self.sectors_per_cluster > 128 {
+            return fail("sectors per cluster is not a power of two up to 128")
+        }
+        if self.media != 0xF0 && self.media < 0xF8 {
+            return fail("invalid media descriptor")
+        }
+        if self.reserved == 0 {
+            return fail("no reserved sectors for the boot sector")
+        }
+        if self.fats == 0 {
+            return fail("no FATs")
+        }
+        if self.fat_size == 0 {
+            return fail("FAT size is zero")
+        }
+        if self.kind == FatType::Fat32 && self.root_entries != 0 {
+            return fail("root directory entries on a FAT32 volume")
+        }
+        if self.kind != FatType::Fat32 && self.root_entries == 0 {
+            return fail("no root directory entries")
+        }
+        if !self.mirrored() && self.active_fat() >= self.fats {
+            return fail("active FAT out of range")
+        }
+        if self.total_sectors > info.size {
+            return fail("total sectors exceeds the size of the partition")
+        }
+        if self.cluster_begin() >= self.total_sectors {
+            return fail("FATs extend past the end of the volume")
+        }
+        if self.kind == FatType::Fat32 && (self.root_cluster < 2 || self.root_cluster >= self.clusters() + 2) {
+            return fail("root directory cluster out of range")
+        }
+        if self.fsinfo >= self.reserved || self.backup >= self.reserved {
+            return fail("FSInfo or backup boot sector outside of the reserved sectors")
+        }
+        Ok(())
+    }
+
+    /// Whether changes to the FAT are written to every copy
+    fn mirrored(&self) -> bool {
+        self.flags & 0x80 == 0
+    }
+
+    /// The copy of the FAT in use when mirroring is disabled
+    fn active_fat(&self) -> usize {
+        (self.flags & 0x0F) as usize
+    }
+
+    /// LBA of the FAT12/16 root directory
+    fn root_begin(&self) -> usize {
+        self.reserved + self.fats * self.fat_size
+    }
+
+    /// LBA of the first cluster
+    fn cluster_begin(&self) -> usize {
+        self.root_begin() + root_sectors(self.root_entries)
+    }
+
+    /// Number of data clusters
+    fn clusters(&self) -> usize {
+        count_clusters(self.kind, self.total_sectors, self.cluster_begin(), self.sectors_per_cluster, self.fat_size)
+    }
+}
+
+/// The variants of FAT, named for the width of their entries
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// The variant the spec requires for a volume of `clusters` clusters
+    pub fn from_clusters(clusters: usize) -> FatType {
+        match clusters {
+            0...4084 => FatType::Fat12,
+            4085...65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        }
+    }
+
+    fn bits(&self) -> usize {
+        match *self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        }
+    }
+
+    /// The bits of an entry which hold its value
+    fn mask(&self) -> u32 {
+        match *self {
+            FatType::Fat12 => 0x00000FFF,
+            FatType::Fat16 => 0x0000FFFF,
+            FatType::Fat32 => 0x0FFFFFFF, // the top 4 bits are reserved
+        }
+    }
+
+    /// Where the entry of cluster `c` is within the FAT
+    ///
+    /// Returns the offset of its first byte and how far the value is
+    /// shifted within the bytes, as FAT12 entries share a byte.
+    fn locate(&self, c: usize) -> (usize, usize) {
+        match *self {
+            FatType::Fat12 => (c + c / 2, (c % 2) * 4),
+            FatType::Fat16 => (c * 2, 0),
+            FatType::Fat32 => (c * 4, 0),
+        }
+    }
+
+    /// Number of bytes an entry spans
+    fn entry_bytes(&self) -> usize {
+        (self.bits() + 7) / 8
+    }
+
+    /// Number of entries in a FAT of `fsize` sectors
+    fn entries(&self, fsize: usize) -> usize {
+        fsize * 512 * 8 / self.bits()
+    }
 }
 
 enum FatEntry {
@@ -21,8 +238,57 @@ enum FatEntry {
     Reserved(u32),
 }
 
-// TODO: handle file attributes
-const IS_SUBDIR: u8 = 1 << 4;
+impl FatEntry {
+    /// Interpret the value of an entry of a `kind` FAT
+    fn decode(n: u32, kind: FatType) -> FatEntry {
+        let max = kind.mask();
+        match n {
+            // TODO: reorganize or something so that hot code path is first
+            n if n >= max - 7 => { FatEntry::End },
+            n if n == max - 8 => { FatEntry::Bad },
+            n if n >= max - 15 => { FatEntry::Reserved(n) },
+            n if n >= 0x00000002 => { FatEntry::Cont(n) },
+            n if n == 0x00000001 => { FatEntry::Reserved(n) },
+            _ => { FatEntry::Free },
+        }
+    }
+    fn encode(&self, kind: FatType) -> u32 {
+        match *self {
+            FatEntry::Cont(n)     => { n },
+            FatEntry::Free        => { 0x00000000 },
+            FatEntry::End         => { kind.mask() },
+            FatEntry::Bad         => { kind.mask() - 8 },
+            FatEntry::Reserved(n) => { n },
+        }
+    }
+}
+
+/// Timestamps stored in a directory entry
+///
+/// A zeroed date means the time was never recorded, so these are `None`.
+/// FAT only stores the date a file was last accessed, not the time.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct Times {
+    created: Option<DateTime>,
+    modified: Option<DateTime>,
+    accessed: Option<DateTime>,
+}
+impl Times {
+    /// Timestamps for an entry created at `now`
+    fn new(now: DateTime) -> Times {
+        Times {
+            created: Some(now),
+            modified: Some(now),
+            accessed: Some(now),
+        }
+    }
+    /// Record that an entry was written at `now`
+    fn touch(&mut self, now: DateTime) {
+        self.modified = Some(now);
+        self.accessed = Some(now);
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 enum DirEntry {
     End,
@@ -32,14 +298,35 @@ enum DirEntry {
         name: String,
         ext: String,
         start: usize,
+        attrib: Attributes,
+        times: Times,
     },
     File {
         name: String,
         ext: String,
         start: usize,
         size: usize,
-    }
+        attrib: Attributes,
+        times: Times,
+    },
+    /// A piece of a VFAT long file name
+    ///
+    /// These precede the short entry they describe, with the last piece
+    /// of the name first.
+    LongName {
+        ord: u8, // position of this piece, LFN_LAST is set on the final piece
+        checksum: u8, // checksum of the short name, see `lfn_checksum`
+        chars: [u16; 13], // UCS-2 characters
+    },
 }
+
+/// Attribute bits which mark a `DirEntry::LongName`
+const LFN_ATTRIB: u8 = 0x0F;
+/// Flag set in `ord` of the last piece of a long file name
+const LFN_LAST: u8 = 0x40;
+/// Byte offsets of the 13 characters within a long name entry
+const LFN_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
 impl DirEntry {
     fn name(&self) -> Option<&str> {
         let name: Option<&str> = match *self {
@@ -79,70 +366,256 @@ impl DirEntry {
             _ => None,
         }
     }
+    fn attrib(&self) -> Option<Attributes> {
+        match *self {
+            DirEntry::Dir { attrib, ..} => Some(attrib | DIRECTORY),
+            DirEntry::File { attrib, ..} => Some(attrib & !DIRECTORY),
+            _ => None,
+        }
+    }
+    fn times(&self) -> Option<Times> {
+        match *self {
+            DirEntry::Dir { times, ..} => Some(times),
+            DirEntry::File { times, ..} => Some(times),
+            _ => None,
+        }
+    }
+    /// The 11 bytes of the short name as stored on disk
+    fn short_name(&self) -> Option<[u8; 11]> {
+        use std::iter::repeat;
+
+        let (name, ext) = match *self {
+            DirEntry::Dir { ref name, ref ext, ..} => (name, ext),
+            DirEntry::File { ref name, ref ext, ..} => (name, ext),
+            _ => return None,
+        };
+
+        let mut short = [b' '; 11];
+        let name = name.bytes().chain(repeat(b' ')).take(8);
+        let ext = ext.bytes().chain(repeat(b' ')).take(3);
+        for (i, byte) in name.chain(ext).enumerate() {
+            short[i] = byte;
+        }
+        Some(short)
+    }
+    /// The short name formatted as `NAME.EXT`
+    fn short_file_name(&self) -> Option<String> {
+        let mut name = match self.name() {
+            Some(name) => name.trim_right().to_owned(),
+            None => return None,
+        };
+        if let Some(ext) = self.ext().map(|e| e.trim_right()) {
+            if ext.len() > 0 {
+                name.push('.');
+                name.push_str(ext);
+            }
+        }
+        Some(name)
+    }
+    fn set_short_name(&mut self, stem: String, extension: String) {
+        match *self {
+            DirEntry::Dir { ref mut name, ref mut ext, ..} |
+            DirEntry::File { ref mut name, ref mut ext, ..} => {
+                *name = stem;
+                *ext = extension;
+            },
+            _ => { },
+        }
+    }
 }
 
 impl Fat32 {
     pub fn new(disk: Box<Disk>) -> Result<Fat32> {
-        let fat_begin;
-        let cluster_begin;
+        Fat32::with_clock(disk, Box::new(SystemClock))
+    }
 
-        {
-            let header = try!(disk.read_sector(0));
-            fat_begin = (&header[14..16]).read_u16::<LittleEndian>().unwrap() as usize;
+    /// Mount a FAT filesystem, taking timestamps from `clock`
+    ///
+    /// Using a `FixedClock` makes the resulting images reproducible.
+    pub fn with_clock(disk: Box<Disk>, clock: Box<Clock>) -> Result<Fat32> {
+        let bpb = try!(Bpb::parse(try!(disk.read_sector(0))));
+        try!(bpb.validate(&disk.info()));
+        debug!("Fat32 bpb={:?}", bpb);
+
+        let clusters = bpb.clusters();
+        let mut fsinfo = None;
+        let mut next_free = 2;
 
-            let fats = (&header[16..17]).read_u8().unwrap() as usize;
-            let fsize = (&header[36..40]).read_u32::<LittleEndian>().unwrap() as usize;
-            cluster_begin = fat_begin + fats*fsize;
+        {
+            // the FSInfo values are only hints, the free count is recomputed
+            // from the FAT anyway, so only keep the next free cluster
+            let lba = bpb.fsinfo;
+            if lba != 0 {
+                let sector = try!(disk.read_sector(lba));
+                let u32_at = |i: usize| (&sector[i..i+4]).read_u32::<LittleEndian>().unwrap() as usize;
+                if u32_at(0) == FSINFO_LEAD_SIG && u32_at(484) == FSINFO_STRUCT_SIG &&
+                   u32_at(508) == FSINFO_TRAIL_SIG {
+                    fsinfo = Some(lba);
+                    if u32_at(492) >= 2 && u32_at(492) < clusters + 2 {
+                        next_free = u32_at(492);
+                    }
+                }
+            }
         }
 
-        Ok(Fat32 {
+        let active_fat = if bpb.mirrored() { 0 } else { bpb.active_fat() };
+        let used = try!(load_used(&*disk, bpb.kind, bpb.reserved + active_fat * bpb.fat_size, clusters));
+        let free_count = used.count_clear();
+        let fs = Fat32 {
             disk: disk,
-            fat_begin: fat_begin,
-            cluster_begin: cluster_begin,
-            cluster_size: 1,
-            rdir_cluster: 2,
-        })
+            fat_type: bpb.kind,
+            fat_begin: bpb.reserved,
+            fats: bpb.fats,
+            fat_size: bpb.fat_size,
+            active_fat: Cell::new(active_fat),
+            mirrored: bpb.mirrored(),
+            cluster_begin: bpb.cluster_begin(),
+            cluster_size: bpb.sectors_per_cluster,
+            rdir_cluster: bpb.root_cluster,
+            rdir_begin: bpb.root_begin(),
+            rdir_entries: bpb.root_entries,
+            fsinfo: fsinfo,
+            fsinfo_dirty: false,
+            used: used,
+            used_stale: Cell::new(false),
+            free_count: free_count,
+            next_free: next_free,
+            clock: clock,
+        };
+        debug!("Fat32 type={:?} clusters={} free_count={} next_free=0x{:x}",
+               fs.fat_type, clusters, fs.free_count, next_free);
+        Ok(fs)
+    }
+
+    /// Record the free cluster count and next free hint in the FSInfo sector
+    ///
+    /// Called once at the end of each operation rather than for every
+    /// cluster. Only the primary FSInfo sector is kept up to date, the
+    /// backup copy is left as `format()` wrote it, which the spec allows.
+    fn flush_fsinfo(&mut self) -> Result<()> {
+        try!(self.reload_used());
+        let lba = match self.fsinfo {
+            Some(lba) if self.fsinfo_dirty => lba,
+            _ => return Ok(()),
+        };
+        let mut sector = try!(self.disk.read_sector(lba)).clone();
+        (&mut sector[488..492]).write_u32::<LittleEndian>(self.free_count as u32).unwrap();
+        (&mut sector[492..496]).write_u32::<LittleEndian>(self.next_free as u32).unwrap();
+        try!(self.disk.write_sector(lba, &sector));
+        self.fsinfo_dirty = false;
+        Ok(())
+    }
+
+    /// Number of dir entries which fit in a cluster
+    fn dires_per_cluster(&self) -> usize {
+        self.cluster_size * 16
+    }
+
+    /// Number of dir entries in `cluster` of a directory listing
+    ///
+    /// Cluster 0 stands for the whole fixed size root directory of FAT12/16.
+    fn dires_in(&self, cluster: usize) -> usize {
+        if cluster == 0 { self.rdir_entries } else { self.dires_per_cluster() }
     }
 
     // TODO: should Fat32::read_cluster() even return Result???
-    fn read_cluster(&self, c: usize) -> Result<&Sector> {
-        debug!("read_cluster c=0x{:x} r=0x{:x}", c, self.cluster_begin + (c - 2) * self.cluster_size);
-        let r = self.disk.read_sector(self.cluster_begin + (c - 2) * self.cluster_size);
+    /// Read the `i`th sector of cluster `c`
+    fn read_cluster(&self, c: usize, i: usize) -> Result<&Sector> {
+        assert!(i < self.cluster_size, "Fat32: Invalid sector `{}` of cluster `0x{:x}`", i, c);
+        let lba = self.cluster_begin + (c - 2) * self.cluster_size + i;
+        debug!("read_cluster c=0x{:x} i={} r=0x{:x}", c, i, lba);
+        let r = self.disk.read_sector(lba);
         assert!(r.is_ok(), "Fat32: Invalid cluster `0x{:x}`", c);
         r
     }
 
-    fn write_cluster(&mut self, c: usize, data: &[u8]) -> Result<()> {
-        let r = self.disk.write_sector(self.cluster_begin + (c - 2) * self.cluster_size, data);
+    /// Write the `i`th sector of cluster `c`
+    fn write_cluster(&mut self, c: usize, i: usize, data: &[u8]) -> Result<()> {
+        assert!(i < self.cluster_size, "Fat32: Invalid sector `{}` of cluster `0x{:x}`", i, c);
+        let lba = self.cluster_begin + (c - 2) * self.cluster_size + i;
+        let r = self.disk.write_sector(lba, data);
         assert!(r.is_ok(), "Fat32: Invalid cluster `0x{:x}`", c);
         r
     }
 
+    /// Write a whole cluster, zero filling whatever `data` doesn't cover
+    fn fill_cluster(&mut self, c: usize, data: &[u8]) -> Result<()> {
+        for i in 0..self.cluster_size {
+            let part = data.chunks(512).nth(i).unwrap_or(&[]);
+            try!(self.write_cluster(c, i, part));
+        }
+        Ok(())
+    }
+
+    /// Which variant of FAT the volume uses
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Which copy of the FAT is being read from
+    ///
+    /// This is the first copy, unless it was found to be corrupt and
+    /// one of the mirrors was used instead.
+    pub fn active_fat(&self) -> usize {
+        self.active_fat.get()
+    }
+
+    /// Switch to reading the next copy of the FAT
+    ///
+    /// Returns `false` if there are no more copies to try.
+    fn fall_back(&self) -> bool {
+        let active = self.active_fat.get();
+        if !self.mirrored || active + 1 >= self.fats {
+            return false
+        }
+        warn!("Fat32: FAT copy {} is corrupt, reading copy {} instead", active, active + 1);
+        self.active_fat.set(active + 1);
+        // the corrupt copy may have marked clusters free which are in use
+        self.used_stale.set(true);
+        true
+    }
+
+    /// Rebuild the free cluster bitmap and count if the FAT copy they
+    /// were loaded from has since been found to be corrupt
+    ///
+    /// `fall_back()` only has a shared reference, so this is left until
+    /// the bitmap is next needed.
+    fn reload_used(&mut self) -> Result<()> {
+        if !self.used_stale.get() {
+            return Ok(())
+        }
+        let lba = self.fat_begin + self.active_fat.get() * self.fat_size;
+        self.used = try!(load_used(&*self.disk, self.fat_type, lba, self.used.len() - 2));
+        self.free_count = self.used.count_clear();
+        self.fsinfo_dirty = true;
+        self.used_stale.set(false);
+        debug!("reload_used active_fat={} free_count={}", self.active_fat.get(), self.free_count);
+        Ok(())
+    }
+
+    /// Find the next cluster of a chain which must not end at `cluster`
+    ///
+    /// If the FAT says otherwise, the mirrors are consulted.
+    fn expect_next(&self, cluster: usize) -> Result<usize> {
+        loop {
+            match try!(self.read_fate(cluster)) {
+                FatEntry::Cont(n) if (n as usize) < self.used.len() => return Ok(n as usize),
+                _ if self.fall_back() => { },
+                _ => return Err(Error::CorruptFAT),
+            }
+        }
+    }
+
     /// Read FAT entry
     ///
     /// Returns the FAT entry of the specified cluster
     fn read_fate(&self, c: usize) -> Result<FatEntry> {
-        let lba = self.fat_begin + (c / 128);
-        let offset = c % 128;
+        let lba = self.fat_begin + self.active_fat.get() * self.fat_size;
+        let entry = try!(read_entry(&*self.disk, self.fat_type, lba, c));
 
-        let fat_sector = try!(self.disk.read_sector(lba));
-        const MASK: u32 = 0x0fffffff;
-        let entry = MASK & (&fat_sector[offset..offset+4]).read_u32::<LittleEndian>().unwrap();
-
-        debug!("read_fate cluster=0x{:x} lba=0x{:x} offset=0x{:x} entry=0x{:x}", c, lba, offset, entry);
-
-        let status = match entry {
-            // TODO: reorganize or something so that hot code path is first
-            n if n >= 0x0FFFFFF8 => { FatEntry::End },
-            n if n == 0x0FFFFFF7 => { FatEntry::Bad },
-            n if n >= 0x0FFFFFF0 => { FatEntry::Reserved(n) },
-            n if n >= 0x00000002 => { FatEntry::Cont(n) },
-            n if n == 0x00000001 => { FatEntry::Reserved(n) },
-            n if n == 0x00000000 => { FatEntry::Free},
-            _ => unreachable!(),
-        };
+        debug!("read_fate cluster=0x{:x} entry=0x{:x}", c, entry);
 
-        Ok(status)
+        Ok(FatEntry::decode(entry, self.fat_type))
     }
 
     /// Write FAT entry
@@ -150,20 +623,25 @@ impl Fat32 {
     /// Writes the FAT entry for the specified cluster
     // TODO: resolve inconsistent naming with write_fate versus set_dire
     fn write_fate(&mut self, c: usize, fate: &FatEntry) -> Result<()> {
-        let entry = match *fate {
-            FatEntry::Cont(n)     => { n },
-            FatEntry::Free        => { 0x00000000 },
-            FatEntry::End         => { 0x0FFFFFFF },
-            FatEntry::Bad         => { 0x0FFFFFF7 },
-            FatEntry::Reserved(n) => { n },
-        };
+        let entry = fate.encode(self.fat_type);
 
-        let lba = self.fat_begin + c / 128;
-        let offset = c % 128;
+        for copy in 0..self.fats {
+            if !self.mirrored && copy != self.active_fat.get() {
+                continue
+            }
+            let lba = self.fat_begin + copy * self.fat_size;
+            try!(write_entry(&mut *self.disk, self.fat_type, lba, c, entry));
+        }
 
-        let mut fat_sector = try!(self.disk.read_sector(lba)).clone();
-        (&mut fat_sector[offset..offset+4]).write_u32::<LittleEndian>(entry).unwrap();
-        try!(self.disk.write_sector(lba, &fat_sector));
+        // keep the free cluster bitmap and count in sync
+        if c >= 2 && c < self.used.len() && self.used.get(c) != (entry != 0) {
+            self.used.set(c, entry != 0);
+            if entry == 0 {
+                self.free_count += 1;
+            } else {
+                self.free_count -= 1;
+            }
+        }
 
         Ok(())
     }
@@ -180,24 +658,29 @@ impl Fat32 {
         }
     }
 
-    /// Looks for a child in a directory
+    /// Find a child in a directory listing
     ///
-    /// `cluster` should point to the beginning of the directory listing.
-    /// All of the parent directories of `child` are ignored. Only the
-    /// file name and extension are used.
-    fn find_dire_cluster(&self, cluster: usize, child: &Path) -> Result<Option<usize>> {
-        // TODO: optimize with `find_dire_index`
-        // may involve the optimization mentioned in `find_dire_index`
-        // or specializing `find_dire_index` for this case.
-        match try!(self.find_dire_index(cluster, child)) {
-            Some(i) => {
-                // `DirEntry::start()` should always return Some below
-                // `find_dire_index()` above already proved the dire is a File/Dir
-                // which always have a .start()
-                Ok(try!(self.get_dire(cluster, i)).start())
+    /// `cluster` points to the beginning of the directory listing.
+    /// Only the file name of `child` is considered, which is compared
+    /// against both the long and the short name of each entry. Like other
+    /// FAT implementations, the comparison ignores case.
+    fn find_listed(&self, cluster: usize, child: &Path) -> Result<Option<Listed>> {
+        debug!("find_listed cluster=0x{:x} path={:?}", cluster, child);
+        let fname = match child.file_name().and_then(|n| n.to_str()) {
+            Some(fname) => fname,
+            None => return Ok(None), // can't have been stored in the first place
+        };
+
+        for listed in DirIter::new(self, cluster) {
+            let listed = try!(listed);
+            let matches = listed.long_name.as_ref().map_or(false, |n| names_equal(n, fname)) ||
+                          listed.dire.short_file_name().map_or(false, |n| names_equal(&n, fname));
+            if matches {
+                debug!("find_listed match index={}", listed.index);
+                return Ok(Some(listed))
             }
-            None => Ok(None), // file/dir not found in directory
         }
+        Ok(None)
     }
 
     /// Find index of a directory entry
@@ -207,54 +690,8 @@ impl Fat32 {
     /// The result is given relative to `cluster`, which may be greater than
     /// what any individual cluster may hold. `set_dire` / `get_dire` will
     /// adjust the index accordingly.
-    fn find_dire_index(&self, mut cluster: usize, child: &Path) -> Result<Option<usize>> {
-        // use `dire_index` to keep track of the index if the dir listing
-        // spans multiple clusters. The is the index relative to the beginning
-        // of the listing. Could potentially return a (usize, usize) that describes
-        // both the cluster in the middle of the directory listing and the index
-        // within that cluster
-        let mut dire_index = 0;
-        debug!("find_dire_cluster cluster=0x{:x} path={:?}", cluster, child);
-        loop {
-            let sector = try!(self.read_cluster(cluster));
-            for i in 0..16 {
-                let entry = &sector[i * 32 .. (i + 1) * 32];
-
-                debug!("find_dire_index entry[0]=0x{:x}", entry[0]);
-                match entry[0] {
-                    0x00 => { return Ok(None) } // end of directory
-                    0xE5 => { continue } // skip unused entry
-                    _ => { },
-                }
-
-                // if `name` is shorter than 8 chars, then it's padded with spaces
-                // same for `ext`, so remove them.
-                // TODO: ensure all paths are sanitized of spaces
-                let name = String::from_utf8_lossy(&entry[0..8]);
-                let ext = String::from_utf8_lossy(&entry[8..11]);
-                let fname = child.file_stem().unwrap_or("".as_ref());
-                let fext = child.extension().unwrap_or("".as_ref());
-
-                debug!("find_dire_cluster i={} name={:?} ext={:?} fname={:?} fext={:?}", i, name, ext, fname, fext);
-
-                if *name.trim_right() == *fname && *ext.trim_right() == *fext {
-                    debug!("find_dire_cluster match");
-                    return Ok(Some(dire_index + i))
-                }
-            }
-
-            dire_index += 16;
-            cluster = match try!(self.next_cluster(cluster)) {
-                Some(c) => c,
-                None => {
-                    // something went wrong
-                    // perhaps dir listing is corrupt and we missed an DirEntry::End
-                    // perhaps FAT is corrupt.
-                    // TODO: read backup FAT
-                    return Err(Error::CorruptFAT)
-                }
-            }
-        }
+    fn find_dire_index(&self, cluster: usize, child: &Path) -> Result<Option<usize>> {
+        Ok(try!(self.find_listed(cluster, child)).map(|listed| listed.index))
     }
 
     /// Find a free cluster
@@ -264,135 +701,368 @@ impl Fat32 {
     /// is given, extend FAT chain. The `old` cluster can poitn anywhere
     /// in the FAT chain (e.g. at the beginning, middle, or end)
     fn alloc_cluster(&mut self, old: Option<usize>) -> Result<usize> {
-        // TODO: improve Fat32::alloc_cluster()
-        // traversing the FAT linearly will become very slow
-        let mut new = self.cluster_begin;
-        loop {
-            if let FatEntry::Free = try!(self.read_fate(new)) {
-                break;
-            }
-            new += 1;
-            // TODO: handle full FS
-        }
-
-        // found a cluster, now zero it and set FAT
-        try!(self.write_cluster(new, &EMPTY_SECTOR));
-
-        if let Some(mut old) = old {
-            // extending existing cluster
-            // check if this is the end of the FAT entry chain
-            loop {
-                match try!(self.read_fate(old)) {
-                    FatEntry::End => { break } // at end of chain
-                    FatEntry::Cont(c) => { old = c as usize }, // continue chain
-                    _ => { // chain broken, unsure what to do here
-                        // TODO: read backup FAT
-                        return Err(Error::CorruptFAT)
+        let last = match old {
+            Some(mut old) => {
+                // extending existing cluster
+                // check if this is the end of the FAT entry chain
+                loop {
+                    match try!(self.read_fate(old)) {
+                        FatEntry::End => { break } // at end of chain
+                        FatEntry::Cont(c) => { old = c as usize }, // continue chain
+                        // chain broken, try again with a mirror
+                        _ if self.fall_back() => { },
+                        _ => return Err(Error::CorruptFAT),
                     }
                 }
-            }
+                Some(old)
+            },
+            None => None,
+        };
+
+        // keep the chain contiguous if the following cluster is free,
+        // otherwise begin at the next free hint
+        let hint = last.map_or(self.next_free, |c| c + 1);
+        try!(self.reload_used());
+        let new = match self.used.find_clear(hint) {
+            Some(new) => new,
+            None => return Err(Error::NoSpace),
+        };
+
+        // found a cluster, now zero it and set FAT
+        try!(self.fill_cluster(new, &[]));
+
+        if let Some(last) = last {
             // extend FAT chain
-            try!(self.write_fate(old, &FatEntry::Cont(new as u32)));
+            try!(self.write_fate(last, &FatEntry::Cont(new as u32)));
         }
         // ensure FAT chain ends
         try!(self.write_fate(new, &FatEntry::End));
 
+        self.next_free = if new + 1 == self.used.len() { 2 } else { new + 1 };
+        self.fsinfo_dirty = true;
+
         Ok(new)
     }
 
-    /// Finds a free directory listing entry
+    /// Make sure the chain beginning at `start` can hold `len` bytes
     ///
-    /// `cluster` should point to the beginning of the directory listing.
-    /// The resulting index is given relative to the beginning of the directory
-    /// listing (i.e. the index may be larger than an individual cluster will
-    /// hold, but `set_dire` etc will account for this)
-    fn alloc_dire(&mut self, mut cluster: usize) -> Result<usize> {
-        debug!("alloc_dire cluster=0x{:x}", cluster);
-
-        let mut iteration = 0;
-        loop {
-            for i in 0..16 {
-                match try!(self.get_dire(cluster, i)) {
-                    DirEntry::Free => {
-                        return Ok(iteration * 16 + i)
-                    },
-                    DirEntry::End => {
-                        try!(self.set_dire(cluster, i, &DirEntry::Free));
-                        if i < 15 {
-                            // cluster has enough room to put DirEntry::End inside
-                            try!(self.set_dire(cluster, i + 1, &DirEntry::End));
-                        } else {
-                            // cluster does not have enough room
-                            // allocate new cluster and place DirEntry::End at beginning
-                            let new = try!(self.alloc_cluster(Some(cluster)));
-                            try!(self.set_dire(new, 0, &DirEntry::End));
-                        }
-                        return Ok(iteration * 16 + i)
-                    }
-                    _ => { continue }
-                }
+    /// Lets an operation fail with `NoSpace` before it modifies anything,
+    /// rather than part of the way through.
+    fn check_space(&mut self, start: Option<usize>, len: usize) -> Result<()> {
+        let csize = self.cluster_size * 512;
+        let needed = max((len + csize - 1) / csize, 1);
+        let mut have = 0;
+        // an empty file may have no chain at all
+        let mut cluster = start.and_then(|c| if c == 0 { None } else { Some(c) });
+        while let Some(c) = cluster {
+            have += 1;
+            if have >= needed {
+                return Ok(())
             }
-            iteration += 1;
-            cluster = try!(self.next_cluster(cluster)).expect("Corrupt FAT");
+            cluster = try!(self.next_cluster(c));
         }
+        try!(self.reload_used());
+        if needed - have > self.free_count {
+            return Err(Error::NoSpace)
+        }
+        Ok(())
     }
 
-    fn get_dire(&self, mut cluster: usize, mut offset: usize) -> Result<DirEntry> {
-        while offset >= 16 {
-            // dir entry is not in this cluster
-            // so traverse the FAT until the appropriate cluster/offset pair is found
-            cluster = try!(self.next_cluster(cluster)).expect("Corrupt FAT");
-            offset -= 16;
+    /// End a FAT chain at `cluster`, freeing any clusters that followed it
+    fn free_after(&mut self, cluster: usize) -> Result<()> {
+        if let Some(next) = try!(self.next_cluster(cluster)) {
+            try!(self.write_fate(cluster, &FatEntry::End));
+            try!(self.free_chain(next));
         }
+        Ok(())
+    }
 
-        let sector = try!(self.read_cluster(cluster));
-        let entry = &sector[offset * 32 .. (offset + 1) * 32];
-        match entry[0] {
-            // TODO: harden against invalid filenames
-            // that may contain DirE signals such as these below
-            0x00 => { return Ok(DirEntry::End) } // end of directory
-            0xE5 => { return Ok(DirEntry::Free) } // skip unused entry
-            _ => { },
+    /// Write `buf` over the chain beginning at `fcluster`, extending or
+    /// shortening the chain to fit
+    fn write_chain(&mut self, mut fcluster: usize, buf: &[u8]) -> Result<()> {
+        let mut chunks = buf.chunks(self.cluster_size * 512).peekable(); // TODO generc over sector size
+        while let Some(chunk) = chunks.next() {
+            debug!("write_chain fcluster=0x{:x}", fcluster);
+            try!(self.fill_cluster(fcluster, chunk));
+            if chunks.peek().is_none() {
+                break
+            }
+            // find where to write next cluster
+            fcluster = match try!(self.next_cluster(fcluster)) {
+                // file had already allocated enough space in FAT chain
+                Some(c) => {
+                    debug!("write_chain reusing fcluster=0x{:x}", c);
+                    c
+                },
+                // need to extend file's FAT chain
+                None => try!(self.alloc_cluster(Some(fcluster))),
+            }
         }
 
-        let name = String::from_utf8_lossy(&entry[0..8]);
-        let ext = String::from_utf8_lossy(&entry[8..11]);
-
-        let attrib = entry[11];
-
-        let cluster_hi = (&entry[20..22]).read_u16::<LittleEndian>().unwrap() as usize;
-        let cluster_lo = (&entry[26..28]).read_u16::<LittleEndian>().unwrap() as usize;
-        let cluster = cluster_hi << 16 | cluster_lo;
+        // an overwritten file may have had a longer chain
+        self.free_after(fcluster)
+    }
 
-        if attrib & IS_SUBDIR > 0 {
-            // subdirectory
-            Ok(DirEntry::Dir {
-                name: name.into_owned(),
-                ext: ext.into_owned(),
-                start: cluster,
-            })
-        } else {
-            // file
-            let size = (&entry[28..32]).read_u32::<LittleEndian>().unwrap() as usize;
-            Ok(DirEntry::File {
-                name: name.into_owned(),
-                ext: ext.into_owned(),
-                start: cluster,
-                size: size,
-            })
+    /// The first cluster of a file, allocating one if it has none
+    ///
+    /// Other implementations give empty files cluster 0. The new cluster is
+    /// recorded in `dire`, which the caller must write back.
+    fn file_start(&mut self, dire: &mut DirEntry) -> Result<usize> {
+        match *dire {
+            DirEntry::File { ref mut start, .. } => {
+                if *start == 0 {
+                    *start = try!(self.alloc_cluster(None));
+                }
+                Ok(*start)
+            },
+            _ => Err(Error::CorruptDisk),
         }
     }
 
-    fn set_dire(&mut self, mut cluster: usize, mut offset: usize, dire: &DirEntry) -> Result<()> {
-        while offset >= 16 {
-            // dir entry is not in this cluster
-            // so traverse the FAT until the appropriate cluster/offset pair is found
-            cluster = try!(self.next_cluster(cluster)).expect("Corrupt FAT");
-            offset -= 16;
+    /// Free an entire FAT chain
+    ///
+    /// Every cluster from `cluster` to the end of its chain is marked free.
+    /// Empty files made elsewhere may start at cluster 0, with no chain.
+    fn free_chain(&mut self, mut cluster: usize) -> Result<()> {
+        if cluster == 0 {
+            return Ok(())
         }
+        let mut result = Ok(());
+        loop {
+            let next = match try!(self.read_fate(cluster)) {
+                // chain broken, try again with a mirror
+                FatEntry::Free | FatEntry::Bad | FatEntry::Reserved(_) if self.fall_back() => continue,
+                next => next,
+            };
+            debug!("free_chain cluster=0x{:x}", cluster);
+            try!(self.write_fate(cluster, &FatEntry::Free));
+            match next {
+                FatEntry::End => { break }
+                FatEntry::Cont(c) => { cluster = c as usize },
+                _ => { // chain broken
+                    result = Err(Error::CorruptFAT);
+                    break
+                }
+            }
+        }
+        self.fsinfo_dirty = true;
+        result
+    }
+
+    /// Checks whether a directory listing has any children
+    ///
+    /// `cluster` should point to the beginning of the directory listing.
+    fn is_dir_empty(&self, cluster: usize) -> Result<bool> {
+        for listed in DirIter::new(self, cluster) {
+            if !try!(listed).is_dot() {
+                return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    /// Finds free directory listing entries
+    ///
+    /// Looks for `count` consecutive free entries, growing the listing if
+    /// necessary. `cluster` should point to the beginning of the directory
+    /// listing. The resulting index is given relative to the beginning of the
+    /// directory listing (i.e. the index may be larger than an individual
+    /// cluster will hold, but `set_dire` etc will account for this)
+    fn alloc_dire(&mut self, cluster: usize, count: usize) -> Result<usize> {
+        debug!("alloc_dire cluster=0x{:x} count={}", cluster, count);
+
+        let mut first = 0; // beginning of the current run of free entries
+        let mut i = 0;
+        loop {
+            match try!(self.get_dire(cluster, i)) {
+                DirEntry::Free if i + 1 - first == count => { return Ok(first) },
+                DirEntry::Free => { },
+                // everything after the end of the listing is available
+                DirEntry::End => { break },
+                _ => { first = i + 1 },
+            }
+            i += 1;
+        }
+
+        // make sure the listing has room for the entries and a new DirEntry::End
+        let end = first + count;
+        if cluster == 0 {
+            // the fixed root directory can't grow, but it can be filled
+            // completely as its end also ends the listing
+            if end > self.rdir_entries {
+                return Err(Error::NoSpace)
+            }
+        } else {
+            let mut last = cluster;
+            let mut capacity = self.dires_per_cluster();
+            while let Some(c) = try!(self.next_cluster(last)) {
+                last = c;
+                capacity += self.dires_per_cluster();
+            }
+            while capacity <= end {
+                // new clusters are zeroed, so they're full of DirEntry::End
+                last = try!(self.alloc_cluster(Some(last)));
+                capacity += self.dires_per_cluster();
+            }
+        }
+
+        for j in i..end {
+            try!(self.set_dire(cluster, j, &DirEntry::Free));
+        }
+        if cluster != 0 || end < self.rdir_entries {
+            try!(self.set_dire(cluster, end, &DirEntry::End));
+        }
+
+        Ok(first)
+    }
+
+    /// Add an entry for `path` to a directory listing
+    ///
+    /// The short name of `dire` is derived from the file name of `path`. If
+    /// the name doesn't fit in 8.3 form, long name entries are placed before
+    /// the short entry. Returns the index of the short entry.
+    ///
+    /// If the entry can't be added, the cluster chain of `dire` is freed so
+    /// that callers don't leak it.
+    fn add_dire(&mut self, dcluster: usize, path: &Path, dire: DirEntry) -> Result<usize> {
+        // unwrap() should be safe, `dire` is a File or Dir
+        let start = dire.start().unwrap();
+        match self.insert_dire(dcluster, path, dire) {
+            Ok(direi) => Ok(direi),
+            Err(e) => {
+                try!(self.free_chain(start));
+                Err(e)
+            },
+        }
+    }
+
+    fn insert_dire(&mut self, dcluster: usize, path: &Path, mut dire: DirEntry) -> Result<usize> {
+        let fname = match path.file_name().and_then(|n| n.to_str()) {
+            Some(fname) if is_valid_name(fname) => fname,
+            _ => return Err(Error::InvalidPath),
+        };
+
+        // the short name must be unique within the directory
+        let taken = try!(DirIter::new(self, dcluster)
+                             .map(|r| r.map(|listed| listed.dire.short_name().unwrap()))
+                             .collect::<Result<Vec<[u8; 11]>>>());
+        let (stem, ext) = try!(short_name(fname, &taken));
+        dire.set_short_name(stem, ext);
+        let lfn = if is_short_name(fname) {
+            Vec::new()
+        } else {
+            // unwrap() should be safe, `dire` is a File or Dir
+            try!(long_name_dires(fname, lfn_checksum(&dire.short_name().unwrap())))
+        };
+
+        let first = try!(self.alloc_dire(dcluster, lfn.len() + 1));
+        for (i, piece) in lfn.iter().enumerate() {
+            try!(self.set_dire(dcluster, first + i, piece));
+        }
+        let direi = first + lfn.len();
+        try!(self.set_dire(dcluster, direi, &dire));
+
+        Ok(direi)
+    }
+
+    /// Find the sector holding entry `offset` of the listing at `cluster`
+    ///
+    /// Returns the LBA of the sector and the index of the entry within it.
+    fn locate_dire(&self, mut cluster: usize, mut offset: usize) -> Result<(usize, usize)> {
+        while offset >= self.dires_in(cluster) {
+            if cluster == 0 {
+                // past the end of the fixed root directory
+                return Err(Error::NoSpace)
+            }
+            // dir entry is not in this cluster
+            // so traverse the FAT until the appropriate cluster/offset pair is found
+            cluster = try!(self.expect_next(cluster));
+            offset -= self.dires_per_cluster();
+        }
+
+        let lba = match cluster {
+            0 => self.rdir_begin,
+            c => self.cluster_begin + (c - 2) * self.cluster_size,
+        };
+        Ok((lba + offset / 16, offset % 16))
+    }
+
+    fn get_dire(&self, cluster: usize, offset: usize) -> Result<DirEntry> {
+        use std::slice::bytes::copy_memory;
+
+        let (lba, offset) = try!(self.locate_dire(cluster, offset));
+        let sector = try!(self.disk.read_sector(lba));
+        let entry = &sector[offset * 32 .. (offset + 1) * 32];
+        match entry[0] {
+            // short names never begin with these, see `is_valid_name` and
+            // `set_dire` for how a leading 0xE5 is stored
+            0x00 => { return Ok(DirEntry::End) } // end of directory
+            0xE5 => { return Ok(DirEntry::Free) } // skip unused entry
+            _ => { },
+        }
+
+        if entry[11] & 0x3F == LFN_ATTRIB {
+            let mut chars = [0; 13];
+            for (i, &at) in LFN_OFFSETS.iter().enumerate() {
+                chars[i] = (&entry[at..at + 2]).read_u16::<LittleEndian>().unwrap();
+            }
+            return Ok(DirEntry::LongName {
+                ord: entry[0],
+                checksum: entry[13],
+                chars: chars,
+            })
+        }
+
+        let mut short = [0; 11];
+        copy_memory(&entry[0..11], &mut short);
+        if short[0] == 0x05 {
+            // a leading 0xE5 is stored as 0x05 so the entry isn't considered free
+            short[0] = 0xE5;
+        }
+        let name = String::from_utf8_lossy(&short[0..8]);
+        let ext = String::from_utf8_lossy(&short[8..11]);
+
+        let attrib = Attributes(entry[11]);
+
+        let cluster_hi = (&entry[20..22]).read_u16::<LittleEndian>().unwrap() as usize;
+        let cluster_lo = (&entry[26..28]).read_u16::<LittleEndian>().unwrap() as usize;
+        let cluster = cluster_hi << 16 | cluster_lo;
+
+        let read_u16 = |i: usize| (&entry[i..i + 2]).read_u16::<LittleEndian>().unwrap();
+        let times = Times {
+            // the creation time also has a count of 10ms units in byte 13
+            created: decode_datetime(read_u16(16), read_u16(14), entry[13]),
+            modified: decode_datetime(read_u16(24), read_u16(22), 0),
+            accessed: decode_datetime(read_u16(18), 0, 0),
+        };
+
+        if attrib.contains(DIRECTORY) {
+            // subdirectory
+            Ok(DirEntry::Dir {
+                name: name.into_owned(),
+                ext: ext.into_owned(),
+                start: cluster,
+                attrib: attrib,
+                times: times,
+            })
+        } else {
+            // file
+            let size = (&entry[28..32]).read_u32::<LittleEndian>().unwrap() as usize;
+            Ok(DirEntry::File {
+                name: name.into_owned(),
+                ext: ext.into_owned(),
+                start: cluster,
+                size: size,
+                attrib: attrib,
+                times: times,
+            })
+        }
+    }
 
+    fn set_dire(&mut self, cluster: usize, offset: usize, dire: &DirEntry) -> Result<()> {
         debug!("set_dire cluster=0x{:x} offset=0x{:x} dire={:?}", cluster, offset, dire);
-        let mut sector = try!(self.read_cluster(cluster)).clone();
+        let (lba, offset) = try!(self.locate_dire(cluster, offset));
+        let mut sector = try!(self.disk.read_sector(lba)).clone();
         {
             let entry = &mut sector[offset * 32 .. (offset + 1) * 32];
             match *dire {
@@ -402,26 +1072,46 @@ impl Fat32 {
                 DirEntry::Free => {
                     entry[0] = 0xE5;
                 },
+                DirEntry::LongName { ord, checksum, ref chars } => {
+                    use std::slice::bytes::copy_memory;
+
+                    copy_memory(&[0; 32], entry);
+                    entry[0] = ord;
+                    entry[11] = LFN_ATTRIB;
+                    entry[13] = checksum;
+                    for (&c, &at) in chars.iter().zip(LFN_OFFSETS.iter()) {
+                        (&mut entry[at..at + 2]).write_u16::<LittleEndian>(c).unwrap();
+                    }
+                },
                 DirEntry::Dir { .. }  | DirEntry::File { .. } => {
                     // Dir & File contain several shared fields
-                    use std::iter::repeat;
-
-                    let name = dire.name().unwrap_or("").bytes().chain(repeat(b' ')).take(8);
-                    let ext = dire.ext().unwrap_or("").bytes().chain(repeat(b' ')).take(3);
-                    let mut i = 0;
-                    for byte in name {
-                        entry[i] = byte;
-                        i += 1;
-                    }
-                    for byte in ext {
-                        entry[i] = byte;
-                        i += 1;
+                    use std::slice::bytes::copy_memory;
+
+                    // don't let anything from a previous entry linger
+                    copy_memory(&[0; 32], entry);
+                    copy_memory(&dire.short_name().unwrap(), &mut entry[0..11]);
+                    if entry[0] == 0xE5 {
+                        entry[0] = 0x05;
                     }
 
-                    if let DirEntry::Dir { .. } = *dire {
-                        entry[11] = IS_SUBDIR;
+                    entry[11] = dire.attrib().unwrap().bits();
+
+                    let times = dire.times().unwrap();
+                    if let Some(created) = times.created {
+                        let (date, time, fine) = encode_datetime(&created);
+                        entry[13] = fine;
+                        (&mut entry[14..16]).write_u16::<LittleEndian>(time).unwrap();
+                        (&mut entry[16..18]).write_u16::<LittleEndian>(date).unwrap();
+                    }
+                    if let Some(accessed) = times.accessed {
+                        let (date, _, _) = encode_datetime(&accessed);
+                        (&mut entry[18..20]).write_u16::<LittleEndian>(date).unwrap();
+                    }
+                    if let Some(modified) = times.modified {
+                        let (date, time, _) = encode_datetime(&modified);
+                        (&mut entry[22..24]).write_u16::<LittleEndian>(time).unwrap();
+                        (&mut entry[24..26]).write_u16::<LittleEndian>(date).unwrap();
                     }
-                    // TODO: handle file attributes
 
                     let cluster = dire.start().unwrap();
                     let cluster_hi = (cluster & 0xFFFF0000) >> 16;
@@ -437,11 +1127,23 @@ impl Fat32 {
                 }
             }
         }
-        try!(self.write_cluster(cluster, &sector));
+        try!(self.disk.write_sector(lba, &sector));
 
         Ok(())
     }
 
+    /// Find the directory entry of a path
+    ///
+    /// Returns the beginning of the parent directory listing along with
+    /// the entry found within it.
+    fn find_dire(&self, path: &Path) -> Result<(usize, Listed)> {
+        let dcluster = try!(self.find_parent_dir(path));
+        match try!(self.find_listed(dcluster, path)) {
+            Some(listed) => Ok((dcluster, listed)),
+            None => Err(Error::Nonexistent(path.to_owned())),
+        }
+    }
+
     fn find_dir(&self, path: &Path) -> Result<usize> {
         let mut cluster = self.rdir_cluster;
         let mut iter = path.iter();
@@ -452,16 +1154,39 @@ impl Fat32 {
                 None => { break },
             };
             debug!("find_dir item={:?}", item);
-            match try!(self.find_dire_cluster(cluster, item.as_ref())) {
-                Some(c) => { cluster = c },
-                None => {
+            if Path::new(item).has_root() {
+                // absolute paths begin at the root directory anyway
+                continue
+            }
+            if item == "." || (item == ".." && cluster == self.rdir_cluster) {
+                // the root directory has no dot entries, it is its own parent
+                continue
+            }
+
+            let found = if item == ".." {
+                // `..` is always the second entry of a subdirectory
+                Some(try!(self.get_dire(cluster, 1)))
+            } else {
+                match try!(self.find_dire_index(cluster, item.as_ref())) {
+                    Some(i) => Some(try!(self.get_dire(cluster, i))),
+                    None => None,
+                }
+            };
+            match found {
+                // a `..` entry pointing to cluster 0 means the root directory
+                Some(DirEntry::Dir { start: 0, .. }) => { cluster = self.rdir_cluster },
+                Some(DirEntry::Dir { start, .. }) => { cluster = start },
+                found => {
                     let count = iter.count();
                     let mut epath = path;
                     for _ in 0..count {
                         // unwrap() should be fine here, see Path::parent() docs
                         epath = epath.parent().unwrap();
                     }
-                    return Err(Error::Nonexistent(epath.to_owned()))
+                    return Err(match found {
+                        Some(_) => Error::NotADirectory(epath.to_owned()),
+                        None => Error::Nonexistent(epath.to_owned()),
+                    })
                 }
             }
         }
@@ -477,194 +1202,2693 @@ impl Fat32 {
 }
 
 
+/// A file or directory found in a directory listing
+#[derive(Debug)]
+struct Listed {
+    index: usize, // index of the short entry within the listing
+    first: usize, // index of the first long name entry, `index` if there are none
+    dire: DirEntry, // always a DirEntry::File or DirEntry::Dir
+    long_name: Option<String>,
+}
+impl Listed {
+    /// Whether this is the `.` or `..` entry of a subdirectory
+    fn is_dot(&self) -> bool {
+        match self.dire.name() {
+            Some(".       ") | Some("..      ") => self.long_name.is_none(),
+            _ => false,
+        }
+    }
+    /// The name of the entry, preferring the long name if there is one
+    fn file_name(&self) -> String {
+        match self.long_name {
+            Some(ref name) => name.clone(),
+            // unwrap() should be safe, `dire` is a File or Dir
+            None => self.dire.short_file_name().unwrap(),
+        }
+    }
+}
+
+/// Iterator over the entries of a directory listing
+///
+/// Yields each file and directory along with its position relative to the
+/// beginning of the listing. Long name entries are joined and checked
+/// against the short entry that follows them; orphaned pieces are ignored.
+/// `DirEntry::Free` entries are skipped and iteration stops at
+/// `DirEntry::End`, following the FAT chain as necessary.
+struct DirIter<'a> {
+    fs: &'a Fat32,
+    cluster: usize, // current cluster of the listing
+    offset: usize, // index of the next entry within `cluster`
+    index: usize, // index of the next entry within the whole listing
+    done: bool,
+    lfn: Vec<[u16; 13]>, // long name pieces seen so far, last piece first
+    lfn_first: usize, // index of the first piece
+    lfn_checksum: u8,
+    lfn_next: u8, // `ord` of the next piece expected, 0 once the name is complete
+}
+
+impl<'a> DirIter<'a> {
+    fn new(fs: &'a Fat32, cluster: usize) -> DirIter<'a> {
+        DirIter {
+            fs: fs,
+            cluster: cluster,
+            offset: 0,
+            index: 0,
+            done: false,
+            lfn: Vec::new(),
+            lfn_first: 0,
+            lfn_checksum: 0,
+            lfn_next: 0,
+        }
+    }
+
+    /// Collect a piece of a long name
+    fn push_lfn(&mut self, index: usize, ord: u8, checksum: u8, chars: [u16; 13]) {
+        let n = ord & !LFN_LAST;
+        if ord & LFN_LAST != 0 && n > 0 {
+            // pieces are stored last first, so this begins a new name
+            self.lfn.clear();
+            self.lfn.push(chars);
+            self.lfn_first = index;
+            self.lfn_checksum = checksum;
+            self.lfn_next = n - 1;
+        } else if self.lfn.len() > 0 && n == self.lfn_next && n > 0 && checksum == self.lfn_checksum {
+            self.lfn.push(chars);
+            self.lfn_next -= 1;
+        } else {
+            // orphaned or out of order piece
+            self.lfn.clear();
+        }
+    }
+}
+
+impl<'a> Iterator for DirIter<'a> {
+    type Item = Result<Listed>;
+
+    fn next(&mut self) -> Option<Result<Listed>> {
+        while !self.done {
+            if self.offset == self.fs.dires_in(self.cluster) {
+                if self.cluster == 0 {
+                    // a full fixed root directory has no DirEntry::End
+                    self.done = true;
+                    break
+                }
+                // end of this cluster, move on to the next one in the listing
+                // the listing can't run out before DirEntry::End
+                match self.fs.expect_next(self.cluster) {
+                    Ok(c) => {
+                        self.cluster = c;
+                        self.offset = 0;
+                    },
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e))
+                    },
+                }
+            }
+
+            let dire = match self.fs.get_dire(self.cluster, self.offset) {
+                Ok(dire) => dire,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e))
+                },
+            };
+            let index = self.index;
+            self.offset += 1;
+            self.index += 1;
+
+            match dire {
+                DirEntry::End => { self.done = true },
+                DirEntry::Free => { self.lfn.clear() },
+                DirEntry::LongName { ord, checksum, chars } => {
+                    self.push_lfn(index, ord, checksum, chars);
+                },
+                // the volume label isn't a child of the directory
+                ref dire if dire.attrib().unwrap().contains(VOLUME_LABEL) => { self.lfn.clear() },
+                dire => {
+                    let mut listed = Listed {
+                        index: index,
+                        first: index,
+                        dire: dire,
+                        long_name: None,
+                    };
+
+                    // unwrap() should be safe, `dire` is a File or Dir
+                    let checksum = lfn_checksum(&listed.dire.short_name().unwrap());
+                    let complete = self.lfn.len() > 0 && self.lfn_next == 0;
+                    if complete && checksum == self.lfn_checksum {
+                        let units = self.lfn.iter().rev()
+                                        .flat_map(|piece| piece.iter())
+                                        .cloned()
+                                        .take_while(|&c| c != 0x0000)
+                                        .collect::<Vec<u16>>();
+                        listed.long_name = Some(String::from_utf16_lossy(&units));
+                        listed.first = self.lfn_first;
+                    }
+                    self.lfn.clear();
+
+                    return Some(Ok(listed))
+                },
+            }
+        }
+        None
+    }
+}
+
 impl FileSystem for Fat32 {
+    fn read_dir<'a>(&'a mut self, path: &Path) -> Result<Box<Iterator<Item=Result<Entry>> + 'a>> {
+        let cluster = try!(self.find_dir(path));
+        let iter = DirIter::new(self, cluster).filter(|r| {
+            r.as_ref().map(|listed| !listed.is_dot()).unwrap_or(true)
+        }).map(|r| r.map(|listed| {
+            let name = listed.file_name();
+            let dire = listed.dire;
+            Entry {
+                name: name,
+                kind: match dire {
+                    DirEntry::Dir { .. } => FileType::Directory,
+                    _ => FileType::File,
+                },
+                size: dire.size().unwrap_or(0),
+                // unwrap() should be safe, DirIter only yields Files and Dirs
+                start: dire.start().unwrap(),
+            }
+        }));
+
+        Ok(Box::new(iter))
+    }
+
+    fn open<'a>(&'a mut self, path: &Path, mode: Mode) -> Result<Box<Handle + 'a>> {
+        let dcluster = try!(self.find_parent_dir(path));
+        let (direi, start, size) = match try!(self.find_dire_index(dcluster, path)) {
+            Some(i) => {
+                match try!(self.get_dire(dcluster, i)) {
+                    DirEntry::File { start, size, .. } => (i, start, size),
+                    _ => return Err(Error::NotAFile(path.to_owned())),
+                }
+            },
+            None if mode == Mode::Write => {
+                let start = try!(self.alloc_cluster(None));
+                let dire = DirEntry::File {
+                    name: String::new(),
+                    ext: String::new(),
+                    start: start,
+                    size: 0,
+                    attrib: ARCHIVE,
+                    times: Times::new(self.clock.now()),
+                };
+                (try!(self.add_dire(dcluster, path, dire)), start, 0)
+            },
+            None => return Err(Error::Nonexistent(path.to_owned())),
+        };
+
+        let mut file = FatFile {
+            fs: self,
+            mode: mode,
+            dcluster: dcluster,
+            direi: direi,
+            start: start,
+            cluster: start,
+            index: 0,
+            pos: 0,
+            size: size,
+            dirty: false,
+        };
+
+        if mode == Mode::Write && size > 0 {
+            // truncate, but keep the first cluster so the dir entry stays valid
+            try!(file.fs.free_after(start));
+            file.size = 0;
+            file.dirty = true;
+        }
+
+        Ok(Box::new(file))
+    }
+
     fn make_dir(&mut self, path: &Path) -> Result<()> {
-        let mut cluster = try!(self.find_parent_dir(path));
+        let cluster = try!(self.find_parent_dir(path));
+        if try!(self.find_dire_index(cluster, path)).is_some() {
+            return Err(Error::AlreadyExists(path.to_owned()))
+        }
 
         // alloc a cluster for the directory listing
         // and prepare the list
         let new_c = try!(self.alloc_cluster(None));
-        try!(self.set_dire(new_c, 0, &DirEntry::End));
+        let times = Times::new(self.clock.now());
+        let dot = |name: &str, start: usize| DirEntry::Dir {
+            name: name.to_owned(),
+            ext: String::new(),
+            start: start,
+            attrib: DIRECTORY,
+            times: times,
+        };
+        // `..` refers to the root directory as cluster 0
+        let parent = if cluster == self.rdir_cluster { 0 } else { cluster };
+        try!(self.set_dire(new_c, 0, &dot(".", new_c)));
+        try!(self.set_dire(new_c, 1, &dot("..", parent)));
+        try!(self.set_dire(new_c, 2, &DirEntry::End));
+
+        // now add an entry to the parent directory's listing
+        let direi = try!(self.add_dire(cluster, path, dot("", new_c)));
+        debug!("make_dir cluster=0x{:x} direi=0x{:x}", cluster, direi);
 
-        // now find an entry in the parent directory's listing
-        let direi = try!(self.alloc_dire(cluster));
+        self.flush_fsinfo()
+    }
 
-        let name = try!(normalize_stem(path)).to_owned();
-        let ext = try!(normalize_ext(path)).to_owned();
-        let dire = DirEntry::Dir {
-            name: name,
-            ext: ext,
-            start: new_c,
-        };
+    fn metadata(&mut self, path: &Path) -> Result<Metadata> {
+        if path.file_name().is_none() {
+            // the root directory has no entry of its own
+            try!(self.find_dir(path));
+            return Ok(Metadata {
+                kind: FileType::Directory,
+                size: 0,
+                attributes: DIRECTORY,
+                created: None,
+                modified: None,
+                accessed: None,
+            })
+        }
 
-        debug!("make_dir cluster=0x{:x} direi=0x{:x}", cluster, direi);
-        try!(self.set_dire(cluster, direi, &dire));
+        let dire = try!(self.find_dire(path)).1.dire;
+        // unwrap()s should be safe, find_dire() only returns Files and Dirs
+        let times = dire.times().unwrap();
+        Ok(Metadata {
+            kind: match dire {
+                DirEntry::Dir { .. } => FileType::Directory,
+                _ => FileType::File,
+            },
+            size: dire.size().unwrap_or(0),
+            attributes: dire.attrib().unwrap(),
+            created: times.created,
+            modified: times.modified,
+            accessed: times.accessed,
+        })
+    }
 
-        Ok(())
+    fn attributes(&mut self, path: &Path) -> Result<Attributes> {
+        self.metadata(path).map(|meta| meta.attributes)
+    }
+
+    fn set_attributes(&mut self, path: &Path, attrib: Attributes) -> Result<()> {
+        if path.file_name().is_none() {
+            // the root directory has no entry to store attributes in
+            return Err(Error::InvalidPath)
+        }
+
+        let (dcluster, Listed { index: direi, mut dire, .. }) = try!(self.find_dire(path));
+        match dire {
+            DirEntry::Dir { attrib: ref mut a, .. } | DirEntry::File { attrib: ref mut a, .. } => {
+                // whether an entry is a directory or a volume label can't be changed
+                let fixed = DIRECTORY | VOLUME_LABEL;
+                *a = (*a & fixed) | (attrib & !fixed);
+            },
+            _ => unreachable!(), // find_dire() only returns Files and Dirs
+        }
+        self.set_dire(dcluster, direi, &dire)
     }
 
     fn delete(&mut self, path: &Path) -> Result<()> {
-        Ok(())
+        let (dcluster, Listed { index: direi, first, dire, .. }) = try!(self.find_dire(path));
+        if let DirEntry::Dir { start, .. } = dire {
+            if !try!(self.is_dir_empty(start)) {
+                return Err(Error::DirectoryNotEmpty(path.to_owned()))
+            }
+        }
+
+        debug!("delete cluster=0x{:x} direi=0x{:x}", dcluster, direi);
+        // free the long name entries along with the short entry
+        for i in first..direi + 1 {
+            try!(self.set_dire(dcluster, i, &DirEntry::Free));
+        }
+        // unwrap() should be safe, see Fat32::find_dire_index()
+        try!(self.free_chain(dire.start().unwrap()));
+
+        self.flush_fsinfo()
     }
-    fn write_file(&mut self, path: &Path, buf: &[u8]) -> Result<()> {
-        use std::iter::repeat;
+    fn rename(&mut self, from: &Path, to: &Path, replace: bool) -> Result<()> {
+        let (src_dcluster, listed) = try!(self.find_dire(from));
+        let dst_dcluster = try!(self.find_parent_dir(to));
+        // unwrap() should be safe, `dire` is a File or Dir
+        let start = listed.dire.start().unwrap();
+        let is_dir = match listed.dire { DirEntry::Dir { .. } => true, _ => false };
+
+        if is_dir {
+            // a directory can't be moved inside of itself
+            let mut cluster = dst_dcluster;
+            while cluster != self.rdir_cluster {
+                if cluster == start {
+                    return Err(Error::InvalidPath)
+                }
+                cluster = match try!(self.get_dire(cluster, 1)).start() {
+                    Some(0) | None => self.rdir_cluster,
+                    Some(parent) => parent,
+                };
+            }
+        }
 
+        let target = match try!(self.find_listed(dst_dcluster, to)) {
+            // only changing the case of the name
+            Some(ref target) if dst_dcluster == src_dcluster && target.index == listed.index => None,
+            Some(target) => {
+                let target_is_dir = match target.dire { DirEntry::Dir { .. } => true, _ => false };
+                if !replace {
+                    return Err(Error::AlreadyExists(to.to_owned()))
+                } else if is_dir && !target_is_dir {
+                    return Err(Error::NotADirectory(to.to_owned()))
+                } else if !is_dir && target_is_dir {
+                    return Err(Error::NotAFile(to.to_owned()))
+                } else if target_is_dir && !try!(self.is_dir_empty(target.dire.start().unwrap())) {
+                    return Err(Error::DirectoryNotEmpty(to.to_owned()))
+                }
+                Some(target)
+            },
+            None => None,
+        };
+
+        // free the old entries first so a new short name doesn't collide
+        // with the old one, but keep them in case the new ones don't fit.
+        // The target's entries go too, though its clusters are only freed
+        // once the new entries are in place.
+        let mut runs = vec![(src_dcluster, listed.first, listed.index)];
+        if let Some(ref target) = target {
+            runs.push((dst_dcluster, target.first, target.index));
+        }
+        let mut freed = vec![];
+        for (dcluster, first, last) in runs {
+            for i in first..last + 1 {
+                freed.push((dcluster, i, try!(self.get_dire(dcluster, i))));
+                try!(self.set_dire(dcluster, i, &DirEntry::Free));
+            }
+        }
+        if let Err(e) = self.insert_dire(dst_dcluster, to, listed.dire) {
+            for &(dcluster, i, ref dire) in &freed {
+                try!(self.set_dire(dcluster, i, dire));
+            }
+            return Err(e)
+        }
+        if let Some(target) = target {
+            // unwrap() should be safe, `target` is a File or Dir
+            try!(self.free_chain(target.dire.start().unwrap()));
+        }
+
+        if is_dir && dst_dcluster != src_dcluster {
+            let mut dotdot = try!(self.get_dire(start, 1));
+            if let DirEntry::Dir { start: ref mut parent, .. } = dotdot {
+                // `..` refers to the root directory as cluster 0
+                *parent = if dst_dcluster == self.rdir_cluster { 0 } else { dst_dcluster };
+            }
+            try!(self.set_dire(start, 1, &dotdot));
+        }
+
+        self.flush_fsinfo()
+    }
+    fn write_file(&mut self, path: &Path, buf: &[u8]) -> Result<()> {
         // find the directory and where the file exists within it
         let dcluster = try!(self.find_parent_dir(path));
-        let (direi, mut fcluster) = match try!(self.find_dire_index(dcluster, path)) {
+        let now = self.clock.now();
+        try!(match try!(self.find_dire_index(dcluster, path)) {
             // File exists, overwriting
             Some(i) => {
-                // unwrap() should be safe, see Fat32::find_dire_cluster()
-                (i, try!(self.get_dire(dcluster, i)).start().unwrap())
+                let mut dire = try!(self.get_dire(dcluster, i));
+                let start = match dire {
+                    DirEntry::File { start, ref mut size, ref mut attrib, ref mut times, .. } => {
+                        *size = buf.len();
+                        // keep any other attributes, but flag the file as modified
+                        attrib.insert(ARCHIVE);
+                        times.touch(now);
+                        start
+                    },
+                    _ => return Err(Error::NotAFile(path.to_owned())),
+                };
+                // fail before any of the old contents are overwritten
+                try!(self.check_space(Some(start), buf.len()));
+                let start = try!(self.file_start(&mut dire));
+                try!(self.write_chain(start, buf));
+                self.set_dire(dcluster, i, &dire)
             },
             // File does not exist, alloc dire / cluster
             None => {
-                (try!(self.alloc_dire(dcluster)), try!(self.alloc_cluster(None)))
+                try!(self.check_space(None, buf.len()));
+                let start = try!(self.alloc_cluster(None));
+                let dire = DirEntry::File {
+                    name: String::new(),
+                    ext: String::new(),
+                    start: start,
+                    size: buf.len(),
+                    attrib: ARCHIVE,
+                    times: Times::new(now),
+                };
+                try!(self.add_dire(dcluster, path, dire));
+                match self.write_chain(start, buf) {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        // growing the directory may have used up the space,
+                        // don't leave a half written file behind
+                        try!(self.delete(path));
+                        Err(e)
+                    },
+                }
             }
+        });
+        self.flush_fsinfo()
+    }
+    fn truncate(&mut self, path: &Path, len: usize) -> Result<()> {
+        let (dcluster, listed) = try!(self.find_dire(path));
+        let (start, size) = match listed.dire {
+            DirEntry::File { start, size, .. } => (start, size),
+            _ => return Err(Error::NotAFile(path.to_owned())),
         };
+        try!(self.check_space(Some(start), len));
+        let mut dire = listed.dire;
+        let start = try!(self.file_start(&mut dire));
+
+        // keep the first cluster even if the file becomes empty
+        let csize = self.cluster_size * 512;
+        let keep = max((len + csize - 1) / csize, 1);
+        // anything in the kept clusters past the old or new end must read as zero
+        let from = min(len, size);
+
+        let mut cluster = start;
+        for i in 0..keep {
+            if i > 0 {
+                cluster = match try!(self.next_cluster(cluster)) {
+                    Some(c) => c,
+                    None => {
+                        // new clusters are zeroed already
+                        cluster = try!(self.alloc_cluster(Some(cluster)));
+                        continue
+                    },
+                };
+            }
+            for j in 0..self.cluster_size {
+                let begin = i * csize + j * 512;
+                if begin + 512 <= from {
+                    continue
+                }
+                let mut sector = try!(self.read_cluster(cluster, j)).clone();
+                for b in &mut sector[from.saturating_sub(begin)..] {
+                    *b = 0;
+                }
+                try!(self.write_cluster(cluster, j, &sector));
+            }
+        }
+        try!(self.free_after(cluster));
 
-        // update dir entry
-        // TODO sanitize paths
-        let fname = try!(normalize_stem(path));
-        let fext = try!(normalize_ext(path));
-        let dire = DirEntry::File {
-            name: fname.chars().chain(repeat(' ')).take(8).collect(),
-            ext: fext.chars().chain(repeat(' ')).take(3).collect(),
-            start: fcluster,
-            size: buf.len(),
+        if let DirEntry::File { ref mut size, ref mut attrib, ref mut times, .. } = dire {
+            *size = len;
+            attrib.insert(ARCHIVE);
+            times.touch(self.clock.now());
+        }
+        try!(self.set_dire(dcluster, listed.index, &dire));
+        self.flush_fsinfo()
+    }
+    fn write_at(&mut self, path: &Path, offset: usize, buf: &[u8]) -> Result<()> {
+        use std::slice::bytes::copy_memory;
+
+        let (start, size) = match try!(self.find_dire(path)).1.dire {
+            DirEntry::File { start, size, .. } => (start, size),
+            _ => return Err(Error::NotAFile(path.to_owned())),
         };
-        try!(self.set_dire(dcluster, direi, &dire));
+        if buf.len() == 0 {
+            return Ok(())
+        }
+        try!(self.check_space(Some(start), max(size, offset + buf.len())));
+        if offset > size {
+            // zero fills the gap, extending the chain up to the offset
+            try!(self.truncate(path, offset));
+        }
 
-        for chunk in buf.chunks(self.cluster_size * 512) { // TODO generc over sector size
-            debug!("write_file fcluster=0x{:x}", fcluster);
-            try!(self.write_cluster(fcluster, chunk));
-            // find where to write next cluster
-            fcluster = match try!(self.next_cluster(fcluster)) {
-                // file had already allocated enough space in FAT chain
-                Some(c) => {
-                    debug!("write_file reusing fcluster=0x{:x}", c);
-                    c
-                },
-                // need to extend file's FAT chain
-                None => try!(self.alloc_cluster(Some(fcluster))),
+        let (dcluster, listed) = try!(self.find_dire(path));
+        let mut dire = listed.dire;
+        let csize = self.cluster_size * 512;
+        let mut cluster = try!(self.file_start(&mut dire));
+        let mut index = 0; // position of `cluster` within the chain
+        let mut pos = offset;
+        let mut data = buf;
+        while data.len() > 0 {
+            while index < pos / csize {
+                cluster = match try!(self.next_cluster(cluster)) {
+                    Some(c) => c,
+                    // writing past the end of the chain
+                    None => try!(self.alloc_cluster(Some(cluster))),
+                };
+                index += 1;
             }
-        }
 
-        // TODO: free any excess space in FAT chain
-        // the logic is a little difficult at 2AM and boring, so do later
+            // only write up to the end of the current sector
+            let (i, offset) = ((pos % csize) / 512, pos % 512);
+            let len = min(data.len(), 512 - offset);
+            let mut sector = if len == 512 {
+                EMPTY_SECTOR
+            } else {
+                try!(self.read_cluster(cluster, i)).clone()
+            };
+            copy_memory(&data[..len], &mut sector[offset..offset + len]);
+            try!(self.write_cluster(cluster, i, &sector));
+
+            pos += len;
+            data = &data[len..];
+        }
 
-        Ok(())
+        if let DirEntry::File { ref mut size, ref mut attrib, ref mut times, .. } = dire {
+            *size = max(*size, pos);
+            attrib.insert(ARCHIVE);
+            times.touch(self.clock.now());
+        }
+        try!(self.set_dire(dcluster, listed.index, &dire));
+        self.flush_fsinfo()
     }
-    fn read_file(&mut self, path: &Path, buf: &mut [u8]) -> Result<()> {
-        Ok(())
+    fn append(&mut self, path: &Path, buf: &[u8]) -> Result<()> {
+        let size = match try!(self.find_dire(path)).1.dire {
+            DirEntry::File { size, .. } => size,
+            _ => return Err(Error::NotAFile(path.to_owned())),
+        };
+        self.write_at(path, size, buf)
     }
-}
+    fn read_file(&mut self, path: &Path, buf: &mut [u8]) -> Result<usize> {
+        use std::slice::bytes::copy_memory;
+
+        let (mut fcluster, size) = match try!(self.find_dire(path)).1.dire {
+            DirEntry::File { start, size, .. } => (start, size),
+            _ => return Err(Error::NotAFile(path.to_owned())),
+        };
 
-fn normalize_stem(path: &Path) -> Result<&str> {
-    match path.file_stem().and_then(|s| s.to_str()) {
-        Some(s) => Ok(s),
-        None => {
-            // This covers the case where file_stem() is None
-            // and when the stem cannot be converted to a utf8 &str
-            Err(Error::InvalidPath)
+        if buf.len() < size {
+            return Err(Error::BufferTooSmall(size))
         }
-    }
-}
-fn normalize_ext(path: &Path) -> Result<&str> {
-    match path.extension() {
-        Some(s) => {
-            match s.to_str() {
-                Some(s) => Ok(s),
-                None => {
-                    Err(Error::InvalidPath)
-                }
+
+        let mut chunks = buf[..size].chunks_mut(self.cluster_size * 512).peekable();
+        while let Some(chunk) = chunks.next() {
+            debug!("read_file fcluster=0x{:x}", fcluster);
+            for (i, part) in chunk.chunks_mut(512).enumerate() {
+                let len = part.len();
+                copy_memory(&try!(self.read_cluster(fcluster, i))[..len], part);
+            }
+
+            if chunks.peek().is_none() {
+                break
             }
+            // chain can't be shorter than the size in the dir entry
+            fcluster = try!(self.expect_next(fcluster));
         }
-        None => Ok(""),
+
+        Ok(size)
     }
 }
 
-/// Format drive as a FAT32 filesystem
+/// An open file on a `Fat32` filesystem
 ///
-/// This will overwrite the first sector, the reserved sectors,
-/// and the space used by the FATs. Everything after will be left intact
-/// until the FS is mounted and written to.
-pub fn format<T: Disk>(disk: &mut T) -> Result<()> {
-    // TODO support clusters > 1 sector
-    const FATS: usize = 2;
-    const RESERVED: usize = 32;
-    const SSIZE: usize = 512; // sector size (bytes)
-    const CSIZE: usize = 1; // cluster size (sectors)
-
-    let dsize = disk.info().size;
-    debug!("format dsize={}", dsize);
-    let (fsize, csize) = calc_sizes(dsize, FATS, RESERVED);
+/// The current cluster is cached so sequential reads and writes don't walk
+/// the FAT chain from the start each time. The dir entry's size is updated
+/// on `flush()` or when the handle is dropped.
+pub struct FatFile<'a> {
+    fs: &'a mut Fat32,
+    mode: Mode,
+    dcluster: usize, // beginning of the parent directory listing
+    direi: usize, // index of the file's dir entry within the listing
+    start: usize, // first cluster of the file
+    cluster: usize, // current cluster
+    index: usize, // position of `cluster` within the chain
+    pos: usize, // current offset in bytes
+    size: usize,
+    dirty: bool, // whether the file was written since the dir entry was updated
+}
+
+impl<'a> FatFile<'a> {
+    /// Move `cluster` to the `index`th cluster of the file
+    ///
+    /// If `extend` is set, the FAT chain will be grown as necessary.
+    fn seek_cluster(&mut self, index: usize, extend: bool) -> Result<()> {
+        if self.start == 0 {
+            // an empty file with no clusters yet
+            if !extend {
+                return Err(Error::CorruptDisk)
+            }
+            let mut dire = try!(self.fs.get_dire(self.dcluster, self.direi));
+            self.start = try!(self.fs.file_start(&mut dire));
+            try!(self.fs.set_dire(self.dcluster, self.direi, &dire));
+            self.cluster = self.start;
+            self.index = 0;
+        }
+        if index < self.index {
+            // FAT chains can only be followed forwards
+            self.cluster = self.start;
+            self.index = 0;
+        }
+        while self.index < index {
+            self.cluster = match try!(self.fs.next_cluster(self.cluster)) {
+                Some(c) => c,
+                None if extend => try!(self.fs.alloc_cluster(Some(self.cluster))),
+                // chain can't be shorter than the size in the dir entry
+                None => try!(self.fs.expect_next(self.cluster)),
+            };
+            self.index += 1;
+        }
+        Ok(())
+    }
+
+    /// Write as much of `buf` as fits in the sector at the current position
+    fn write_sector(&mut self, buf: &[u8]) -> io::Result<usize> {
+        use std::slice::bytes::copy_memory;
+
+        let csize = self.fs.cluster_size * 512;
+        try!(self.seek_cluster(self.pos / csize, true));
+
+        // only write up to the end of the current sector
+        let (i, offset) = ((self.pos % csize) / 512, self.pos % 512);
+        let len = min(buf.len(), 512 - offset);
+        let mut sector = try!(self.fs.read_cluster(self.cluster, i)).clone();
+        copy_memory(&buf[..len], &mut sector[offset..offset + len]);
+        try!(self.fs.write_cluster(self.cluster, i, &sector));
+
+        self.pos += len;
+        if self.pos > self.size {
+            self.size = self.pos;
+        }
+        self.dirty = true;
+        Ok(len)
+    }
+}
+
+impl<'a> Read for FatFile<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::slice::bytes::copy_memory;
+
+        if self.mode == Mode::Write {
+            return Err(io::Error::new(io::ErrorKind::Other, "file not opened for reading"))
+        }
+        if self.pos >= self.size || buf.len() == 0 {
+            return Ok(0)
+        }
+
+        let csize = self.fs.cluster_size * 512;
+        try!(self.seek_cluster(self.pos / csize, false));
+
+        // only read up to the end of the current sector
+        let (i, offset) = ((self.pos % csize) / 512, self.pos % 512);
+        let len = min(buf.len(), min(512 - offset, self.size - self.pos));
+        let sector = try!(self.fs.read_cluster(self.cluster, i));
+        copy_memory(&sector[offset..offset + len], &mut buf[..len]);
+
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl<'a> Write for FatFile<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.mode == Mode::Read {
+            return Err(io::Error::new(io::ErrorKind::Other, "file not opened for writing"))
+        }
+        if buf.len() == 0 {
+            return Ok(0)
+        }
+
+        if self.pos > self.size {
+            // the clusters may still hold whatever was there before the
+            // file was truncated, so the gap must be zeroed explicitly
+            let end = self.pos;
+            self.pos = self.size;
+            while self.pos < end {
+                let len = min(end - self.pos, 512);
+                try!(self.write_sector(&EMPTY_SECTOR[..len]));
+            }
+        }
+        self.write_sector(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // opening the file may have allocated its first cluster
+        try!(self.fs.flush_fsinfo());
+        if !self.dirty {
+            return Ok(())
+        }
+
+        let dire = match try!(self.fs.get_dire(self.dcluster, self.direi)) {
+            DirEntry::File { name, ext, start, attrib, mut times, .. } => {
+                times.touch(self.fs.clock.now());
+                DirEntry::File {
+                    name: name,
+                    ext: ext,
+                    start: start,
+                    size: self.size,
+                    attrib: attrib | ARCHIVE,
+                    times: times,
+                }
+            },
+            _ => return Err(Error::CorruptDisk.into()),
+        };
+        try!(self.fs.set_dire(self.dcluster, self.direi, &dire));
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+impl<'a> Seek for FatFile<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => self.size as i64 + n,
+            SeekFrom::Current(n) => self.pos as i64 + n,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"))
+        }
+
+        self.pos = pos as usize;
+        Ok(self.pos as u64)
+    }
+}
+
+impl<'a> Handle for FatFile<'a> { }
+
+impl<'a> Drop for FatFile<'a> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Decode a FAT date and time
+///
+/// `fine` counts 10ms units, only the whole seconds are kept. Returns `None`
+/// for a zeroed or otherwise invalid date. exFAT timestamps use the same
+/// encoding.
+pub fn decode_datetime(date: u16, time: u16, fine: u8) -> Option<DateTime> {
+    let day = (date & 0x1F) as u8;
+    let month = ((date >> 5) & 0x0F) as u8;
+    if day == 0 || month == 0 || month > 12 {
+        return None
+    }
+
+    Some(DateTime {
+        year: 1980 + (date >> 9),
+        month: month,
+        day: day,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3F) as u8,
+        second: ((time & 0x1F) * 2) as u8 + fine / 100,
+    })
+}
+
+/// Encode a FAT date and time
+///
+/// Returns the date, the time in 2 second units, and the remainder in
+/// 10ms units for the creation timestamp.
+pub fn encode_datetime(dt: &DateTime) -> (u16, u16, u8) {
+    let year = if dt.year < 1980 { 0 } else { dt.year - 1980 };
+    let date = year << 9 | (dt.month as u16) << 5 | dt.day as u16;
+    let time = (dt.hour as u16) << 11 | (dt.minute as u16) << 5 | (dt.second as u16) / 2;
+    (date, time, (dt.second % 2) * 100)
+}
+
+/// Split a file name into its stem and extension
+///
+/// The extension is whatever follows the last `.`, if any.
+fn split_name(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    }
+}
+
+/// Compare two file names, ignoring case
+fn names_equal(a: &str, b: &str) -> bool {
+    a.chars().flat_map(|c| c.to_uppercase()).eq(b.chars().flat_map(|c| c.to_uppercase()))
+}
+
+/// Whether a short name read from disk is acceptable
+///
+/// This is more lenient than `is_short_char()`, other implementations
+/// store lowercase letters and a leading 0xE5 is read back as is.
+fn is_valid_short_entry(short: &[u8; 11]) -> bool {
+    short[0] != b' ' && short.iter().all(|&b| match b {
+        0x00...0x1F => false,
+        b'"' | b'*' | b'+' | b',' | b'.' | b'/' | b':' | b';' | b'<' | b'=' | b'>' | b'?' |
+        b'[' | b'\\' | b']' | b'|' => false,
+        _ => true,
+    })
+}
+
+/// Whether `c` may appear in a short 8.3 name
+fn is_short_char(c: char) -> bool {
+    match c {
+        'A'...'Z' | '0'...'9' => true,
+        '!' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' | '-' | '@' | '^' | '_' | '`' | '{' | '}' | '~' => true,
+        _ => false,
+    }
+}
+
+/// Whether `name` can be stored exactly as a short 8.3 name
+fn is_short_name(name: &str) -> bool {
+    let (stem, ext) = split_name(name);
+    stem.len() > 0 && stem.len() <= 8 && ext.len() <= 3 &&
+        stem.chars().chain(ext.chars()).all(is_short_char)
+}
+
+/// Whether `name` may be stored in a directory
+///
+/// Control characters (including NUL) and the characters reserved by VFAT
+/// are rejected, as are names made up only of dots and spaces.
+fn is_valid_name(name: &str) -> bool {
+    let reserved = |c: char| c < ' ' || c == '\x7F' || "\"*/:<>?\\|".contains(c);
+    name.len() > 0 && !name.chars().any(reserved) && !name.chars().all(|c| c == '.' || c == ' ')
+}
+
+/// Generate the short 8.3 name for a file name
+///
+/// Follows the basis-name algorithm from the FAT specification: the name is
+/// uppercased, spaces and leading dots are removed, and characters which
+/// aren't allowed in short names are replaced by `_`. Only the last dot
+/// separates the extension. A numeric tail (`~1`, `~2`...) is added when
+/// characters had to be replaced or dropped, or when the result would
+/// collide with one of the short names in `taken`.
+///
+/// The long name is kept in separate entries, see `long_name_dires`.
+fn short_name(name: &str, taken: &[[u8; 11]]) -> Result<(String, String)> {
+    let mut lossy = false;
+    let (stem, ext) = split_name(name.trim_left_matches('.'));
+    let (stem, ext) = {
+        let mut convert = |s: &str| -> String {
+            s.chars()
+             .filter(|&c| c != ' ' && c != '.')
+             .map(|c| c.to_ascii_uppercase())
+             .map(|c| if is_short_char(c) { c } else { lossy = true; '_' })
+             .collect()
+        };
+        (convert(stem), convert(ext))
+    };
+    if stem.len() == 0 {
+        return Err(Error::InvalidPath)
+    }
+
+    let ext = ext.chars().take(3).collect::<String>();
+    let is_taken = |stem: &str| {
+        let dire = DirEntry::File {
+            name: stem.to_owned(),
+            ext: ext.clone(),
+            start: 0,
+            size: 0,
+            attrib: Attributes::empty(),
+            times: Times::default(),
+        };
+        taken.contains(&dire.short_name().unwrap())
+    };
+
+    let basis = stem.chars().take(8).collect::<String>();
+    if !lossy && is_short_name(&name.to_uppercase()) && !is_taken(&basis) {
+        return Ok((basis, ext))
+    }
+
+    for n in 1..1000000 {
+        let tail = format!("~{}", n);
+        let mut candidate = stem.chars().take(8 - tail.len()).collect::<String>();
+        candidate.push_str(&tail);
+        if !is_taken(&candidate) {
+            return Ok((candidate, ext))
+        }
+    }
+    // every possible numeric tail is in use
+    Err(Error::InvalidPath)
+}
+
+/// Checksum of a short name, stored in each of its long name entries
+fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &b| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b)
+    })
+}
+
+/// Build the long name entries for `name`
+///
+/// The entries are returned in the order they are stored on disk, which
+/// is the last piece of the name first.
+fn long_name_dires(name: &str, checksum: u8) -> Result<Vec<DirEntry>> {
+    let mut units = name.encode_utf16().collect::<Vec<u16>>();
+    if units.len() > 255 {
+        return Err(Error::InvalidPath)
+    }
+
+    // the name is null terminated if it doesn't fill the last piece,
+    // and any space left after that is padded with 0xFFFF
+    if units.len() % 13 != 0 {
+        units.push(0x0000);
+    }
+    while units.len() % 13 != 0 {
+        units.push(0xFFFF);
+    }
+
+    let count = units.len() / 13;
+    Ok((0..count).rev().map(|i| {
+        let mut chars = [0; 13];
+        for (j, &c) in units[i * 13 .. (i + 1) * 13].iter().enumerate() {
+            chars[j] = c;
+        }
+        DirEntry::LongName {
+            ord: (i + 1) as u8 | if i + 1 == count { LFN_LAST } else { 0 },
+            checksum: checksum,
+            chars: chars,
+        }
+    }).collect())
+}
+
+/// A problem found by `Fat32::check()`
+///
+/// Each variant notes what `Fat32::repair()` does about it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// A dir entry that can't be right, it is removed
+    BadEntry(PathBuf, &'static str),
+    /// A chain runs into a free or reserved cluster, it is ended early
+    BrokenChain(PathBuf),
+    /// A chain leads back into itself, it is ended before the loop
+    ChainLoop(PathBuf),
+    /// Two entries share a cluster, this is left alone
+    CrossLinked(PathBuf, PathBuf, usize), // the shared cluster
+    /// A file's chain doesn't fit its size, excess clusters are freed
+    /// or the size is cut down to the chain
+    SizeMismatch(PathBuf, usize, usize), // size in bytes, length of chain
+    /// A chain not reachable from any dir entry, it is saved as a file
+    /// named `FSCKnnnn.REC` in the root directory
+    LostChain(usize, usize), // first cluster, length of chain
+    /// A copy of the FAT disagrees with the one being read, it is overwritten
+    FatMismatch(usize, usize), // copy, number of differing entries
+    /// The FSInfo free cluster count is wrong, it is corrected
+    FreeCount(usize, usize), // recorded, actual
+}
+
+/// The result of checking a filesystem
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    pub repaired: bool, // whether the problems have been repaired
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check the consistency of a FAT filesystem, see `Fat32::check()`
+pub fn check(disk: Box<Disk>) -> Result<Report> {
+    try!(Fat32::new(disk)).check()
+}
+
+impl Fat32 {
+    /// Check the consistency of the filesystem without modifying it
+    pub fn check(&mut self) -> Result<Report> {
+        Checker::new(self, false).run()
+    }
+
+    /// Check the filesystem and repair what can be safely repaired
+    ///
+    /// The report lists the problems as they were found.
+    pub fn repair(&mut self) -> Result<Report> {
+        Checker::new(self, true).run()
+    }
+}
+
+/// State of a `Fat32::check()` in progress
+struct Checker<'a> {
+    fs: &'a mut Fat32,
+    repair: bool,
+    owners: Vec<Option<usize>>, // which entry each cluster belongs to, indexes `paths`
+    paths: Vec<PathBuf>,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(fs: &'a mut Fat32, repair: bool) -> Checker<'a> {
+        let len = fs.used.len();
+        Checker {
+            fs: fs,
+            repair: repair,
+            owners: vec![None; len],
+            paths: vec![],
+            problems: vec![],
+        }
+    }
+
+    fn run(mut self) -> Result<Report> {
+        let mismatched = try!(self.check_fat_copies());
+        try!(self.check_free_count());
+
+        let root = self.fs.rdir_cluster;
+        let owner = self.add_owner(PathBuf::from("/"));
+        if root != 0 {
+            // unless it's the fixed FAT12/16 root directory
+            try!(self.walk_chain(root, owner));
+        }
+        try!(self.check_dir(root, PathBuf::from("/"), root));
+        try!(self.check_lost());
+
+        if self.repair {
+            // changes were mirrored, but the copies might have disagreed already
+            if mismatched {
+                try!(self.sync_fat_copies());
+            }
+            self.fs.fsinfo_dirty = true;
+            try!(self.fs.flush_fsinfo());
+        }
+
+        Ok(Report {
+            problems: self.problems,
+            repaired: self.repair,
+        })
+    }
+
+    fn add_owner(&mut self, path: PathBuf) -> usize {
+        self.paths.push(path);
+        self.paths.len() - 1
+    }
+
+    /// Compare each copy of the FAT against the one being read
+    fn check_fat_copies(&mut self) -> Result<bool> {
+        let active = self.fs.active_fat.get();
+        if !self.fs.mirrored {
+            return Ok(false)
+        }
+        let mut mismatched = false;
+        for copy in 0..self.fs.fats {
+            if copy == active {
+                continue
+            }
+            let mut differ = 0;
+            let kind = self.fs.fat_type;
+            for c in 0..kind.entries(self.fs.fat_size) {
+                let a = try!(read_entry(&*self.fs.disk, kind, self.fs.fat_begin + active * self.fs.fat_size, c));
+                let b = try!(read_entry(&*self.fs.disk, kind, self.fs.fat_begin + copy * self.fs.fat_size, c));
+                if a != b {
+                    differ += 1;
+                }
+            }
+            if differ > 0 {
+                self.problems.push(Problem::FatMismatch(copy, differ));
+                mismatched = true;
+            }
+        }
+        Ok(mismatched)
+    }
+
+    fn sync_fat_copies(&mut self) -> Result<()> {
+        let active = self.fs.active_fat.get();
+        for copy in (0..self.fs.fats).filter(|&copy| copy != active) {
+            for i in 0..self.fs.fat_size {
+                let sector = try!(self.fs.disk.read_sector(self.fs.fat_begin + active * self.fs.fat_size + i)).clone();
+                try!(self.fs.disk.write_sector(self.fs.fat_begin + copy * self.fs.fat_size + i, &sector));
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare the FSInfo free count against the FAT
+    ///
+    /// `Fat32` counts the free clusters when mounting, so the recorded
+    /// value is corrected by writing the FSInfo sector again.
+    fn check_free_count(&mut self) -> Result<()> {
+        let lba = match self.fs.fsinfo {
+            Some(lba) => lba,
+            None => return Ok(()),
+        };
+        let recorded = (&try!(self.fs.disk.read_sector(lba))[488..492]).read_u32::<LittleEndian>().unwrap();
+        // an unknown count isn't wrong
+        if recorded != 0xFFFFFFFF && recorded as usize != self.fs.free_count {
+            self.problems.push(Problem::FreeCount(recorded as usize, self.fs.free_count));
+        }
+        Ok(())
+    }
+
+    /// Follow the chain beginning at `start`, claiming its clusters for `owner`
+    ///
+    /// Returns the number of clusters claimed.
+    fn walk_chain(&mut self, start: usize, owner: usize) -> Result<usize> {
+        let mut cluster = start;
+        let mut prev = None;
+        let mut length = 0;
+        loop {
+            match self.owners[cluster] {
+                Some(o) if o == owner => {
+                    self.problems.push(Problem::ChainLoop(self.paths[owner].clone()));
+                    if self.repair {
+                        // unwrap() is fine, the first cluster can't loop to itself yet
+                        try!(self.fs.write_fate(prev.unwrap(), &FatEntry::End));
+                    }
+                    break
+                },
+                Some(o) => {
+                    let other = self.paths[o].clone();
+                    self.problems.push(Problem::CrossLinked(other, self.paths[owner].clone(), cluster));
+                    break
+                },
+                None => { },
+            }
+            self.owners[cluster] = Some(owner);
+            length += 1;
+            match try!(self.fs.read_fate(cluster)) {
+                FatEntry::End => { break },
+                FatEntry::Cont(n) if (n as usize) >= 2 && (n as usize) < self.owners.len() => {
+                    prev = Some(cluster);
+                    cluster = n as usize;
+                },
+                _ => {
+                    self.problems.push(Problem::BrokenChain(self.paths[owner].clone()));
+                    if self.repair {
+                        try!(self.fs.write_fate(cluster, &FatEntry::End));
+                    }
+                    break
+                },
+            }
+        }
+        Ok(length)
+    }
+
+    /// Check each entry of the directory at `cluster` and everything below it
+    fn check_dir(&mut self, cluster: usize, path: PathBuf, parent: usize) -> Result<()> {
+        // a broken listing has already been reported by walk_chain()
+        let listing = DirIter::new(self.fs, cluster).take_while(|r| r.is_ok())
+                                                    .map(|r| r.unwrap())
+                                                    .collect::<Vec<Listed>>();
+        let csize = self.fs.cluster_size * 512;
+
+        for listed in listing {
+            if listed.is_dot() {
+                let name = listed.dire.short_file_name().unwrap();
+                let expected = match &name[..] {
+                    "." => cluster,
+                    _ if parent == self.fs.rdir_cluster => 0,
+                    _ => parent,
+                };
+                if listed.dire.start() != Some(expected) {
+                    self.problems.push(Problem::BadEntry(path.join(name), "dot entry refers to the wrong directory"));
+                    if self.repair {
+                        let mut dire = listed.dire.clone();
+                        if let DirEntry::Dir { ref mut start, .. } = dire {
+                            *start = expected;
+                        }
+                        try!(self.fs.set_dire(cluster, listed.index, &dire));
+                    }
+                }
+                continue
+            }
+
+            let epath = path.join(listed.file_name());
+            let start = listed.dire.start().unwrap();
+            let problem = if !listed.dire.short_name().map_or(false, |n| is_valid_short_entry(&n)) {
+                Some("invalid short name")
+            } else if start == 0 && listed.dire.size() == Some(0) {
+                // empty files needn't have any clusters
+                continue
+            } else if start < 2 || start >= self.owners.len() {
+                Some("first cluster out of range")
+            } else if let FatEntry::Free = try!(self.fs.read_fate(start)) {
+                Some("first cluster is free")
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                self.problems.push(Problem::BadEntry(epath, problem));
+                if self.repair {
+                    for i in listed.first..listed.index + 1 {
+                        try!(self.fs.set_dire(cluster, i, &DirEntry::Free));
+                    }
+                }
+                continue
+            }
+
+            let owner = self.add_owner(epath.clone());
+            let length = try!(self.walk_chain(start, owner));
+            match listed.dire {
+                DirEntry::File { size, .. } => {
+                    // empty files may still have a cluster
+                    let needed = (size + csize - 1) / csize;
+                    if length >= needed && length <= max(needed, 1) {
+                        continue
+                    }
+                    self.problems.push(Problem::SizeMismatch(epath, size, length));
+                    if !self.repair {
+                        continue
+                    }
+                    if length > needed {
+                        let mut last = start;
+                        for _ in 1..max(needed, 1) {
+                            last = try!(self.fs.expect_next(last));
+                        }
+                        let excess = try!(self.fs.expect_next(last));
+                        try!(self.fs.write_fate(last, &FatEntry::End));
+                        try!(self.fs.free_chain(excess));
+                    } else {
+                        let mut dire = listed.dire.clone();
+                        if let DirEntry::File { ref mut size, .. } = dire {
+                            *size = length * csize;
+                        }
+                        try!(self.fs.set_dire(cluster, listed.index, &dire));
+                    }
+                },
+                _ => {
+                    // don't descend into a directory belonging to something else
+                    if self.owners[start] == Some(owner) {
+                        try!(self.check_dir(start, epath, cluster));
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Find chains which are in use but not reachable from any dir entry
+    fn check_lost(&mut self) -> Result<()> {
+        let len = self.owners.len();
+        let mut lost = Bitmap::new(len);
+        let mut targeted = Bitmap::new(len);
+        for c in 2..len {
+            if self.owners[c].is_some() {
+                continue
+            }
+            match try!(self.fs.read_fate(c)) {
+                FatEntry::End => { lost.set(c, true) },
+                FatEntry::Cont(n) => {
+                    lost.set(c, true);
+                    if (n as usize) < len {
+                        targeted.set(n as usize, true);
+                    }
+                },
+                _ => { },
+            }
+        }
+
+        // walk from the heads of chains first, then whatever loops remain
+        let heads = (2..len).filter(|&c| lost.get(c) && !targeted.get(c));
+        let rest = (2..len).filter(|&c| lost.get(c) && targeted.get(c));
+        let mut recovered = 0;
+        for start in heads.chain(rest).collect::<Vec<usize>>() {
+            if self.owners[start].is_some() {
+                continue
+            }
+            let mut name = format!("FSCK{:04}.REC", recovered);
+            while try!(self.fs.find_dire_index(self.fs.rdir_cluster, Path::new(&name))).is_some() {
+                recovered += 1;
+                name = format!("FSCK{:04}.REC", recovered);
+            }
+            recovered += 1;
+            let owner = self.add_owner(PathBuf::from("/").join(&name));
+            // the chain isn't reported as broken or looping, it's lost anyway
+            let problems = self.problems.len();
+            let length = try!(self.walk_chain(start, owner));
+            self.problems.truncate(problems);
+            self.problems.push(Problem::LostChain(start, length));
+
+            if self.repair {
+                let dire = DirEntry::File {
+                    name: String::new(),
+                    ext: String::new(),
+                    start: start,
+                    size: length * self.fs.cluster_size * 512,
+                    attrib: ARCHIVE,
+                    times: Times::new(self.fs.clock.now()),
+                };
+                let root = self.fs.rdir_cluster;
+                try!(self.fs.add_dire(root, Path::new(&name), dire));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options for `format_with()`
+///
+/// Anything left as `None` is picked based on the disk.
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    /// Sectors per cluster, a power of two no greater than 128
+    pub cluster_size: Option<usize>,
+    /// Variant of FAT, by default the one the spec requires for the number
+    /// of clusters. FAT32 can be forced even on volumes which are too small.
+    pub fat_type: Option<FatType>,
+    /// Serial number of the volume, by default made from the current time
+    pub volume_id: Option<u32>,
+}
+
+/// Format drive as a FAT filesystem
+///
+/// This will overwrite the first sector, the reserved sectors,
+/// the space used by the FATs and the root directory. Everything after
+/// will be left intact until the FS is mounted and written to.
+///
+/// Returns whether FAT12, FAT16 or FAT32 was chosen.
+pub fn format<T: Disk>(disk: &mut T) -> Result<FatType> {
+    format_with(disk, &FormatOptions::default())
+}
+
+/// Format a disk, deriving the volume ID from `clock`
+pub fn format_with_clock<T: Disk>(disk: &mut T, clock: &Clock) -> Result<FatType> {
+    format_with(disk, &FormatOptions { volume_id: Some(volume_id(&clock.now())), ..FormatOptions::default() })
+}
+
+/// Format drive as a FAT filesystem, see `format()`
+pub fn format_with<T: Disk>(disk: &mut T, options: &FormatOptions) -> Result<FatType> {
+    use std::slice::bytes::copy_memory;
+
+    const SSIZE: usize = 512; // sector size (bytes)
+
+    let info = disk.info();
+    let dsize = info.size;
+    let layout = pick_layout(dsize, options);
+    debug!("format dsize={} layout={:?}", dsize, layout);
+    let (kind, csize, reserved, fsize) = (layout.kind, layout.cluster_size, layout.reserved, layout.fat_size);
+
+    let volume_id = options.volume_id.unwrap_or_else(|| volume_id(&SystemClock.now()));
+
+    // an unpartitioned FAT12 volume of a standard size is taken to be a floppy,
+    // anything else is described as a partition of the first hard disk
+    let (spt, heads, media, drive) = match floppy_geometry(dsize) {
+        Some((spt, heads, media)) if kind == FatType::Fat12 && info.start == 0 => (spt, heads, media, 0x00),
+        _ => (63, 255, MEDIA_FIXED, 0x80),
+    };
+
+    // the 16 bit fields are used when the values fit, FAT32 always uses the 32 bit ones
+    let total16 = if kind != FatType::Fat32 && dsize <= 0xFFFF { dsize } else { 0 };
+    let fsize16 = if kind != FatType::Fat32 { fsize } else { 0 };
 
     let mut header = Sector([0; 512]);
+    copy_memory(&[0xEB, 0x58, 0x90], &mut header[0..3]); // jmp over the header to offset 90
+    copy_memory(b"VOS     ", &mut header[3..11]); // OEM name
     let _ = (&mut header[11..13]).write_u16::<LittleEndian>(SSIZE as u16); // bytes per sector
-    let _ = (&mut header[13..14]).write_u8(CSIZE as u8); // sector per cluster
-    let _ = (&mut header[14..16]).write_u16::<LittleEndian>(RESERVED as u16); // reserved sectors
+    let _ = (&mut header[13..14]).write_u8(csize as u8); // sector per cluster
+    let _ = (&mut header[14..16]).write_u16::<LittleEndian>(reserved as u16); // reserved sectors
     let _ = (&mut header[16..17]).write_u8(FATS as u8); // number of FATs
-    let _ = (&mut header[19..21]).write_u16::<LittleEndian>(0); // FAT16: total sectors
-    let _ = (&mut header[32..36]).write_u32::<LittleEndian>(dsize as u32); // FAT32: total sectors
-    let _ = (&mut header[36..40]).write_u32::<LittleEndian>(fsize as u32); // Size of FAT in sectors
-    try!(disk.write_sector(0, &header));
+    let _ = (&mut header[17..19]).write_u16::<LittleEndian>(layout.root_entries as u16); // FAT16: root dir entries
+    let _ = (&mut header[19..21]).write_u16::<LittleEndian>(total16 as u16); // FAT16: total sectors
+    let _ = (&mut header[21..22]).write_u8(media); // media descriptor
+    let _ = (&mut header[22..24]).write_u16::<LittleEndian>(fsize16 as u16); // FAT16: size of FAT in sectors
+    let _ = (&mut header[24..26]).write_u16::<LittleEndian>(spt); // CHS: sectors per track
+    let _ = (&mut header[26..28]).write_u16::<LittleEndian>(heads); // CHS: number of heads
+    let _ = (&mut header[28..32]).write_u32::<LittleEndian>(info.start as u32); // hidden sectors
+    let total32 = if total16 == 0 { dsize } else { 0 };
+    let _ = (&mut header[32..36]).write_u32::<LittleEndian>(total32 as u32); // FAT32: total sectors
+    let ebr = if kind == FatType::Fat32 {
+        let _ = (&mut header[36..40]).write_u32::<LittleEndian>(fsize as u32); // Size of FAT in sectors
+        let _ = (&mut header[40..42]).write_u16::<LittleEndian>(0); // flags: FATs are mirrored
+        let _ = (&mut header[42..44]).write_u16::<LittleEndian>(0); // version 0.0
+        let _ = (&mut header[44..48]).write_u32::<LittleEndian>(2); // root directory cluster
+        let _ = (&mut header[48..50]).write_u16::<LittleEndian>(FSINFO_SECTOR as u16);
+        let _ = (&mut header[50..52]).write_u16::<LittleEndian>(BACKUP_SECTOR as u16);
+        64
+    } else {
+        // the extended boot record directly follows the FAT12/16 BPB
+        36
+    };
+    let _ = (&mut header[ebr..ebr + 1]).write_u8(drive); // drive number: first floppy or hard disk
+    let _ = (&mut header[ebr + 2..ebr + 3]).write_u8(0x29); // signature: the next three fields are valid
+    let _ = (&mut header[ebr + 3..ebr + 7]).write_u32::<LittleEndian>(volume_id);
+    copy_memory(b"NO NAME    ", &mut header[ebr + 7..ebr + 18]); // volume label
+    copy_memory(match kind { // filesystem type
+        FatType::Fat12 => b"FAT12   ",
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    }, &mut header[ebr + 18..ebr + 26]);
+    copy_memory(&[0x55, 0xAA], &mut header[510..512]);
 
     // zero out reserved sectors
-    for i in 0..RESERVED {
-        try!(disk.write_sector(1 + i, &EMPTY_SECTOR));
+    // the reserved sector count includes the header itself
+    for i in 1..reserved {
+        try!(disk.write_sector(i, &EMPTY_SECTOR));
+    }
+    try!(disk.write_sector(0, &header));
+
+    if kind == FatType::Fat32 {
+        // only the root directory's cluster is in use
+        let mut fsinfo = Sector([0; 512]);
+        let _ = (&mut fsinfo[0..4]).write_u32::<LittleEndian>(FSINFO_LEAD_SIG as u32);
+        let _ = (&mut fsinfo[484..488]).write_u32::<LittleEndian>(FSINFO_STRUCT_SIG as u32);
+        let _ = (&mut fsinfo[488..492]).write_u32::<LittleEndian>(layout.clusters as u32 - 1); // free cluster count
+        let _ = (&mut fsinfo[492..496]).write_u32::<LittleEndian>(3); // next free cluster
+        let _ = (&mut fsinfo[508..512]).write_u32::<LittleEndian>(FSINFO_TRAIL_SIG as u32);
+
+        // the boot sector and FSInfo sector are each followed by a backup
+        try!(disk.write_sector(FSINFO_SECTOR, &fsinfo));
+        try!(disk.write_sector(BACKUP_SECTOR, &header));
+        try!(disk.write_sector(BACKUP_SECTOR + FSINFO_SECTOR, &fsinfo));
     }
 
     // zero out FATs
     for i in 0..FATS {
-        let fat_start = 1 + RESERVED + i * fsize;
-        let mut sector = EMPTY_SECTOR.clone();
+        let fat_start = reserved + i * fsize;
+        for j in 0..fsize {
+            try!(disk.write_sector(fat_start + j, &EMPTY_SECTOR));
+        }
 
         // first two entries of FAT are reserved
         // they're reserved because of the whole fat entry format
         // 0x00000000 means free
         // 0x00000001 is reserved
         // so these cluster addresses cannot be used
-        (&mut sector[0..4]).write_u32::<LittleEndian>(0x00000001).unwrap();
-        (&mut sector[4..8]).write_u32::<LittleEndian>(0x00000001).unwrap();
-        (&mut sector[8..12]).write_u32::<LittleEndian>(0x0FFFFFFF).unwrap(); // signal end of root dir
-        try!(disk.write_sector(fat_start, &sector));
+        let media = (0xFFFFFF00 | media as u32) & kind.mask();
+        try!(write_entry(disk, kind, fat_start, 0, media));
+        try!(write_entry(disk, kind, fat_start, 1, kind.mask()));
+        if kind == FatType::Fat32 {
+            try!(write_entry(disk, kind, fat_start, 2, kind.mask())); // signal end of root dir
+        }
+    }
 
-        for j in 1..fsize {
-            try!(disk.write_sector(fat_start + j, &EMPTY_SECTOR));
+    // the root directory is empty, whether it's a cluster or a fixed region
+    let rdir_start = reserved + FATS * fsize;
+    let rdir_size = if kind == FatType::Fat32 { csize } else { root_sectors(layout.root_entries) };
+    for i in 0..rdir_size {
+        try!(disk.write_sector(rdir_start + i, &EMPTY_SECTOR));
+    }
+
+    Ok(kind)
+}
+
+/// Sizes of the structures on a FAT volume, see `pick_layout()`
+#[derive(Debug, Clone, PartialEq)]
+struct Layout {
+    kind: FatType,
+    cluster_size: usize, // sectors per cluster
+    reserved: usize, // reserved sectors, including the boot sector
+    root_entries: usize, // size of the FAT12/16 root directory, 0 for FAT32
+    fat_size: usize, // size of each FAT in sectors
+    clusters: usize,
+}
+
+impl Layout {
+    fn new(kind: FatType, dsize: usize, csize: usize) -> Layout {
+        let (reserved, root_entries) = match kind {
+            // FAT12/16 have no FSInfo sector or backup boot sector, but
+            // leave the same room for the volume bootloader as FAT32
+            FatType::Fat12 => (BACKUP_SECTOR, 224),
+            FatType::Fat16 => (BACKUP_SECTOR, 512),
+            FatType::Fat32 => (32, 0),
+        };
+        let (fat_size, clusters) = calc_sizes(kind, dsize, FATS, reserved + root_sectors(root_entries), csize);
+        Layout {
+            kind: kind,
+            cluster_size: csize,
+            reserved: reserved,
+            root_entries: root_entries,
+            fat_size: fat_size,
+            clusters: clusters,
         }
     }
+}
 
+/// Decide the variant of FAT and cluster size for a disk of `dsize` sectors
+///
+/// The spec tells FAT12, FAT16 and FAT32 apart by the number of clusters
+/// alone. If the count falls between what two variants allow, because
+/// their FATs differ in size, the cluster size is doubled unless it was
+/// given in `options`.
+fn pick_layout(dsize: usize, options: &FormatOptions) -> Layout {
+    let kinds = match options.fat_type {
+        Some(kind) => vec![kind],
+        None => vec![FatType::Fat12, FatType::Fat16, FatType::Fat32],
+    };
+    let mut csize = options.cluster_size.unwrap_or_else(|| pick_cluster_size(dsize)); // cluster size (sectors)
+    loop {
+        assert!(csize.is_power_of_two() && csize <= 128, "Invalid cluster size: {}", csize);
+        for &kind in &kinds {
+            let layout = Layout::new(kind, dsize, csize);
+            // a forced FAT32 is recognised by its boot sector instead
+            if FatType::from_clusters(layout.clusters) == kind || options.fat_type == Some(FatType::Fat32) {
+                return layout
+            }
+        }
+        assert!(options.cluster_size.is_none() && csize < 128,
+                "Unable to fit {:?} on a disk of {} sectors", options.fat_type, dsize);
+        csize *= 2;
+    }
+}
+
+/// Pick a cluster size in sectors for a disk of `dsize` sectors
+///
+/// Larger disks get larger clusters, so that the FATs stay reasonably small.
+fn pick_cluster_size(dsize: usize) -> usize {
+    match dsize {
+        0...532479 => 1, // up to 260MB
+        532480...16777215 => 8, // up to 8GB
+        16777216...33554431 => 16, // up to 16GB
+        33554432...67108863 => 32, // up to 32GB
+        _ => 64,
+    }
+}
+
+/// Geometry of a standard floppy disk of `dsize` sectors
+///
+/// Returns the sectors per track, number of heads and media descriptor.
+fn floppy_geometry(dsize: usize) -> Option<(u16, u16, u8)> {
+    match dsize {
+        720 => Some((9, 2, 0xFD)), // 360K 5.25"
+        1440 => Some((9, 2, 0xF9)), // 720K 3.5"
+        2400 => Some((15, 2, 0xF9)), // 1.2M 5.25"
+        2880 => Some((18, 2, MEDIA_FLOPPY)), // 1.44M 3.5"
+        5760 => Some((36, 2, MEDIA_FLOPPY)), // 2.88M 3.5"
+        _ => None,
+    }
+}
+
+/// Number of copies of the FAT made by `format()`
+const FATS: usize = 2;
+/// Media descriptor for non-removable disks
+const MEDIA_FIXED: u8 = 0xF8;
+/// Media descriptor for 1.44M and 2.88M floppies
+const MEDIA_FLOPPY: u8 = 0xF0;
+/// Sector of the FSInfo structure, relative to each copy of the boot sector
+const FSINFO_SECTOR: usize = 1;
+/// Sector of the backup boot sector
+const BACKUP_SECTOR: usize = 6;
+/// Signatures identifying a valid FSInfo sector
+const FSINFO_LEAD_SIG: usize = 0x41615252;
+const FSINFO_STRUCT_SIG: usize = 0x61417272;
+const FSINFO_TRAIL_SIG: usize = 0xAA550000;
+
+/// Like DOS, make a volume ID from the time of formatting
+fn volume_id(now: &DateTime) -> u32 {
+    let (date, time, fine) = encode_datetime(now);
+    ((date as u32) << 16 | time as u32).wrapping_add(fine as u32)
+}
+
+/// Number of sectors taken by a FAT12/16 root directory of `entries` entries
+fn root_sectors(entries: usize) -> usize {
+    (entries * 32 + 511) / 512
+}
+
+/// Read the entry of cluster `c` from the FAT beginning at `lba`
+fn read_entry(disk: &Disk, kind: FatType, lba: usize, c: usize) -> Result<u32> {
+    let (offset, shift) = kind.locate(c);
+    let mut raw = 0;
+    for i in 0..kind.entry_bytes() {
+        // a FAT12 entry may straddle two sectors
+        let at = offset + i;
+        raw |= (try!(disk.read_sector(lba + at / 512))[at % 512] as u32) << (8 * i);
+    }
+    Ok((raw >> shift) & kind.mask())
+}
+
+/// Set the entry of cluster `c` in the FAT beginning at `lba`
+///
+/// The bits belonging to a neighbouring FAT12 entry, or reserved by FAT32,
+/// are left as they were.
+fn write_entry(disk: &mut Disk, kind: FatType, lba: usize, c: usize, value: u32) -> Result<()> {
+    let (offset, shift) = kind.locate(c);
+    let mask = kind.mask() << shift;
+    let value = (value << shift) & mask;
+    let mut i = 0;
+    while i < kind.entry_bytes() {
+        // update each sector the entry spans once
+        let sector_lba = lba + (offset + i) / 512;
+        let mut sector = try!(disk.read_sector(sector_lba)).clone();
+        while i < kind.entry_bytes() && lba + (offset + i) / 512 == sector_lba {
+            let at = (offset + i) % 512;
+            let byte_mask = (mask >> (8 * i)) as u8;
+            sector[at] = (sector[at] & !byte_mask) | ((value >> (8 * i)) as u8 & byte_mask);
+            i += 1;
+        }
+        try!(disk.write_sector(sector_lba, &sector));
+    }
     Ok(())
 }
 
+/// Build a bitmap of the clusters in use by reading the FAT
+///
+/// Clusters 0 and 1 don't exist so they are always marked as used.
+fn load_used(disk: &Disk, kind: FatType, fat_begin: usize, clusters: usize) -> Result<Bitmap> {
+    use std::slice::bytes::copy_memory;
+
+    let mut used = Bitmap::new(clusters + 2);
+    used.set(0, true);
+    used.set(1, true);
+    // read the FAT a few sectors at a time, rather than going through
+    // read_entry(), three sectors hold a whole number of FAT12 entries
+    let group = if kind == FatType::Fat12 { 3 } else { 1 };
+    let per_group = kind.entries(group);
+    let mut buf = vec![0; group * 512];
+    for i in 0..(clusters + 2 + per_group - 1) / per_group {
+        for j in 0..group {
+            copy_memory(&try!(disk.read_sector(fat_begin + i * group + j))[..], &mut buf[j * 512..(j + 1) * 512]);
+        }
+        for j in 0..per_group {
+            let c = i * per_group + j;
+            if c < 2 || c >= clusters + 2 {
+                continue
+            }
+            let (offset, shift) = kind.locate(j);
+            let raw = (0..kind.entry_bytes()).fold(0, |raw, k| raw | (buf[offset + k] as u32) << (8 * k));
+            used.set(c, (raw >> shift) & kind.mask() != 0);
+        }
+    }
+    Ok(used)
+}
+
+/// Number of data clusters
+///
+/// This is limited both by the sectors following `cluster_begin` and
+/// by the number of entries in a FAT of `fsize` sectors.
+fn count_clusters(kind: FatType, dsize: usize, cluster_begin: usize, csize: usize, fsize: usize) -> usize {
+    let available = dsize.saturating_sub(cluster_begin) / csize;
+    min(available, kind.entries(fsize).saturating_sub(2))
+}
+
 /// Calculate sizes for various FS structures
 ///
-/// The size of the file allocation tables depends on the size of the disk,
-/// so this simple algorithm will approximate appropriate sizes for the FATs
-/// and actual cluster groups.
+/// `begin` counts the sectors before the clusters other than the FATs,
+/// which are the reserved sectors and any fixed root directory.
 ///
 /// Results:
 /// - size of each FAT in sectors
 /// - number of clusters
-fn calc_sizes(dsize: usize, fats: usize, reserved: usize) -> (usize, usize) {
-    let mut available = dsize - reserved - 1; // -1 for the FS header
+fn calc_sizes(kind: FatType, dsize: usize, fats: usize, begin: usize, csize: usize) -> (usize, usize) {
+    // clusters left after FATs of `fsize` sectors
+    let available = |fsize: usize| dsize.saturating_sub(begin + fats * fsize) / csize;
+
+    // begin with FATs big enough for the whole disk to be clusters, then
+    // shrink them while they still cover what's left
+    let mut fsize = ((dsize / csize + 2) * kind.bits() + 4095) / 4096;
+    while fsize > 1 && kind.entries(fsize - 1) >= available(fsize - 1) + 2 {
+        fsize -= 1;
+    }
+    let clusters = count_clusters(kind, dsize, begin + fats * fsize, csize, fsize);
+
+    debug!("calc_size {:?}", (fsize, clusters));
+    (fsize, clusters)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write, Seek, SeekFrom};
+    use std::path::Path;
+
+    use disk::{RamDisk, Error};
+    use fs::{FileSystem, FixedClock, DateTime, Entry, FileType, Mode, attributes};
+    use super::{Fat32, FatEntry, FatType, FormatOptions, Problem, format, format_with, format_with_clock, decode_datetime, encode_datetime, short_name};
+
+    // small disks are only formatted as FAT32 when asked
+    fn fat32() -> FormatOptions {
+        FormatOptions { fat_type: Some(FatType::Fat32), ..FormatOptions::default() }
+    }
+
+    fn ramdisk_fs(size: usize) -> Fat32 {
+        let mut disk = RamDisk::new(size);
+        format_with(&mut disk, &fat32()).unwrap();
+        Fat32::new(Box::new(disk)).unwrap()
+    }
+
+    fn fixed_clock_fs(size: usize, now: DateTime) -> Fat32 {
+        let mut disk = RamDisk::new(size);
+        format_with(&mut disk, &fat32()).unwrap();
+        Fat32::with_clock(Box::new(disk), Box::new(FixedClock(now))).unwrap()
+    }
+
+    /// Create an empty file with no clusters, as other implementations do
+    fn empty_file(fs: &mut Fat32, path: &Path) {
+        fs.write_file(path, &[]).unwrap();
+        let (dcluster, listed) = fs.find_dire(path).unwrap();
+        fs.free_chain(listed.dire.start().unwrap()).unwrap();
+        let mut dire = listed.dire.clone();
+        if let super::DirEntry::File { ref mut start, .. } = dire {
+            *start = 0;
+        }
+        fs.set_dire(dcluster, listed.index, &dire).unwrap();
+        fs.flush_fsinfo().unwrap();
+    }
+
+    #[test]
+    fn read_file() {
+        let mut fs = ramdisk_fs(2048);
+        let data = (0..3000).map(|i| i as u8).collect::<Vec<u8>>();
+        fs.write_file(Path::new("kernel.bin"), &data).unwrap();
+
+        let mut buf = vec![0; 4096];
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(3000));
+        assert_eq!(&buf[..3000], &data[..]);
+
+        let mut small = vec![0; 100];
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut small), Err(Error::BufferTooSmall(3000)));
+        assert_eq!(fs.read_file(Path::new("missing.bin"), &mut buf),
+                   Err(Error::Nonexistent(Path::new("missing.bin").to_owned())));
+    }
+
+    #[test]
+    fn delete() {
+        let mut fs = ramdisk_fs(2048);
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.write_file(Path::new("boot/kernel.bin"), &[1; 1000]).unwrap();
+
+        assert_eq!(fs.delete(Path::new("boot")), Err(Error::DirectoryNotEmpty(Path::new("boot").to_owned())));
+
+        fs.delete(Path::new("boot/kernel.bin")).unwrap();
+        let mut buf = [0; 1000];
+        assert_eq!(fs.read_file(Path::new("boot/kernel.bin"), &mut buf),
+                   Err(Error::Nonexistent(Path::new("boot/kernel.bin").to_owned())));
+
+        fs.delete(Path::new("boot")).unwrap();
+        assert_eq!(fs.delete(Path::new("boot")), Err(Error::Nonexistent(Path::new("boot").to_owned())));
+
+        // an empty file without a first cluster leaves the FAT alone
+        empty_file(&mut fs, Path::new("empty.txt"));
+        let free = fs.free_count;
+        let fat = fs.disk.read_sector(fs.fat_begin).unwrap().clone();
+        fs.delete(Path::new("empty.txt")).unwrap();
+        assert_eq!(&fs.disk.read_sector(fs.fat_begin).unwrap()[..], &fat[..]);
+        assert_eq!(fs.free_count, free);
+    }
 
-    let mut fsize = 0;
-    let mut csize = 0;
+    #[test]
+    fn open() {
+        let mut fs = ramdisk_fs(2048);
+        let data = (0..2000).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        {
+            let mut file = fs.open(Path::new("initrd.img"), Mode::Write).unwrap();
+            for chunk in data.chunks(300) {
+                file.write_all(chunk).unwrap();
+            }
+        }
+
+        let mut buf = vec![0; 2000];
+        assert_eq!(fs.read_file(Path::new("initrd.img"), &mut buf), Ok(2000));
+        assert_eq!(buf, data);
+
+        {
+            let mut file = fs.open(Path::new("initrd.img"), Mode::ReadWrite).unwrap();
+            file.seek(SeekFrom::Start(1020)).unwrap();
+            file.write_all(&[0xAA; 8]).unwrap();
+
+            let mut v = Vec::new();
+            file.seek(SeekFrom::End(-976)).unwrap();
+            file.read_to_end(&mut v).unwrap();
+            assert_eq!(&v[..4], &[0xAA; 4]);
+            assert_eq!(&v[4..], &data[1020 + 8..]);
+        }
+
+        {
+            let mut file = fs.open(Path::new("initrd.img"), Mode::Write).unwrap();
+            file.write_all(b"short").unwrap();
+        }
+        assert_eq!(fs.read_file(Path::new("initrd.img"), &mut buf), Ok(5));
+
+        // the truncated file's old bytes mustn't show through a gap
+        {
+            let mut file = fs.open(Path::new("initrd.img"), Mode::Write).unwrap();
+            file.seek(SeekFrom::Start(1500)).unwrap();
+            file.write_all(b"end").unwrap();
+        }
+        assert_eq!(fs.read_file(Path::new("initrd.img"), &mut buf), Ok(1503));
+        assert!(buf[..1500].iter().all(|&b| b == 0));
+        assert_eq!(&buf[1500..1503], b"end");
+        assert!(fs.open(Path::new("missing.img"), Mode::Read).is_err());
+
+        // an empty file with no clusters gets its first one on writing
+        empty_file(&mut fs, Path::new("empty.txt"));
+        let free = fs.free_count;
+        {
+            let mut file = fs.open(Path::new("empty.txt"), Mode::Write).unwrap();
+            file.write_all(&data).unwrap();
+        }
+        assert_eq!(fs.free_count, free - 4);
+        assert_eq!(fs.read_file(Path::new("empty.txt"), &mut buf), Ok(2000));
+        assert_eq!(buf, data);
+        assert!(fs.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn read_dir() {
+        let mut fs = ramdisk_fs(2048);
+        fs.make_dir(Path::new("boot")).unwrap();
+        // enough entries to span several clusters of the listing
+        for i in 0..40 {
+            fs.write_file(&Path::new("boot").join(format!("f{}.bin", i)), &[0; 600]).unwrap();
+        }
+        fs.delete(Path::new("boot/f3.bin")).unwrap();
+
+        let entries = fs.read_dir(Path::new("boot")).unwrap()
+                        .collect::<Result<Vec<Entry>, Error>>().unwrap();
+        assert_eq!(entries.len(), 39);
+        assert_eq!(entries[0].name, "f0.bin");
+        assert!(entries.iter().all(|e| e.kind == FileType::File && e.size == 600));
+        assert!(!entries.iter().any(|e| e.name == "f3.bin"));
+
+        let root = fs.read_dir(Path::new("/")).unwrap()
+                     .collect::<Result<Vec<Entry>, Error>>().unwrap();
+        assert_eq!(root.len(), 1);
+        assert_eq!(root[0].name, "boot");
+        assert_eq!(root[0].kind, FileType::Directory);
+
+        assert!(fs.read_dir(Path::new("boot/f0.bin")).is_err());
+    }
+
+    #[test]
+    fn metadata() {
+        let mut fs = ramdisk_fs(2048);
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.write_file(Path::new("boot/kernel.bin"), &[0; 1234]).unwrap();
+
+        let meta = fs.metadata(Path::new("boot/kernel.bin")).unwrap();
+        assert_eq!(meta.kind, FileType::File);
+        assert_eq!(meta.size, 1234);
+        assert!(!meta.attributes.contains(attributes::DIRECTORY));
+        assert_eq!(fs.metadata(Path::new("boot")).unwrap().kind, FileType::Directory);
+        assert_eq!(fs.metadata(Path::new("/")).unwrap().kind, FileType::Directory);
+
+        let dt = DateTime { year: 2015, month: 8, day: 21, hour: 13, minute: 37, second: 59 };
+        let (date, time, fine) = encode_datetime(&dt);
+        assert_eq!(decode_datetime(date, time, fine), Some(dt));
+        assert_eq!(decode_datetime(0, 0, 0), None);
+    }
+
+    #[test]
+    fn timestamps() {
+        let now = DateTime { year: 2015, month: 10, day: 3, hour: 2, minute: 0, second: 30 };
+        let mut fs = fixed_clock_fs(2048, now);
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.write_file(Path::new("boot/kernel.bin"), &[0; 10]).unwrap();
+
+        for path in &["boot", "boot/kernel.bin"] {
+            let meta = fs.metadata(Path::new(path)).unwrap();
+            assert_eq!(meta.created, Some(now));
+            assert_eq!(meta.modified, Some(now));
+            assert_eq!(meta.accessed, Some(DateTime { hour: 0, minute: 0, second: 0, .. now }));
+        }
+    }
+
+    #[test]
+    fn set_attributes() {
+        use fs::attributes::*;
 
-    while available > 128 + fats {
-        fsize += 1;
-        csize += 128;
-        available -= 128; // number of entries in a FAT sector
-        available -= fats; // reserve a sector for each FAT to hold the entries
+        let mut fs = ramdisk_fs(2048);
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.write_file(Path::new("boot/kernel.bin"), &[0; 10]).unwrap();
+        assert_eq!(fs.attributes(Path::new("boot/kernel.bin")), Ok(ARCHIVE));
+
+        fs.set_attributes(Path::new("boot/kernel.bin"), HIDDEN | SYSTEM).unwrap();
+        fs.write_file(Path::new("boot/kernel.bin"), &[1; 20]).unwrap();
+        assert_eq!(fs.attributes(Path::new("boot/kernel.bin")), Ok(HIDDEN | SYSTEM | ARCHIVE));
+
+        // the directory bit can't be cleared
+        fs.set_attributes(Path::new("boot"), READ_ONLY).unwrap();
+        assert_eq!(fs.attributes(Path::new("boot")), Ok(READ_ONLY | DIRECTORY));
+        assert_eq!(fs.read_dir(Path::new("boot")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn long_names() {
+        let mut fs = ramdisk_fs(2048);
+        fs.make_dir(Path::new("boot loader")).unwrap();
+        fs.write_file(Path::new("boot loader/kernel-debug.elf"), &[1; 700]).unwrap();
+        fs.write_file(Path::new("boot loader/kernel-release.elf"), &[2; 700]).unwrap();
+        fs.write_file(Path::new("boot loader/A Very Long File Name With Several Pieces.txt"), &[3]).unwrap();
+
+        let mut buf = [0; 700];
+        assert_eq!(fs.read_file(Path::new("boot loader/kernel-debug.elf"), &mut buf), Ok(700));
+        assert_eq!(&buf[..], &[1; 700][..]);
+        assert_eq!(fs.read_file(Path::new("boot loader/kernel-release.elf"), &mut buf), Ok(700));
+        assert_eq!(&buf[..], &[2; 700][..]);
+
+        let names = fs.read_dir(Path::new("boot loader")).unwrap()
+                      .map(|e| e.unwrap().name)
+                      .collect::<Vec<String>>();
+        assert_eq!(names, ["kernel-debug.elf", "kernel-release.elf",
+                           "A Very Long File Name With Several Pieces.txt"]);
+
+        fs.delete(Path::new("boot loader/kernel-debug.elf")).unwrap();
+        let names = fs.read_dir(Path::new("boot loader")).unwrap()
+                      .map(|e| e.unwrap().name)
+                      .collect::<Vec<String>>();
+        assert_eq!(names, ["kernel-release.elf", "A Very Long File Name With Several Pieces.txt"]);
     }
-    if available > fats {
-        fsize += 1;
-        available -= fats;
-        csize += available;
+
+    #[test]
+    fn short_names() {
+        let short = |name: &str| short_name(name, &[]);
+        let ok = |stem: &str, ext: &str| Ok((stem.to_owned(), ext.to_owned()));
+        assert_eq!(short("KERNEL.BIN"), ok("KERNEL", "BIN"));
+        assert_eq!(short("kernel.bin"), ok("KERNEL", "BIN"));
+        assert_eq!(short("kernel-debug.elf"), ok("KERNEL~1", "ELF"));
+        assert_eq!(short("readme.markdown"), ok("README~1", "MAR"));
+        assert_eq!(short(".bashrc"), ok("BASHRC~1", ""));
+        assert_eq!(short("a+b.tar.gz"), ok("A_BTAR~1", "GZ"));
+        assert_eq!(short("my file.txt"), ok("MYFILE~1", "TXT"));
+        assert_eq!(short("..."), Err(Error::InvalidPath));
+        assert_eq!(short_name("kernel.bin", &[*b"KERNEL  BIN"]), ok("KERNEL~1", "BIN"));
+
+        let mut fs = ramdisk_fs(2048);
+        fs.write_file(Path::new("kernel-debug.elf"), &[1]).unwrap();
+        fs.write_file(Path::new("kernel-dump.elf"), &[2]).unwrap();
+        let mut buf = [0];
+        assert_eq!(fs.read_file(Path::new("KERNEL~2.ELF"), &mut buf), Ok(1));
+        assert_eq!(buf, [2]);
+
+        assert_eq!(fs.write_file(Path::new("bad\0name"), &[]), Err(Error::InvalidPath));
+        assert_eq!(fs.write_file(Path::new("what?"), &[]), Err(Error::InvalidPath));
+        assert_eq!(fs.make_dir(Path::new(". .")), Err(Error::InvalidPath));
+        assert_eq!(fs.open(Path::new("a|b"), Mode::Write).err(), Some(Error::InvalidPath));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let mut fs = ramdisk_fs(2048);
+        fs.make_dir(Path::new("Boot")).unwrap();
+        fs.write_file(Path::new("boot/Kernel.Bin"), &[1; 10]).unwrap();
+
+        let mut buf = [0; 10];
+        assert_eq!(fs.read_file(Path::new("BOOT/KERNEL.BIN"), &mut buf), Ok(10));
+        assert_eq!(fs.read_file(Path::new("boot/kernel.bin"), &mut buf), Ok(10));
+
+        // overwrites the existing file, which keeps its name
+        fs.write_file(Path::new("BOOT/kernel.BIN"), &[2; 5]).unwrap();
+        let entries = fs.read_dir(Path::new("boot")).unwrap()
+                        .collect::<Result<Vec<Entry>, Error>>().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "Kernel.Bin");
+        assert_eq!(entries[0].size, 5);
+
+        assert_eq!(fs.make_dir(Path::new("BOOT")), Err(Error::AlreadyExists(Path::new("BOOT").to_owned())));
     }
 
-    debug!("calc_size {:?}", (fsize, csize));
-    (fsize, csize)
+    #[test]
+    fn dot_entries() {
+        let mut fs = ramdisk_fs(2048);
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.make_dir(Path::new("boot/grub")).unwrap();
+
+        let boot = fs.find_dir(Path::new("boot")).unwrap();
+        let grub = fs.find_dir(Path::new("boot/grub")).unwrap();
+        assert_eq!(fs.get_dire(grub, 0).unwrap().start(), Some(grub));
+        assert_eq!(fs.get_dire(grub, 1).unwrap().start(), Some(boot));
+        assert_eq!(fs.get_dire(boot, 1).unwrap().start(), Some(0));
+
+        assert_eq!(fs.find_dir(Path::new("boot/grub/..")), Ok(boot));
+        assert_eq!(fs.find_dir(Path::new("boot/grub/../..")), Ok(fs.rdir_cluster));
+        assert_eq!(fs.find_dir(Path::new("../boot/./grub")), Ok(grub));
+
+        fs.write_file(Path::new("boot/grub/../kernel.bin"), &[1; 4]).unwrap();
+        let names = fs.read_dir(Path::new("boot")).unwrap()
+                      .map(|e| e.unwrap().name)
+                      .collect::<Vec<String>>();
+        assert_eq!(names, ["grub", "kernel.bin"]);
+
+        // dot entries don't count as children
+        fs.delete(Path::new("boot/grub")).unwrap();
+    }
+
+    /// The fields of a FAT32 boot sector, read as another implementation would
+    #[derive(Debug)]
+    struct Bpb {
+        jump: [u8; 3],
+        bytes_per_sector: u16,
+        sectors_per_cluster: u8,
+        reserved: u16,
+        fats: u8,
+        root_entries: u16,
+        total_sectors16: u16,
+        media: u8,
+        fat_size16: u16,
+        hidden: u32,
+        total_sectors: u32,
+        fat_size: u32,
+        root_cluster: u32,
+        fsinfo: u16,
+        backup: u16,
+        signature: u8,
+        volume_id: u32,
+        label: Vec<u8>,
+        fs_type: Vec<u8>,
+        boot_signature: [u8; 2],
+    }
+
+    fn parse_bpb(s: &[u8]) -> Bpb {
+        use byteorder::{LittleEndian, ReadBytesExt};
+        let u16_at = |i: usize| (&s[i..i+2]).read_u16::<LittleEndian>().unwrap();
+        let u32_at = |i: usize| (&s[i..i+4]).read_u32::<LittleEndian>().unwrap();
+        Bpb {
+            jump: [s[0], s[1], s[2]],
+            bytes_per_sector: u16_at(11),
+            sectors_per_cluster: s[13],
+            reserved: u16_at(14),
+            fats: s[16],
+            root_entries: u16_at(17),
+            total_sectors16: u16_at(19),
+            media: s[21],
+            fat_size16: u16_at(22),
+            hidden: u32_at(28),
+            total_sectors: u32_at(32),
+            fat_size: u32_at(36),
+            root_cluster: u32_at(44),
+            fsinfo: u16_at(48),
+            backup: u16_at(50),
+            signature: s[66],
+            volume_id: u32_at(67),
+            label: s[71..82].to_vec(),
+            fs_type: s[82..90].to_vec(),
+            boot_signature: [s[510], s[511]],
+        }
+    }
+
+    #[test]
+    fn boot_sector() {
+        use byteorder::{LittleEndian, ReadBytesExt};
+        use disk::{self, Disk, Format, PartitionInfo};
+
+        // format a partition so that there are hidden sectors before it
+        let mut ramdisk = RamDisk::new(4096);
+        let mut mbr = ramdisk.read_sector(0).unwrap().clone();
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+        ramdisk.write_sector(0, &mbr).unwrap();
+        let pinfo = PartitionInfo { format: Format::Fat32, start: 63, size: 4033, bootable: true };
+        disk::set_pinfo(&mut ramdisk, 0, &pinfo).unwrap();
+
+        let mut partition = disk::get_partition(&mut ramdisk, 0).unwrap();
+        let options = FormatOptions { volume_id: Some(0x1234ABCD), ..fat32() };
+        format_with(&mut partition, &options).unwrap();
+
+        let sector = partition.read_sector(0).unwrap().clone();
+        let bpb = parse_bpb(&sector);
+        assert_eq!(bpb.jump, [0xEB, 0x58, 0x90]);
+        assert_eq!(bpb.bytes_per_sector, 512);
+        assert_eq!(bpb.sectors_per_cluster, 1);
+        assert_eq!(bpb.reserved, 32);
+        assert_eq!(bpb.fats, 2);
+        assert_eq!((bpb.root_entries, bpb.total_sectors16, bpb.fat_size16), (0, 0, 0));
+        assert_eq!(bpb.media, 0xF8);
+        assert_eq!(bpb.hidden, 63);
+        assert_eq!(bpb.total_sectors, 4033);
+        assert!(bpb.fat_size > 0);
+        assert!(bpb.reserved as u32 + bpb.fats as u32 * bpb.fat_size < bpb.total_sectors);
+        assert_eq!(bpb.root_cluster, 2);
+        assert_eq!(bpb.fsinfo, 1);
+        assert_eq!(bpb.backup, 6);
+        assert_eq!(bpb.signature, 0x29);
+        assert_eq!(bpb.volume_id, 0x1234ABCD);
+        assert_eq!(&bpb.label[..], b"NO NAME    ");
+        assert_eq!(&bpb.fs_type[..], b"FAT32   ");
+        assert_eq!(bpb.boot_signature, [0x55, 0xAA]);
+
+        // the backup boot sector is an exact copy
+        assert_eq!(&partition.read_sector(6).unwrap()[..], &sector[..]);
+
+        for &lba in &[1, 7] {
+            let fsinfo = partition.read_sector(lba).unwrap().clone();
+            let u32_at = |i: usize| (&fsinfo[i..i+4]).read_u32::<LittleEndian>().unwrap();
+            assert_eq!(u32_at(0), 0x41615252);
+            assert_eq!(u32_at(484), 0x61417272);
+            assert_eq!(u32_at(508), 0xAA550000);
+        }
+
+        // first FAT entry holds the media descriptor
+        let fat = partition.read_sector(bpb.reserved as usize).unwrap().clone();
+        assert_eq!(&fat[0..4], &[0xF8, 0xFF, 0xFF, 0x0F]);
+
+        Fat32::new(Box::new(partition)).unwrap();
+
+        // the volume ID can also be derived from a clock
+        let now = DateTime { year: 2015, month: 8, day: 21, hour: 9, minute: 30, second: 12 };
+        let mut disk = RamDisk::new(4096);
+        format_with_clock(&mut disk, &FixedClock(now)).unwrap();
+        let (date, time, _) = encode_datetime(&now);
+        // FAT12/16 keep the extended boot record at offset 36
+        let sector = disk.read_sector(0).unwrap().clone();
+        assert_eq!((&sector[39..43]).read_u32::<LittleEndian>().unwrap(), (date as u32) << 16 | time as u32);
+    }
+
+    #[test]
+    fn fsinfo() {
+        use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+        use disk::Disk;
+
+        // (free count, next free) as stored on disk
+        fn fsinfo(disk: &Disk) -> (usize, usize) {
+            let sector = disk.read_sector(1).unwrap();
+            ((&sector[488..492]).read_u32::<LittleEndian>().unwrap() as usize,
+             (&sector[492..496]).read_u32::<LittleEndian>().unwrap() as usize)
+        }
+
+        let mut fs = ramdisk_fs(2048);
+        let clusters = fs.used.len() - 2;
+        assert_eq!(fsinfo(&*fs.disk), (clusters - 1, 3));
+
+        fs.write_file(Path::new("kernel.bin"), &[7; 2000]).unwrap();
+        let free = fsinfo(&*fs.disk).0;
+        assert_eq!(free, fs.used.count_clear());
+        assert!(free < clusters - 1);
+
+        // allocation begins at the hint
+        fs.next_free = 100;
+        fs.make_dir(Path::new("boot")).unwrap();
+        assert_eq!(fs.find_dir(Path::new("boot")), Ok(100));
+        assert_eq!(fsinfo(&*fs.disk), (free - 1, 101));
+
+        fs.delete(Path::new("kernel.bin")).unwrap();
+        let free = fsinfo(&*fs.disk).0;
+        assert_eq!(free, fs.used.count_clear());
+
+        // a handle only updates FSInfo once it is flushed
+        {
+            let mut file = fs.open(Path::new("initrd.img"), Mode::Write).unwrap();
+            file.write_all(&[3; 3000]).unwrap();
+        }
+        assert_eq!(fsinfo(&*fs.disk).0, fs.used.count_clear());
+        fs.delete(Path::new("initrd.img")).unwrap();
+        let (free, next) = fsinfo(&*fs.disk);
+        assert_eq!(free, fs.used.count_clear());
+
+        // mounting recounts the free clusters from the FAT rather than
+        // trusting FSInfo, only the hint is loaded, or 2 if it's unknown
+        let fs = Fat32::new(fs.disk).unwrap();
+        assert_eq!((fs.free_count, fs.next_free), (free, next));
+        let mut disk = fs.disk;
+        let mut sector = disk.read_sector(1).unwrap().clone();
+        (&mut sector[488..496]).write_u64::<LittleEndian>(!0).unwrap();
+        disk.write_sector(1, &sector).unwrap();
+        let fs = Fat32::new(disk).unwrap();
+        assert_eq!((fs.free_count, fs.next_free), (free, 2));
+    }
+
+    #[test]
+    fn contiguous_alloc() {
+        let mut fs = ramdisk_fs(2048);
+        fs.write_file(Path::new("a.bin"), &[1; 100]).unwrap();
+        fs.write_file(Path::new("b.bin"), &[2; 100]).unwrap();
+        fs.delete(Path::new("a.bin")).unwrap();
+
+        // extending b.bin prefers the clusters right after it over the
+        // hole left by a.bin, even when the hint points at the hole
+        fs.next_free = 2;
+        {
+            let mut file = fs.open(Path::new("b.bin"), Mode::ReadWrite).unwrap();
+            file.seek(SeekFrom::End(0)).unwrap();
+            file.write_all(&[3; 3000]).unwrap();
+        }
+        let start = fs.find_dire(Path::new("b.bin")).unwrap().1.dire.start().unwrap();
+        let mut cluster = start;
+        while let Some(next) = fs.next_cluster(cluster).unwrap() {
+            assert_eq!(next, cluster + 1);
+            cluster = next;
+        }
+        assert!(cluster - start >= 6);
+    }
+
+    #[test]
+    fn large_clusters() {
+        use super::pick_cluster_size;
+        assert_eq!(pick_cluster_size(8192), 1);
+        assert_eq!(pick_cluster_size(4 << 21), 8);
+
+        let mut disk = RamDisk::new(4096);
+        let options = FormatOptions { cluster_size: Some(4), ..fat32() };
+        format_with(&mut disk, &options).unwrap();
+        let mut fs = Fat32::new(Box::new(disk)).unwrap();
+        assert_eq!(fs.cluster_size, 4);
+
+        // a listing spanning several 64 entry clusters
+        fs.make_dir(Path::new("boot")).unwrap();
+        for i in 0..100 {
+            fs.write_file(&Path::new("boot").join(format!("{}.bin", i)), &[i as u8; 100]).unwrap();
+        }
+        assert_eq!(fs.read_dir(Path::new("boot")).unwrap().count(), 100);
+        let mut buf = [0; 100];
+        assert_eq!(fs.read_file(Path::new("boot/99.bin"), &mut buf), Ok(100));
+        assert_eq!(&buf[..], &[99; 100][..]);
+
+        // file contents spanning clusters and sectors within them
+        let data = (0..5000).map(|i| (i / 7) as u8).collect::<Vec<u8>>();
+        fs.write_file(Path::new("kernel.bin"), &data).unwrap();
+        let start = fs.find_dire(Path::new("kernel.bin")).unwrap().1.dire.start().unwrap();
+        assert_eq!(fs.next_cluster(start), Ok(Some(start + 1)));
+        let mut buf = vec![0; 5000];
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(5000));
+        assert_eq!(buf, data);
+
+        let mut file = fs.open(Path::new("kernel.bin"), Mode::ReadWrite).unwrap();
+        file.seek(SeekFrom::Start(2040)).unwrap();
+        file.write_all(&[0xAA; 20]).unwrap();
+        file.seek(SeekFrom::Start(2030)).unwrap();
+        let mut buf = vec![];
+        (&mut file).take(40).read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..10], &data[2030..2040]);
+        assert_eq!(&buf[10..30], &[0xAA; 20][..]);
+        assert_eq!(&buf[30..], &data[2060..2070]);
+    }
+
+    #[test]
+    fn fat_types() {
+        use byteorder::{LittleEndian, ReadBytesExt};
+        use disk::Disk;
+
+        // a floppy, a small image, and a disk just big enough for FAT32
+        for &(size, kind) in &[(2880, FatType::Fat12), (8192, FatType::Fat16), (70000, FatType::Fat32)] {
+            let mut disk = RamDisk::new(size);
+            assert_eq!(format(&mut disk), Ok(kind));
+            let header = disk.read_sector(0).unwrap().clone();
+            let u16_at = |i: usize| (&header[i..i+2]).read_u16::<LittleEndian>().unwrap();
+            if kind == FatType::Fat32 {
+                assert_eq!((u16_at(17), u16_at(19), u16_at(22)), (0, 0, 0));
+                assert_eq!(&header[82..90], b"FAT32   ");
+            } else {
+                assert!(u16_at(17) > 0);
+                assert_eq!(u16_at(19) as usize, size);
+                assert!(u16_at(22) > 0);
+                assert_eq!(header[38], 0x29);
+                assert_eq!(&header[54..62], if kind == FatType::Fat12 { b"FAT12   " } else { b"FAT16   " });
+            }
+            // media descriptor, geometry and drive number
+            if kind == FatType::Fat12 {
+                assert_eq!((header[21], u16_at(24), u16_at(26), header[36]), (0xF0, 18, 2, 0x00));
+            } else {
+                assert_eq!((header[21], u16_at(24), u16_at(26)), (0xF8, 63, 255));
+            }
+
+            let fs = Fat32::new(Box::new(disk)).unwrap();
+            assert_eq!(fs.fat_type(), kind);
+            assert_eq!(FatType::from_clusters(fs.used.len() - 2), kind);
+        }
+
+        // the first FAT entries are packed into 12 bits
+        let mut disk = RamDisk::new(2880);
+        format(&mut disk).unwrap();
+        assert_eq!(&disk.read_sector(6).unwrap()[..4], &[0xF0, 0xFF, 0xFF, 0x00]);
+
+        // FAT12 volumes which aren't a standard floppy size are left as hard disks
+        let mut disk = RamDisk::new(3000);
+        assert_eq!(format(&mut disk), Ok(FatType::Fat12));
+        let header = disk.read_sector(0).unwrap().clone();
+        assert_eq!((header[21], header[36]), (0xF8, 0x80));
+    }
+
+    #[test]
+    fn fat12_and_fat16() {
+        for &size in &[2880, 8192] {
+            let mut disk = RamDisk::new(size);
+            let kind = format(&mut disk).unwrap();
+            let mut fs = Fat32::new(Box::new(disk)).unwrap();
+            assert_eq!(fs.rdir_cluster, 0);
+            let free = fs.free_count;
+
+            // long enough for the chain to cross FAT sectors, which
+            // some FAT12 entries straddle
+            let data = (0..300000).map(|i| (i / 511) as u8).collect::<Vec<u8>>();
+            fs.make_dir(Path::new("boot")).unwrap();
+            fs.write_file(Path::new("boot/kernel.bin"), &data).unwrap();
+            fs.write_file(Path::new("a long file name.txt"), b"hello").unwrap();
+            let mut buf = vec![0; data.len()];
+            assert_eq!(fs.read_file(Path::new("boot/kernel.bin"), &mut buf), Ok(data.len()));
+            assert_eq!(buf, data);
+            assert_eq!(fs.find_dir(Path::new("boot/..")), Ok(0));
+
+            fs.rename(Path::new("boot/kernel.bin"), Path::new("kernel.bin"), false).unwrap();
+            fs.truncate(Path::new("kernel.bin"), 1000).unwrap();
+            assert_eq!(fs.free_count, free - 4);
+            assert!(fs.check().unwrap().is_clean());
+
+            // the FAT is read back the same way
+            let mut fs = Fat32::new(fs.disk).unwrap();
+            assert_eq!(fs.fat_type(), kind);
+            assert_eq!(fs.free_count, free - 4);
+            assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(1000));
+            assert_eq!(&buf[..1000], &data[..1000]);
+
+            // the root directory can't grow past its fixed size
+            let listed = fs.read_dir(Path::new("/")).unwrap().count();
+            let mut added = 0;
+            while fs.write_file(Path::new(&format!("{}.BIN", added)), &[]).is_ok() {
+                added += 1;
+            }
+            assert_eq!(fs.write_file(Path::new("FULL.BIN"), &[]), Err(Error::NoSpace));
+            assert!(added > 100);
+            assert_eq!(fs.read_dir(Path::new("/")).unwrap().count(), listed + added);
+            assert_eq!(fs.free_count, free - 4 - added);
+            assert!(fs.check().unwrap().is_clean());
+
+            // a short name only needs the one entry that was freed
+            fs.delete(Path::new("0.BIN")).unwrap();
+            fs.write_file(Path::new("LAST.BIN"), b"x").unwrap();
+        }
+    }
+
+    #[test]
+    fn invalid_bpb() {
+        use disk::Disk;
+
+        // apply `corrupt` to a freshly formatted boot sector and try to mount
+        fn mount_with<F: Fn(&mut [u8])>(corrupt: F) -> Result<Fat32, Error> {
+            let mut disk = RamDisk::new(2048);
+            format_with(&mut disk, &fat32()).unwrap();
+            let mut header = disk.read_sector(0).unwrap().clone();
+            corrupt(&mut header[..]);
+            disk.write_sector(0, &header).unwrap();
+            Fat32::new(Box::new(disk))
+        }
+
+        assert!(mount_with(|_| { }).is_ok());
+        let error = |f: fn(&mut [u8])| mount_with(f).err().unwrap();
+        assert_eq!(error(|h| h[511] = 0), Error::CorruptBPB("missing boot sector signature"));
+        assert_eq!(error(|h| h[17] = 0x10), Error::CorruptBPB("root directory entries on a FAT32 volume"));
+        assert_eq!(error(|h| h[12] = 4), Error::CorruptBPB("bytes per sector doesn't match the disk"));
+        assert_eq!(error(|h| h[13] = 3),
+                   Error::CorruptBPB("sectors per cluster is not a power of two up to 128"));
+        assert_eq!(error(|h| h[16] = 0), Error::CorruptBPB("no FATs"));
+        assert_eq!(error(|h| h[33] = 0x10),
+                   Error::CorruptBPB("total sectors exceeds the size of the partition"));
+        assert_eq!(error(|h| h[37] = 0x10), Error::CorruptBPB("FATs extend past the end of the volume"));
+        assert_eq!(error(|h| h[44] = 1), Error::CorruptBPB("root directory cluster out of range"));
+
+        // the root directory needn't be in cluster 2
+        let mut fs = ramdisk_fs(2048);
+        fs.make_dir(Path::new("root")).unwrap();
+        let root = fs.find_dir(Path::new("root")).unwrap();
+        let mut disk = fs.disk;
+        let mut header = disk.read_sector(0).unwrap().clone();
+        header[44] = root as u8;
+        disk.write_sector(0, &header).unwrap();
+        let fs = Fat32::new(disk).unwrap();
+        assert_eq!(fs.rdir_cluster, root);
+    }
+
+    #[test]
+    fn fat_mirror() {
+        use disk::Disk;
+
+        let mut fs = ramdisk_fs(2048);
+        let data = (0..3000).map(|i| i as u8).collect::<Vec<u8>>();
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.write_file(Path::new("boot/kernel.bin"), &data).unwrap();
+        fs.delete(Path::new("boot/kernel.bin")).unwrap();
+        fs.write_file(Path::new("boot/kernel.bin"), &data).unwrap();
+
+        // every copy is kept identical
+        let (first, second) = (fs.fat_begin, fs.fat_begin + fs.fat_size);
+        for i in 0..fs.fat_size {
+            assert_eq!(&fs.disk.read_sector(first + i).unwrap()[..],
+                       &fs.disk.read_sector(second + i).unwrap()[..]);
+        }
+
+        // break the chain in the first copy only
+        let start = fs.find_dire(Path::new("boot/kernel.bin")).unwrap().1.dire.start().unwrap();
+        let lba = first + start / 128;
+        let mut sector = fs.disk.read_sector(lba).unwrap().clone();
+        for b in &mut sector[(start % 128) * 4..(start % 128) * 4 + 4] {
+            *b = 0;
+        }
+        fs.disk.write_sector(lba, &sector).unwrap();
+
+        // the free clusters are counted from the first copy when mounting,
+        // so the file's first cluster starts out looking free
+        let mut fs = Fat32::new(fs.disk).unwrap();
+        let free = fs.free_count;
+        assert_eq!(fs.active_fat(), 0);
+        let mut buf = vec![0; 3000];
+        assert_eq!(fs.read_file(Path::new("boot/kernel.bin"), &mut buf), Ok(3000));
+        assert_eq!(buf, data);
+        assert_eq!(fs.active_fat(), 1);
+
+        // once the mirror is in use, allocating mustn't hand that cluster out
+        fs.next_free = start;
+        fs.write_file(Path::new("boot/initrd.img"), &[7; 1000]).unwrap();
+        assert!(fs.find_dire(Path::new("boot/initrd.img")).unwrap().1.dire.start() != Some(start));
+        assert_eq!(fs.free_count, free - 1 - 2);
+        assert_eq!(fs.read_file(Path::new("boot/kernel.bin"), &mut buf), Ok(3000));
+        assert_eq!(buf, data);
+
+        // with no copies left, the corruption is reported
+        fs.disk.write_sector(second + start / 128, &sector).unwrap();
+        assert_eq!(fs.read_file(Path::new("boot/kernel.bin"), &mut buf), Err(Error::CorruptFAT));
+    }
+
+    #[test]
+    fn check() {
+        use std::path::PathBuf;
+        use disk::Disk;
+
+        let mut fs = ramdisk_fs(2048);
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.write_file(Path::new("boot/kernel.bin"), &[1; 3000]).unwrap();
+        fs.write_file(Path::new("loop.bin"), &[2; 1500]).unwrap();
+        fs.write_file(Path::new("bad.bin"), &[3; 100]).unwrap();
+        fs.write_file(Path::new("empty.bin"), &[]).unwrap();
+        assert!(fs.check().unwrap().is_clean());
+
+        // a chain longer than the file
+        let kernel = fs.find_dire(Path::new("boot/kernel.bin")).unwrap().1.dire.start().unwrap();
+        fs.alloc_cluster(Some(kernel)).unwrap();
+        // a chain looping back to its start
+        let start = fs.find_dire(Path::new("loop.bin")).unwrap().1.dire.start().unwrap();
+        fs.write_fate(start + 2, &FatEntry::Cont(start as u32)).unwrap();
+        // an entry pointing outside the disk, leaving its chain lost
+        let (dcluster, listed) = fs.find_dire(Path::new("bad.bin")).unwrap();
+        let bad = listed.dire.start().unwrap();
+        let mut dire = listed.dire.clone();
+        if let super::DirEntry::File { ref mut start, .. } = dire {
+            *start = 0xFFFFFF;
+        }
+        fs.set_dire(dcluster, listed.index, &dire).unwrap();
+        // a chain nothing refers to
+        let lost = fs.alloc_cluster(None).unwrap();
+        fs.alloc_cluster(Some(lost)).unwrap();
+        // a stale free count
+        let mut sector = fs.disk.read_sector(1).unwrap().clone();
+        sector[488] ^= 0xFF;
+        fs.disk.write_sector(1, &sector).unwrap();
+        let recorded = sector[488] as usize | (sector[489] as usize) << 8;
+        // copies of the FAT which disagree
+        let lba = fs.fat_begin + fs.fat_size + fs.fat_size - 1;
+        let mut sector = fs.disk.read_sector(lba).unwrap().clone();
+        sector[508] = 0xFF;
+        fs.disk.write_sector(lba, &sector).unwrap();
+
+        let report = fs.check().unwrap();
+        let root = PathBuf::from("/");
+        let expected = [
+            Problem::FatMismatch(1, 1),
+            Problem::FreeCount(recorded, fs.free_count),
+            Problem::SizeMismatch(root.join("boot/kernel.bin"), 3000, 7),
+            Problem::ChainLoop(root.join("loop.bin")),
+            Problem::BadEntry(root.join("bad.bin"), "first cluster out of range"),
+            Problem::LostChain(bad, 1),
+            Problem::LostChain(lost, 2),
+        ];
+        for problem in &expected {
+            assert!(report.problems.contains(problem), "{:?} not in {:?}", problem, report.problems);
+        }
+        assert_eq!(report.problems.len(), expected.len());
+        assert!(!report.repaired);
+
+        assert_eq!(fs.repair().unwrap().problems, report.problems);
+        assert!(fs.check().unwrap().is_clean());
+
+        let names = fs.read_dir(Path::new("/")).unwrap()
+                      .map(|e| e.unwrap().name)
+                      .collect::<Vec<String>>();
+        assert_eq!(names, ["boot", "loop.bin", "FSCK0000.REC", "FSCK0001.REC", "empty.bin"]);
+        let mut buf = [0; 1500];
+        assert_eq!(fs.read_file(Path::new("loop.bin"), &mut buf), Ok(1500));
+        assert_eq!(fs.read_file(Path::new("FSCK0000.REC"), &mut buf), Ok(512));
+        assert_eq!(&buf[..100], &[3; 100][..]);
+
+        // cross links are only reported
+        let start = fs.find_dire(Path::new("loop.bin")).unwrap().1.dire.start().unwrap();
+        fs.write_fate(start + 2, &FatEntry::Cont(kernel as u32)).unwrap();
+        let report = fs.repair().unwrap();
+        let cross = Problem::CrossLinked(root.join("boot/kernel.bin"), root.join("loop.bin"), kernel);
+        assert!(report.problems.contains(&cross));
+        assert!(!fs.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn truncate() {
+        let mut fs = ramdisk_fs(2048);
+        let free = fs.free_count;
+        let data = (0..3000).map(|i| i as u8).collect::<Vec<u8>>();
+        fs.write_file(Path::new("kernel.bin"), &data).unwrap();
+        assert_eq!(fs.free_count, free - 6);
+
+        // overwriting with less data releases the rest of the chain
+        fs.write_file(Path::new("kernel.bin"), &data[..600]).unwrap();
+        assert_eq!(fs.free_count, free - 2);
+        fs.write_file(Path::new("kernel.bin"), &[]).unwrap();
+        assert_eq!(fs.free_count, free - 1);
+
+        fs.write_file(Path::new("kernel.bin"), &data).unwrap();
+        fs.truncate(Path::new("kernel.bin"), 1000).unwrap();
+        assert_eq!(fs.free_count, free - 2);
+        let mut buf = vec![0; 3000];
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(1000));
+        assert_eq!(&buf[..1000], &data[..1000]);
+
+        // growing zero fills, including what was cut off before
+        fs.truncate(Path::new("kernel.bin"), 2000).unwrap();
+        assert_eq!(fs.free_count, free - 4);
+        assert_eq!(fs.read_file(Path::new("kernel.bin"), &mut buf), Ok(2000));
+        assert_eq!(&buf[..1000], &data[..1000]);
+        assert!(buf[1000..2000].iter().all(|&b| b == 0));
+
+        fs.truncate(Path::new("kernel.bin"), 0).unwrap();
+        assert_eq!(fs.free_count, free - 1);
+        assert_eq!(fs.metadata(Path::new("kernel.bin")).unwrap().size, 0);
+        assert!(fs.check().unwrap().is_clean());
+
+        // empty files with no clusters get their first one when written
+        empty_file(&mut fs, Path::new("a.bin"));
+        fs.truncate(Path::new("a.bin"), 1000).unwrap();
+        assert_eq!(fs.read_file(Path::new("a.bin"), &mut buf), Ok(1000));
+        assert!(buf[..1000].iter().all(|&b| b == 0));
+        empty_file(&mut fs, Path::new("b.bin"));
+        fs.write_file(Path::new("b.bin"), &data).unwrap();
+        assert_eq!(fs.read_file(Path::new("b.bin"), &mut buf), Ok(3000));
+        assert_eq!(buf, data);
+        assert_eq!(fs.free_count, free - 1 - 2 - 6);
+        assert!(fs.check().unwrap().is_clean());
+
+        fs.make_dir(Path::new("boot")).unwrap();
+        assert_eq!(fs.truncate(Path::new("boot"), 0), Err(Error::NotAFile(Path::new("boot").to_owned())));
+    }
+
+    #[test]
+    fn write_at() {
+        let mut fs = ramdisk_fs(2048);
+        let free = fs.free_count;
+        let path = Path::new("log.txt");
+        fs.write_file(path, b"boot\n").unwrap();
+        for _ in 0..200 {
+            fs.append(path, b"tick\n").unwrap();
+        }
+        assert_eq!(fs.free_count, free - 2);
+        let mut buf = vec![0; 4096];
+        assert_eq!(fs.read_file(path, &mut buf), Ok(1005));
+        assert_eq!(&buf[..10], b"boot\ntick\n");
+        assert_eq!(&buf[1000..1005], b"tick\n");
+
+        // overwriting in the middle, across a cluster boundary
+        fs.write_at(path, 510, b"TICK").unwrap();
+        assert_eq!(fs.read_file(path, &mut buf), Ok(1005));
+        assert_eq!(&buf[505..515], b"tick\nTICK\n");
+        assert_eq!(fs.free_count, free - 2);
+
+        // writing past the end leaves a gap of zeros
+        fs.write_at(path, 3000, b"end").unwrap();
+        assert_eq!(fs.free_count, free - 6);
+        assert_eq!(fs.read_file(path, &mut buf), Ok(3003));
+        assert_eq!(&buf[1000..1005], b"tick\n");
+        assert!(buf[1005..3000].iter().all(|&b| b == 0));
+        assert_eq!(&buf[3000..3003], b"end");
+        assert!(fs.check().unwrap().is_clean());
+
+        // a log made elsewhere starts out with no clusters at all
+        empty_file(&mut fs, Path::new("a.log"));
+        fs.append(Path::new("a.log"), b"tick\n").unwrap();
+        assert_eq!(fs.read_file(Path::new("a.log"), &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"tick\n");
+        empty_file(&mut fs, Path::new("b.log"));
+        fs.write_at(Path::new("b.log"), 1000, b"end").unwrap();
+        assert_eq!(fs.read_file(Path::new("b.log"), &mut buf), Ok(1003));
+        assert!(buf[..1000].iter().all(|&b| b == 0));
+        assert_eq!(&buf[1000..1003], b"end");
+        assert_eq!(fs.free_count, free - 6 - 1 - 2);
+        assert!(fs.check().unwrap().is_clean());
+
+        assert_eq!(fs.append(Path::new("missing"), b"x"),
+                   Err(Error::Nonexistent(Path::new("missing").to_owned())));
+        fs.make_dir(Path::new("boot")).unwrap();
+        assert_eq!(fs.write_at(Path::new("boot"), 0, b"x"),
+                   Err(Error::NotAFile(Path::new("boot").to_owned())));
+    }
+
+    #[test]
+    fn no_space() {
+        let mut fs = ramdisk_fs(2048);
+        let free = fs.free_count;
+        let data = vec![7; (free + 1) * 512];
+
+        // too big to ever fit, nothing is left behind
+        assert_eq!(fs.write_file(Path::new("big.bin"), &data), Err(Error::NoSpace));
+        assert_eq!(fs.free_count, free);
+        assert!(fs.metadata(Path::new("big.bin")).is_err());
+
+        // fills the volume exactly
+        fs.write_file(Path::new("big.bin"), &data[..free * 512]).unwrap();
+        assert_eq!(fs.free_count, 0);
+        assert_eq!(fs.make_dir(Path::new("boot")), Err(Error::NoSpace));
+        assert_eq!(fs.write_file(Path::new("small.bin"), &[1]), Err(Error::NoSpace));
+        assert_eq!(fs.append(Path::new("big.bin"), &[1]), Err(Error::NoSpace));
+        assert_eq!(fs.truncate(Path::new("big.bin"), free * 512 + 1), Err(Error::NoSpace));
+        // but rewriting within the existing chain still works
+        fs.write_at(Path::new("big.bin"), 0, &[8; 100]).unwrap();
+
+        // a failed overwrite keeps the old contents
+        fs.write_file(Path::new("big.bin"), &data[..512]).unwrap();
+        fs.write_file(Path::new("small.bin"), &data[..(free - 1) * 512]).unwrap();
+        assert_eq!(fs.write_file(Path::new("big.bin"), &data[..1024]), Err(Error::NoSpace));
+        let mut buf = vec![0; 1024];
+        assert_eq!(fs.read_file(Path::new("big.bin"), &mut buf), Ok(512));
+        assert_eq!(&buf[..512], &data[..512]);
+        assert!(fs.check().unwrap().is_clean());
+
+        fs.delete(Path::new("small.bin")).unwrap();
+        fs.delete(Path::new("big.bin")).unwrap();
+        assert_eq!(fs.free_count, free);
+    }
+
+    #[test]
+    fn rename() {
+        let mut fs = ramdisk_fs(2048);
+        fs.make_dir(Path::new("boot")).unwrap();
+        fs.make_dir(Path::new("boot/grub")).unwrap();
+        fs.write_file(Path::new("boot/kernel.bin"), &[1; 1000]).unwrap();
+        fs.write_file(Path::new("boot/grub/grub.cfg"), &[2; 10]).unwrap();
+
+        // rotate the kernel before writing a new one
+        fs.rename(Path::new("boot/kernel.bin"), Path::new("boot/kernel.old"), false).unwrap();
+        fs.write_file(Path::new("boot/kernel.bin"), &[3; 500]).unwrap();
+        let mut buf = [0; 1000];
+        assert_eq!(fs.read_file(Path::new("boot/kernel.old"), &mut buf), Ok(1000));
+        assert_eq!(&buf[..], &[1; 1000][..]);
+
+        let exists = Error::AlreadyExists(Path::new("boot/kernel.old").to_owned());
+        assert_eq!(fs.rename(Path::new("boot/kernel.bin"), Path::new("boot/kernel.old"), false), Err(exists));
+        fs.rename(Path::new("boot/kernel.bin"), Path::new("boot/kernel.old"), true).unwrap();
+        assert_eq!(fs.read_file(Path::new("boot/kernel.old"), &mut buf), Ok(500));
+        assert!(fs.find_dire(Path::new("boot/kernel.bin")).is_err());
+
+        // long names and case changes
+        fs.rename(Path::new("boot/kernel.old"), Path::new("boot/Kernel Image.old"), false).unwrap();
+        fs.rename(Path::new("boot/kernel image.old"), Path::new("boot/KERNEL.OLD"), false).unwrap();
+        let names = fs.read_dir(Path::new("boot")).unwrap()
+                      .map(|e| e.unwrap().name)
+                      .collect::<Vec<String>>();
+        assert_eq!(names, ["grub", "KERNEL.OLD"]);
+
+        // moving a directory updates its `..` entry
+        fs.rename(Path::new("boot/grub"), Path::new("grub"), false).unwrap();
+        let grub = fs.find_dir(Path::new("grub")).unwrap();
+        assert_eq!(fs.get_dire(grub, 1).unwrap().start(), Some(0));
+        assert_eq!(fs.read_file(Path::new("grub/../grub/grub.cfg"), &mut buf), Ok(10));
+        fs.rename(Path::new("grub"), Path::new("boot/grub"), false).unwrap();
+        let boot = fs.find_dir(Path::new("boot")).unwrap();
+        assert_eq!(fs.find_dir(Path::new("boot/grub/..")), Ok(boot));
+
+        assert_eq!(fs.rename(Path::new("boot"), Path::new("boot/grub/boot"), false), Err(Error::InvalidPath));
+        assert_eq!(fs.rename(Path::new("boot/grub"), Path::new("boot/KERNEL.OLD"), true),
+                   Err(Error::NotADirectory(Path::new("boot/KERNEL.OLD").to_owned())));
+        assert_eq!(fs.rename(Path::new("boot/KERNEL.OLD"), Path::new("boot/a:b"), false), Err(Error::InvalidPath));
+        assert_eq!(fs.read_file(Path::new("boot/KERNEL.OLD"), &mut buf), Ok(500));
+        assert!(fs.check().unwrap().is_clean());
+
+        // a replaced file survives if the new entry doesn't fit, here the
+        // long name needs one entry more than the target frees up
+        let mut fs = ramdisk_fs(2048);
+        fs.make_dir(Path::new("BOOT")).unwrap();
+        fs.write_file(Path::new("KERNEL.BIN"), &[4; 100]).unwrap();
+        // fill the root directory's only cluster, bar the end marker
+        for i in 0..fs.dires_per_cluster() - 3 {
+            fs.write_file(Path::new(&format!("F{}.BIN", i)), &[]).unwrap();
+        }
+        assert_eq!(fs.next_cluster(fs.rdir_cluster), Ok(None));
+        fs.write_file(Path::new("boot/new.bin"), &[5; 100]).unwrap();
+        let free = fs.free_count;
+        fs.write_file(Path::new("boot/fill.bin"), &vec![0; free * 512]).unwrap();
+        assert_eq!(fs.rename(Path::new("boot/new.bin"), Path::new("kernel.bin"), true), Err(Error::NoSpace));
+        assert_eq!(fs.read_file(Path::new("KERNEL.BIN"), &mut buf), Ok(100));
+        assert_eq!(&buf[..100], &[4; 100][..]);
+        assert_eq!(fs.read_file(Path::new("boot/new.bin"), &mut buf), Ok(100));
+        assert!(fs.check().unwrap().is_clean());
+    }
 }
@@ -1,15 +1,100 @@
+use std::io::{Read, Write, Seek};
 use std::path::Path;
 
 use disk::Result;
 
+pub mod attributes;
+mod bitmap;
+pub mod clock;
+pub mod exfat;
+pub mod ext2;
 pub mod fat;
+pub use self::attributes::Attributes;
+pub use self::clock::{Clock, SystemClock, FixedClock};
+pub use self::exfat::ExFat;
+pub use self::ext2::Ext2;
 pub use self::fat::Fat32;
 
+/// How `FileSystem::open` should treat the file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Read an existing file
+    Read,
+    /// Create the file, or truncate it if it already exists
+    Write,
+    /// Read and modify an existing file in place
+    ReadWrite,
+}
+
+/// An open file that can be streamed to and from
+pub trait Handle: Read + Write + Seek { }
+
+/// The kind of object a directory entry refers to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+/// An item in a directory listing, see `FileSystem::read_dir`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub kind: FileType,
+    pub size: usize, // in bytes, always 0 for directories
+    pub start: usize, // first cluster of the entry's contents
+}
+
+/// A calendar date and time, with a resolution of one second
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8, // 1-12
+    pub day: u8, // 1-31
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// Information about a file or directory, see `FileSystem::metadata`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub kind: FileType,
+    pub size: usize, // in bytes, always 0 for directories
+    pub attributes: Attributes,
+    pub created: Option<DateTime>,
+    pub modified: Option<DateTime>,
+    pub accessed: Option<DateTime>, // FAT12/16/32 only record the date
+}
+
 pub trait FileSystem {
-    // TODO: consider rewriting FileSystem::write_file() accepting T: Read
+    /// Lists the contents of a directory
+    fn read_dir<'a>(&'a mut self, &Path) -> Result<Box<Iterator<Item=Result<Entry>> + 'a>>;
+    fn metadata(&mut self, &Path) -> Result<Metadata>;
+    fn attributes(&mut self, &Path) -> Result<Attributes>;
+    /// Sets the attributes of a file or directory
+    ///
+    /// Whether the entry is a directory or a volume label is left unchanged.
+    fn set_attributes(&mut self, &Path, Attributes) -> Result<()>;
+    fn open<'a>(&'a mut self, &Path, Mode) -> Result<Box<Handle + 'a>>;
     fn write_file(&mut self, &Path, &[u8]) -> Result<()>;
-    fn read_file(&mut self, &Path, &mut [u8]) -> Result<()>;
+    /// Reads a file into `buf`, returning the number of bytes read
+    fn read_file(&mut self, &Path, &mut [u8]) -> Result<usize>;
+    /// Sets the length of a file, freeing space or zero filling as necessary
+    fn truncate(&mut self, &Path, usize) -> Result<()>;
+    /// Writes into an existing file starting at the given offset
+    ///
+    /// The file grows as needed, and any gap between its old end and the
+    /// offset reads as zero.
+    fn write_at(&mut self, &Path, usize, &[u8]) -> Result<()>;
+    /// Writes to the end of an existing file
+    fn append(&mut self, &Path, &[u8]) -> Result<()>;
     fn delete(&mut self, &Path) -> Result<()>;
+    /// Moves a file or directory from the first path to the second
+    ///
+    /// An existing file or empty directory at the destination is replaced
+    /// only if the flag is set, otherwise it's an error.
+    fn rename(&mut self, &Path, &Path, bool) -> Result<()>;
     fn make_dir(&mut self, &Path) -> Result<()>;
 }
 
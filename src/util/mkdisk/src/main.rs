@@ -29,6 +29,7 @@ Options:
     -o, --out=FILE            The output disk image file
     -b, --bootloader=FILE     The master bootloader to use for the first few sectors
     -v, --volume-loader=FILE  The volume bootloader to use for the partition
+    -f, --filesystem=FS       The filesystem of the partition, fat or ext2 [default: fat]
 
 File sizes measured using KB = 1000, KiB=1024 etc
 ";
@@ -47,8 +48,16 @@ fn main() {
     config.exec();
 }
 
+/// Filesystems `mkdisk` can format the partition with
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Filesystem {
+    Fat, // FAT12, FAT16 or FAT32, depending on the size
+    Ext2,
+}
+
 struct Config {
     dsize: usize,
+    filesystem: Filesystem,
     src: PathBuf,
 
     boot_path: PathBuf,
@@ -65,6 +74,11 @@ impl Config {
     pub fn new(args: docopt::ArgvMap) -> Config {
         // default is 4MiB, as specified in USAGE
         let dsize = parse_size(args.get_str("-s"));
+        let filesystem = match &*args.get_str("-f").to_lowercase() {
+            "fat"  => Filesystem::Fat,
+            "ext2" => Filesystem::Ext2,
+            fs     => panic!("Unknown filesystem: `{}`", fs),
+        };
 
         let boot_path: PathBuf = match args.get_str("-b") {
             ""   => panic!("Master bootloader unspecified: use `-b` or `--bootloader`"),
@@ -102,6 +116,7 @@ impl Config {
 
         Config {
             dsize: dsize,
+            filesystem: filesystem,
             src: src,
 
             boot_path: boot_path,
@@ -118,6 +133,8 @@ impl Config {
     pub fn exec(&mut self) {
         use std::io::{Read, Seek};
         use std::ops::Deref;
+        use std::slice::bytes::copy_memory;
+        use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
         let smeta = ::std::fs::metadata(&self.src)
                               .unwrap_or_else(|e| panic!("Unable to query source `{}`: {}", &self.src.display(), e));
@@ -145,8 +162,8 @@ impl Config {
         }
 
 
-        let pinfo = PartitionInfo {
-            format: Format::Fat32,
+        let mut pinfo = PartitionInfo {
+            format: Format::Fat32, // until the volume is formatted
             size: sectors - bs_i,
             start: bs_i,
             bootable: true,
@@ -156,10 +173,23 @@ impl Config {
         // TODO: refactor
         { // borrowck strikes again!
             let mut partition = disk::get_partition(&mut disk, 0).unwrap();
-            fs::fat::format(&mut partition).unwrap();
-
-            // TODO: support something other than FAT32
-            let mut vbr = *partition.read_sector(0).unwrap();
+            let mut vbr = match self.filesystem {
+                Filesystem::Fat => {
+                    // small images are formatted as FAT12 or FAT16
+                    let kind = fs::fat::format(&mut partition).unwrap();
+                    pinfo.format = Format::from(kind);
+                    *partition.read_sector(0).unwrap()
+                },
+                Filesystem::Ext2 => {
+                    fs::ext2::format(&mut partition).unwrap();
+                    pinfo.format = Format::Ext2;
+                    // ext2 has no header in the boot sector, only the jmp
+                    // over where FAT would have one
+                    let mut vbr = EMPTY_SECTOR;
+                    copy_memory(&[0xEB, 0x58, 0x90], &mut vbr[0..3]);
+                    vbr
+                },
+            };
 
             // Volume Boot Record
             //
@@ -177,21 +207,17 @@ impl Config {
             // filesystem.
 
 
-            // Read and offset first stage of volume bootloader
-            self.voot.read(&mut vbr).unwrap(); // TODO: error handling
-
-            // manually encode a jmp instruction
-            vbr[0] = 0xEB; // relative jmp
-            vbr[1] = 90 - 2; // jmp over filesystem header
-            vbr[2] = 0x90; // NOP
+            // Read first stage of volume bootloader, keeping the filesystem
+            // header (including its jmp over itself) written by `format()`
+            let mut stage1: [u8; 512] = [0; 512];
+            self.voot.read(&mut stage1).unwrap(); // TODO: error handling
+            copy_memory(&stage1[90..], &mut vbr[90..]);
 
             assert!(vbr[510] == 0x55, "Invalid volume bootloader signature");
             assert!(vbr[511] == 0xAA, "Invalid volume bootloader signature");
 
-            partition.write_sector(0, &vbr).unwrap();
-
             // Read stage two of volume boot loader
-            let mut i = 1; // skip first sector which has stage1
+            let mut stage2 = vec![];
             loop {
                 let mut sector: [u8; 512] = [0; 512];
                 match self.voot.read(&mut sector) {
@@ -199,10 +225,40 @@ impl Config {
                     Ok(n) => { }
                     Err(e) => { panic!("Unable to read volume bootloader `{}`: {}", self.voot_path.display(), e); },
                 }
-                partition.write_sector(i, &sector);
-                i += 1;
+                stage2.push(sector);
+            }
+
+            // It goes in the reserved sectors between the FSInfo sector
+            // and the backup boot sector, FAT12/16 leave the same room.
+            // ext2 only leaves the rest of its 1024 byte boot block, so it
+            // goes in blocks held by the boot loader inode instead
+            let (start, end) = match pinfo.format {
+                Format::Fat32 => (2, (&vbr[50..52]).read_u16::<LittleEndian>().unwrap() as usize),
+                Format::Ext2 => {
+                    let partition = disk::get_partition(&mut disk, 0).unwrap();
+                    let mut fs = fs::ext2::Ext2::new(Box::new(partition)).unwrap();
+                    let start = fs.reserve_boot_loader(stage2.len() * 512)
+                                  .unwrap_or_else(|e| panic!("Unable to reserve room for volume bootloader: {:?}", e));
+                    (start, start + stage2.len())
+                },
+                _ => (2, (&vbr[14..16]).read_u16::<LittleEndian>().unwrap() as usize), // reserved sectors
+            };
+            assert!(start + stage2.len() <= end, "Volume bootloader too large: `{}`", self.voot_path.display());
+
+            // tell stage1 where to find stage2, see `beta.s`
+            (&mut vbr[504..508]).write_u32::<LittleEndian>(start as u32).unwrap();
+            (&mut vbr[508..510]).write_u16::<LittleEndian>(stage2.len() as u16).unwrap();
+
+            partition.write_sector(0, &vbr).unwrap();
+            if let Format::Fat32 = pinfo.format {
+                // keep the backup boot sector identical
+                partition.write_sector(end, &vbr).unwrap();
+            }
+            for (i, sector) in stage2.iter().enumerate() {
+                partition.write_sector(start + i, sector).unwrap();
             }
         }
+        disk::set_pinfo(&mut disk, 0, &pinfo).unwrap();
 
 
         fn recurse<T: FileSystem + ?Sized>(fs: &mut T, src: &PathBuf, dir: PathBuf) {
@@ -218,17 +274,14 @@ impl Config {
                     fs.make_dir(vpath).unwrap();
                     recurse(fs, src, rpath);
                 } else if ft.is_file() {
-                    use std::io::Read;
-
                     let mut file = match File::open(&rpath) {
                         Ok(f) => f,
                         Err(e) => panic!("Cannot open file `{:?}`: {}", rpath, e),
                     };
 
-                    let mut v = Vec::new();
-                    file.read_to_end(&mut v).unwrap();
-
-                    fs.write_file(vpath, &v);
+                    let mut handle = fs.open(vpath, Mode::Write).unwrap();
+                    ::std::io::copy(&mut file, &mut handle)
+                        .unwrap_or_else(|e| panic!("Unable to copy file `{:?}`: {}", rpath, e));
                 }
             }
         }